@@ -0,0 +1,67 @@
+// Accept loop
+// ===========
+//
+// accept_retry wraps the incoming connections of a TcpListener for serve
+// loops, which must not end on a failed accept. A failed accept (e.g.
+// EMFILE or ENFILE, when the process or the system is out of file
+// descriptors) is logged with warn! and accepting continues after
+// ACCEPT_BACKOFF_MS, so that a lasting error doesn't spin. Connections
+// aborted or reset before the accept are retried at once.
+//
+// The stream fails only, if the back-off timer can't be created.
+//
+
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::{Incoming, TcpListener, TcpStream};
+use tokio_core::reactor::{Handle, Timeout};
+use futures::{Async, Future, Poll, Stream};
+use futures::try_ready;
+use tracing::warn;
+
+pub const ACCEPT_BACKOFF_MS: u64 = 100;
+
+pub struct AcceptRetry {
+    incoming: Incoming,
+    backoff: Option<Timeout>,
+    handle: Handle
+}
+
+pub fn accept_retry(listener: TcpListener, handle: &Handle) -> AcceptRetry {
+    AcceptRetry {
+        incoming: listener.incoming(),
+        backoff: None,
+        handle: handle.clone()
+    }
+}
+
+impl Stream for AcceptRetry {
+    type Item = (TcpStream,SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            if let Some(ref mut backoff) = self.backoff {
+                try_ready!(backoff.poll());
+            }
+            self.backoff = None;
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some(accepted))) => return Ok(Async::Ready(Some(accepted))),
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    warn!(error = %e, "accept failed");
+                    match e.kind() {
+                        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset => (),
+                        _ => {
+                            let backoff = Duration::from_millis(ACCEPT_BACKOFF_MS);
+                            self.backoff = Some(Timeout::new(backoff, &self.handle)?);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
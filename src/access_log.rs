@@ -0,0 +1,93 @@
+// Access log
+// ==========
+//
+// An AccessLogEntry describes one completed tunnel. It is displayed as one
+// line with client address, user ("-" if not authenticated), destination,
+// bytes sent from client to destination, bytes received from destination to
+// client, duration in seconds and "timeout" for tunnels closed by the idle
+// timeout, "error" for tunnels finished by an I/O error ("-" otherwise):
+//
+//     192.0.2.1:50812 alice example.com:443 512 20480 1.250 -
+//
+// User and destination come from the client. Control characters,
+// whitespace and backslashes in them are written as \xNN resp. \u{NNNN},
+// so a client cannot break the line or forge fields.
+//
+// Forwarder and Router emit the entry of each tunnel as tracing event at
+// info level with target ACCESS_LOG_TARGET, so a subscriber can filter the
+// access log or write it to its own file.
+//
+
+use std::fmt;
+use std::net::SocketAddr;
+use tracing::info;
+use relay::RelayStats;
+use socks_fut::SocksRequestResponse;
+
+pub const ACCESS_LOG_TARGET: &str = "socksv5_future::access";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessLogEntry {
+    pub peer: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub destination: String,
+    pub stats: RelayStats
+}
+
+impl AccessLogEntry {
+    pub fn new(peer: Option<SocketAddr>, request: &SocksRequestResponse,
+               stats: RelayStats) -> AccessLogEntry {
+        AccessLogEntry {
+            peer,
+            user: request.user.clone(),
+            destination: request.destination(),
+            stats
+        }
+    }
+
+    pub fn log(&self) {
+        info!(target: ACCESS_LOG_TARGET, "{}", self);
+    }
+}
+
+// Client-controlled field without separators or line breaks
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for c in self.0.chars() {
+            if c == '\\' || c.is_control() || c.is_whitespace() {
+                if c.is_ascii() {
+                    write!(f, "\\x{:02x}", c as u32)?;
+                }
+                else {
+                    write!(f, "\\u{{{:04x}}}", c as u32)?;
+                }
+            }
+            else {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AccessLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{} ", peer)?,
+            None => write!(f, "- ")?
+        }
+        write!(f, "{} {} {} {} {:.3} {}",
+               Escaped(self.user.as_deref().unwrap_or("-")),
+               Escaped(&self.destination),
+               self.stats.sent,
+               self.stats.received,
+               self.stats.duration.as_secs_f64(),
+               match self.stats {
+                   RelayStats { error: Some(_), .. } => "error",
+                   RelayStats { timed_out: true, .. } => "timeout",
+                   _ => "-"
+               })
+    }
+}
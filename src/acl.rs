@@ -0,0 +1,430 @@
+// Access control for socks5 requests
+// ==================================
+//
+// An Acl is an ordered list of rules. The first rule, which matches a
+// request, decides if the request is allowed or denied. If no rule matches,
+// the default action of the Acl is taken.
+//
+// Rules can be loaded from a text file. Each non-empty line, which does not
+// start with '#', is a rule in the format:
+//
+//     allow|deny [from CIDR] [to CIDR] [domain PATTERN] [port PORTS]
+//                [cmd connect|bind|udp] [user NAME]
+//
+// and the default action is set by `default allow|deny`. Example:
+//
+//     # block private ranges and internal domains
+//     deny  to 10.0.0.0/8
+//     deny  to 192.168.0.0/16
+//     deny  domain *.internal
+//     allow port 80,443,8000-8100 cmd connect
+//     default deny
+//
+// A domain pattern is either a glob with '*' and '?' (*.example.com),
+// a suffix starting with '.' (.example.com matches example.com and all
+// subdomains) or an exact name. Domains are compared case insensitive.
+// A domain pattern never matches a request with an ip address. A CIDR
+// destination matches a request with a domain name only with the resolved
+// address (check_resolved), which socks_dial checks before connecting, if
+// an Acl is given. So `deny to 10.0.0.0/8` also blocks names resolving into
+// this range. IPv4-mapped IPv6 addresses (::ffff:10.0.0.1) are compared as
+// IPv4 addresses.
+//
+
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use socks_fut::{Command, SocksRequestResponse};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> io::Result<Cidr> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        if prefix > max {
+            return Err(Error::new(ErrorKind::InvalidInput, "CIDR prefix too long"));
+        }
+        // ::ffff:10.0.0.0/104 is 10.0.0.0/8
+        Ok(match addr {
+            IpAddr::V6(ip) if prefix >= 96 => match ip.to_ipv4_mapped() {
+                Some(ip) => Cidr { addr: IpAddr::V4(ip), prefix: prefix - 96 },
+                None => Cidr { addr, prefix }
+            },
+            _ => Cidr { addr, prefix }
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, unmap(*ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(ip) & mask)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(ip) & mask)
+            },
+            _ => false
+        }
+    }
+}
+
+// IPv4-mapped IPv6 address as IPv4 address
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip)
+        },
+        ip => ip
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    // Accepts "10.0.0.0/8", "::1/128" or a plain address as host route
+    fn from_str(s: &str) -> io::Result<Cidr> {
+        let invalid = || Error::new(ErrorKind::InvalidInput,
+                                    format!("Invalid CIDR '{}'", s));
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| invalid())?;
+        let prefix = match parts.next() {
+            Some(p) => p.parse::<u8>().map_err(|_| invalid())?,
+            None => match addr {
+                IpAddr::V4(_) => 32,
+                IpAddr::V6(_) => 128
+            }
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DomainPattern {
+    Exact(String),
+    Suffix(String),
+    Glob(String)
+}
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        match *self {
+            DomainPattern::Exact(ref name) => domain == *name,
+            DomainPattern::Suffix(ref suffix) => {
+                domain == suffix[1..] || domain.ends_with(suffix.as_str())
+            },
+            DomainPattern::Glob(ref glob) => glob_match(glob.as_bytes(), domain.as_bytes())
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<DomainPattern> {
+        let s = s.trim_end_matches('.').to_ascii_lowercase();
+        if s.is_empty() || s == "." {
+            return Err(Error::new(ErrorKind::InvalidInput, "Empty domain pattern"));
+        }
+        Ok(if s.contains('*') || s.contains('?') {
+            DomainPattern::Glob(s)
+        }
+        else if s.starts_with('.') {
+            DomainPattern::Suffix(s)
+        }
+        else {
+            DomainPattern::Exact(s)
+        })
+    }
+}
+
+// Iterative glob matching with backtracking to the last '*'
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        }
+        else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        }
+        else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        }
+        else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.first <= port && port <= self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = io::Error;
+
+    // Accepts "443" or "8000-8100"
+    fn from_str(s: &str) -> io::Result<PortRange> {
+        let invalid = || Error::new(ErrorKind::InvalidInput,
+                                    format!("Invalid port range '{}'", s));
+        let mut parts = s.splitn(2, '-');
+        let first = parts.next().unwrap_or("").parse::<u16>().map_err(|_| invalid())?;
+        let last = match parts.next() {
+            Some(p) => p.parse::<u16>().map_err(|_| invalid())?,
+            None => first
+        };
+        if last < first {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Destination {
+    Cidr(Cidr),
+    Domain(DomainPattern)
+}
+
+// A condition matches, if all of its given parts match.
+// It is shared by acl rules and routes.
+#[derive(Clone, Debug, Default)]
+pub struct Condition {
+    pub source: Option<Cidr>,
+    pub destination: Option<Destination>,
+    pub ports: Vec<PortRange>,
+    pub command: Option<Command>,
+    pub user: Option<String>
+}
+
+impl Condition {
+    pub fn matches(&self, source: Option<IpAddr>,
+                          request: &SocksRequestResponse,
+                          user: Option<&str>) -> bool {
+        self.matches_resolved(source, request, request.ipaddr(), user)
+    }
+
+    // As matches, but a CIDR destination is compared with the given address,
+    // e.g. an address the domain of the request resolves to.
+    pub fn matches_resolved(&self, source: Option<IpAddr>,
+                                   request: &SocksRequestResponse,
+                                   destination: Option<IpAddr>,
+                                   user: Option<&str>) -> bool {
+        if let Some(ref cidr) = self.source {
+            match source {
+                Some(ip) if cidr.contains(&ip) => (),
+                _ => return false
+            }
+        }
+        match self.destination {
+            Some(Destination::Cidr(ref cidr)) => {
+                match destination {
+                    Some(ip) if cidr.contains(&ip) => (),
+                    _ => return false
+                }
+            },
+            Some(Destination::Domain(ref pattern)) => {
+                let domain = request.hostname()
+                                    .and_then(|name| ::std::str::from_utf8(name).ok());
+                match domain {
+                    Some(name) if pattern.matches(name) => (),
+                    _ => return false
+                }
+            },
+            None => ()
+        }
+        if !self.ports.is_empty() {
+            let port = request.port();
+            if !self.ports.iter().any(|range| range.contains(port)) {
+                return false;
+            }
+        }
+        if let Some(command) = self.command {
+            if command != request.command() {
+                return false;
+            }
+        }
+        if let Some(ref name) = self.user {
+            if user != Some(name.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Parse "key value" pairs: from, to, domain, port, cmd and user
+    pub fn parse_words<'a, I>(words: I) -> io::Result<Condition>
+            where I: IntoIterator<Item=&'a str> {
+        let invalid = |msg: &str, word: &str| Error::new(ErrorKind::InvalidInput,
+                                                         format!("{} '{}'", msg, word));
+        let mut condition = Condition::default();
+        let mut words = words.into_iter();
+        while let Some(key) = words.next() {
+            let value = words.next().ok_or_else(|| invalid("Missing value for", key))?;
+            match key {
+                "from" => condition.source = Some(value.parse()?),
+                "to" => condition.destination = Some(Destination::Cidr(value.parse()?)),
+                "domain" => condition.destination = Some(Destination::Domain(value.parse()?)),
+                "port" => {
+                    for range in value.split(',') {
+                        condition.ports.push(range.parse()?);
+                    }
+                },
+                "cmd" => condition.command = Some(match value {
+                    "connect" => Command::Connect,
+                    "bind" => Command::Bind,
+                    "udp" => Command::UdpAssociate,
+                    _ => return Err(invalid("Unknown command", value))
+                }),
+                "user" => condition.user = Some(value.to_string()),
+                _ => return Err(invalid("Unknown keyword", key))
+            }
+        }
+        Ok(condition)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub condition: Condition
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Rule {
+        Rule {
+            action,
+            condition: Condition::default()
+        }
+    }
+
+    pub fn matches(&self, source: Option<IpAddr>,
+                          request: &SocksRequestResponse,
+                          user: Option<&str>) -> bool {
+        self.condition.matches(source, request, user)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> io::Result<Rule> {
+        let mut words = line.split_whitespace();
+        let action = match words.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            _ => return Err(Error::new(ErrorKind::InvalidInput,
+                                format!("Rule must start with allow or deny: '{}'", line)))
+        };
+        Ok(Rule {
+            action,
+            condition: Condition::parse_words(words)?
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Acl {
+    pub rules: Vec<Rule>,
+    pub default: Action
+}
+
+impl Acl {
+    pub fn new(default: Action) -> Acl {
+        Acl {
+            rules: vec!(),
+            default
+        }
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn check(&self, source: Option<IpAddr>,
+                        request: &SocksRequestResponse,
+                        user: Option<&str>) -> Action {
+        self.rules.iter()
+            .find(|rule| rule.matches(source, request, user))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+
+    // Check a request for a domain name with one of its resolved addresses
+    pub fn check_resolved(&self, source: Option<IpAddr>,
+                                 request: &SocksRequestResponse,
+                                 destination: IpAddr,
+                                 user: Option<&str>) -> Action {
+        self.rules.iter()
+            .find(|rule| rule.condition.matches_resolved(source, request, Some(destination), user))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Acl> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        content.parse()
+    }
+}
+
+impl FromStr for Acl {
+    type Err = io::Error;
+
+    // Without `default` line, all requests not matched by a rule are allowed.
+    fn from_str(s: &str) -> io::Result<Acl> {
+        let mut acl = Acl::new(Action::Allow);
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at_line = |e: io::Error| Error::new(e.kind(), format!("line {}: {}", n + 1, e));
+            let mut words = line.split_whitespace();
+            if words.next() == Some("default") {
+                acl.default = match (words.next(), words.next()) {
+                    (Some("allow"), None) => Action::Allow,
+                    (Some("deny"), None) => Action::Deny,
+                    _ => return Err(at_line(Error::new(ErrorKind::InvalidInput,
+                                                       "default must be allow or deny")))
+                };
+                continue;
+            }
+            acl.push(line.parse().map_err(at_line)?);
+        }
+        Ok(acl)
+    }
+}
@@ -0,0 +1,75 @@
+// Username/password authentication on server side
+// ===============================================
+//
+// With an Authenticator, SocksHandshake::with_authenticator selects the
+// username/password method as per RFC 1929 and the client has to offer it.
+// After successful authentication the username is stored in the request,
+// so acl rules, routes and bandwidth limits can refer to it.
+//
+// UserList is a simple Authenticator, which can be loaded from a text file.
+// Each non-empty line, which does not start with '#', contains a username
+// and its password separated by whitespace:
+//
+//     alice secret
+//     bob   hunter2
+//
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, username: &str, password: &str) -> bool;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct UserList {
+    users: HashMap<String,String>
+}
+
+impl UserList {
+    pub fn new() -> UserList {
+        UserList::default()
+    }
+
+    pub fn insert(mut self, username: &str, password: &str) -> UserList {
+        self.users.insert(username.to_string(), password.to_string());
+        self
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<UserList> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        content.parse()
+    }
+}
+
+impl Authenticator for UserList {
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        self.users.get(username).map(|p| p == password).unwrap_or(false)
+    }
+}
+
+impl FromStr for UserList {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<UserList> {
+        let mut users = UserList::new();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() != 2 {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("line {}: expected username and password", n + 1)));
+            }
+            users = users.insert(words[0], words[1]);
+        }
+        Ok(users)
+    }
+}
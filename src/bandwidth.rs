@@ -0,0 +1,283 @@
+// Bandwidth limits
+// ================
+//
+// A BandwidthLimiter holds token buckets for the upload (client to target)
+// and the download (target to client) direction:
+//
+// - global: shared by all connections
+// - per user: shared by all connections of an authenticated user
+// - per connection: one bucket pair for each connection
+//
+// Each Rate is given in bytes per second. A bucket holds at most one second
+// of its rate, which is the allowed burst. A direction of a connection may
+// move as many bytes as all applicable buckets allow.
+//
+// The limits can be changed at any time. Changed global and user limits
+// apply immediately to all connections, changed connection limits apply to
+// all connections at their next transfer.
+//
+// A Throttle is the view of one connection on the limiter. It is passed to
+// relay or splice_relay (with_throttle), so the limits are enforced after
+// the handshake. If no bytes are allowed, the relay waits until the buckets
+// are refilled.
+//
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+use futures::*;
+use futures::Async;
+
+// Do not wake up for less than this share of a second of the rate
+const MIN_WAKEUP_DIVISOR: u64 = 20;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rate {
+    // Bytes per second from client to target, None for unlimited
+    pub upload: Option<u64>,
+    // Bytes per second from target to client, None for unlimited
+    pub download: Option<u64>
+}
+
+impl Rate {
+    pub fn unlimited() -> Rate {
+        Rate::default()
+    }
+
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Rate {
+        Rate { upload, download }
+    }
+
+    fn get(&self, way: Way) -> Option<u64> {
+        match way {
+            Way::Upload => self.upload,
+            Way::Download => self.download
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Way {
+    Upload,
+    Download
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last: Instant::now()
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        if self.rate != rate {
+            self.refill();
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate.unwrap_or(0) as f64);
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last);
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * rate as f64).min(rate as f64);
+        }
+        self.last = now;
+    }
+
+    fn available(&mut self) -> u64 {
+        self.refill();
+        match self.rate {
+            Some(_) => self.tokens as u64,
+            None => u64::MAX
+        }
+    }
+
+    fn consume(&mut self, n: u64) {
+        if self.rate.is_some() {
+            self.tokens -= n as f64;
+        }
+    }
+
+    // Time until a reasonable amount of bytes can be moved
+    fn delay(&self) -> Duration {
+        match self.rate {
+            Some(rate) if rate > 0 => {
+                let wanted = cmp::max(1, rate / MIN_WAKEUP_DIVISOR) as f64;
+                let missing = (wanted - self.tokens).max(0.0);
+                Duration::from_secs_f64(missing / rate as f64)
+            },
+            // Wait for a new rate
+            Some(_) => Duration::from_secs(1),
+            None => Duration::from_secs(0)
+        }
+    }
+}
+
+struct BucketPair {
+    upload: Bucket,
+    download: Bucket
+}
+
+impl BucketPair {
+    fn new(rate: Rate) -> BucketPair {
+        BucketPair {
+            upload: Bucket::new(rate.upload),
+            download: Bucket::new(rate.download)
+        }
+    }
+
+    fn set_rate(&mut self, rate: Rate) {
+        self.upload.set_rate(rate.upload);
+        self.download.set_rate(rate.download);
+    }
+
+    fn get(&mut self, way: Way) -> &mut Bucket {
+        match way {
+            Way::Upload => &mut self.upload,
+            Way::Download => &mut self.download
+        }
+    }
+}
+
+struct Limits {
+    global: BucketPair,
+    connection: Rate,
+    users: HashMap<String,BucketPair>
+}
+
+pub struct BandwidthLimiter {
+    limits: Mutex<Limits>
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> BandwidthLimiter {
+        BandwidthLimiter::new()
+    }
+}
+
+impl BandwidthLimiter {
+    // Without limits
+    pub fn new() -> BandwidthLimiter {
+        BandwidthLimiter {
+            limits: Mutex::new(Limits {
+                global: BucketPair::new(Rate::unlimited()),
+                connection: Rate::unlimited(),
+                users: HashMap::new()
+            })
+        }
+    }
+
+    pub fn set_global(&self, rate: Rate) {
+        self.limits.lock().unwrap().global.set_rate(rate);
+    }
+
+    pub fn set_per_connection(&self, rate: Rate) {
+        self.limits.lock().unwrap().connection = rate;
+    }
+
+    pub fn set_user(&self, user: &str, rate: Rate) {
+        let mut limits = self.limits.lock().unwrap();
+        match limits.users.get_mut(user) {
+            Some(buckets) => buckets.set_rate(rate),
+            None => {
+                limits.users.insert(user.to_string(), BucketPair::new(rate));
+            }
+        }
+    }
+
+    // Users without own limit are only limited globally and per connection
+    pub fn remove_user(&self, user: &str) {
+        self.limits.lock().unwrap().users.remove(user);
+    }
+
+    // Bytes allowed now and the delay until more bytes are allowed
+    fn allowance(&self, connection: &mut BucketPair, user: Option<&str>, way: Way) -> (u64,Duration) {
+        let mut limits = self.limits.lock().unwrap();
+        let limits = &mut *limits;
+        connection.get(way).set_rate(limits.connection.get(way));
+        let users = &mut limits.users;
+        let mut buckets = vec![limits.global.get(way), connection.get(way)];
+        if let Some(buckets_of_user) = user.and_then(|user| users.get_mut(user)) {
+            buckets.push(buckets_of_user.get(way));
+        }
+        let allowed = buckets.iter_mut().map(|b| b.available()).min().unwrap_or(u64::MAX);
+        let delay = buckets.iter().map(|b| b.delay()).max().unwrap_or_default();
+        (allowed, delay)
+    }
+
+    fn consume(&self, connection: &mut BucketPair, user: Option<&str>, way: Way, n: u64) {
+        let mut limits = self.limits.lock().unwrap();
+        limits.global.get(way).consume(n);
+        connection.get(way).consume(n);
+        if let Some(buckets) = user.and_then(|user| limits.users.get_mut(user)) {
+            buckets.get(way).consume(n);
+        }
+    }
+}
+
+pub struct Throttle {
+    limiter: Arc<BandwidthLimiter>,
+    user: Option<String>,
+    connection: BucketPair,
+    handle: Handle,
+    timer: Option<Timeout>
+}
+
+impl Throttle {
+    pub fn new(limiter: Arc<BandwidthLimiter>, user: Option<&str>, handle: &Handle) -> Throttle {
+        let connection = BucketPair::new(limiter.limits.lock().unwrap().connection);
+        Throttle {
+            limiter,
+            user: user.map(|user| user.to_string()),
+            connection,
+            handle: handle.clone(),
+            timer: None
+        }
+    }
+
+    pub(crate) fn allowance(&mut self, way: Way) -> u64 {
+        self.limiter.allowance(&mut self.connection, self.user.as_deref(), way).0
+    }
+
+    pub(crate) fn consume(&mut self, way: Way, n: u64) {
+        if n > 0 {
+            self.limiter.consume(&mut self.connection, self.user.as_deref(), way, n);
+        }
+    }
+
+    // Wake up the task, when the limited directions may move bytes again
+    pub(crate) fn wait(&mut self, upload: bool, download: bool) -> io::Result<()> {
+        let mut delay: Option<Duration> = None;
+        for (way, limited) in [(Way::Upload, upload), (Way::Download, download)] {
+            if limited {
+                let (_, d) = self.limiter.allowance(&mut self.connection, self.user.as_deref(), way);
+                delay = Some(delay.map_or(d, |delay| cmp::min(delay, d)));
+            }
+        }
+        let at = Instant::now() + delay.unwrap_or_default();
+        match self.timer {
+            Some(ref mut timer) => timer.reset(at),
+            None => self.timer = Some(Timeout::new_at(at, &self.handle)?)
+        }
+        if let Some(ref mut timer) = self.timer {
+            if let Async::Ready(()) = timer.poll()? {
+                task::current().notify();
+            }
+        }
+        Ok(())
+    }
+}
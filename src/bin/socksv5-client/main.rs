@@ -0,0 +1,315 @@
+// socksv5-client
+// ==============
+//
+// Tool to test proxies: connects to TARGET through one or more proxies
+// (ProxyChain) and prints the negotiation on stderr. The steps of each
+// handshake (selected method, state transitions with -v) are the tracing
+// events of SocksConnectHandshake, the decoded reply of every hop (reply
+// code and bound address) is printed at the end.
+//
+// A proxy is given as [socks5://|http://][USER:PASSWORD@]HOST:PORT or, for
+// a proxy on a unix socket path, with unix:PATH instead of HOST:PORT. Several
+// proxies (-x) form a chain in the given order. With --pipe, stdin and
+// stdout are relayed through the tunnel like netcat.
+//
+// The subcommand forward listens on a local address instead (-L) and
+// tunnels every accepted connection to TARGET, like `ssh -L`, see
+// PortForward.
+//
+// Exit code is 0 on success, 1 if the tunnel cannot be established and 2
+// for invalid arguments.
+//
+
+extern crate futures;
+extern crate getopts;
+extern crate tokio_core;
+extern crate tokio_io;
+extern crate tracing;
+extern crate tracing_subscriber;
+extern crate socksv5_future;
+
+use std::env;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::process;
+use std::thread;
+use std::time::Duration;
+use tokio_io::AsyncWrite;
+use tokio_io::io::{copy, write_all};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::{Core, Timeout};
+use futures::{future, Async, Future, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use getopts::Options;
+use tracing::Level;
+use socksv5_future::{ChainError, Command, Credentials, PortForward, Proxy, ProxyAddr, ProxyChain,
+                     ProxyKind, ProxyStream, SocksRequestResponse};
+
+const USAGE: &str = "Usage: socksv5-client [options] -x PROXY TARGET\n       \
+                     socksv5-client forward [options] -L ADDR -x PROXY TARGET";
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optmulti("x", "proxy", "[socks5://|http://][USER:PASSWORD@]HOST:PORT or unix:PATH, \
+                                 repeated for a chain", "PROXY");
+    opts.optflag("p", "pipe", "Relay stdin and stdout through the tunnel");
+    opts.optopt("L", "listen", "Local address of forward", "ADDR");
+    opts.optopt("t", "timeout", "Seconds to establish the tunnel (default 10)", "SECS");
+    opts.optflag("v", "verbose", "Print the state transitions of the handshakes");
+    opts.optflag("q", "quiet", "Print errors only");
+    opts.optflag("h", "help", "Print this help");
+    opts
+}
+
+fn invalid(msg: String) -> io::Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+// HOST:PORT with IPv6 addresses in brackets
+fn split_host_port(s: &str) -> io::Result<(&str,u16)> {
+    let pos = s.rfind(':').ok_or_else(|| invalid(format!("Missing port in '{}'", s)))?;
+    let port = s[pos+1..].parse().map_err(|_| invalid(format!("Invalid port in '{}'", s)))?;
+    let host = s[..pos].trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid(format!("Missing host in '{}'", s)));
+    }
+    Ok((host, port))
+}
+
+fn parse_proxy(s: &str) -> io::Result<Proxy> {
+    let (kind, rest) = match (s.strip_prefix("http://"), s.strip_prefix("socks5://")) {
+        (Some(rest), _) => (ProxyKind::Http, rest),
+        (None, Some(rest)) => (ProxyKind::Socks5, rest),
+        (None, None) => (ProxyKind::Socks5, s)
+    };
+    let (credentials, authority) = match rest.rfind('@') {
+        Some(pos) => {
+            let userinfo = &rest[..pos];
+            let colon = userinfo.find(':')
+                            .ok_or_else(|| invalid(format!("Missing password in '{}'", s)))?;
+            (Some(Credentials::new(&userinfo[..colon], &userinfo[colon+1..])), &rest[pos+1..])
+        },
+        None => (None, rest)
+    };
+    let addr = if authority.starts_with("unix:") {
+        authority.parse::<ProxyAddr>()?
+    }
+    else {
+        let addr = split_host_port(authority)?.to_socket_addrs()?.next()
+                        .ok_or_else(|| invalid(format!("No address for '{}'", authority)))?;
+        ProxyAddr::Tcp(addr)
+    };
+    let proxy = Proxy::with_addr(addr).with_kind(kind);
+    Ok(match credentials {
+        Some(credentials) => proxy.with_credentials(credentials),
+        None => proxy
+    })
+}
+
+fn describe(proxy: &Proxy) -> String {
+    let kind = match proxy.kind {
+        ProxyKind::Socks5 => "socks5",
+        ProxyKind::Http => "http"
+    };
+    match proxy.credentials {
+        Some(ref credentials) => format!("{}://{}@{}", kind, credentials.username, proxy.addr),
+        None => format!("{}://{}", kind, proxy.addr)
+    }
+}
+
+fn print_reply(hop: usize, proxy: &Proxy, reply: &SocksRequestResponse) {
+    let code = reply.reply_code();
+    eprintln!("hop {} {}: reply {:?} ({}), bound address {}",
+              hop, describe(proxy), code, code.to_u8(), reply.destination());
+}
+
+// Blocking stdout for tokio_io::io::copy
+struct Stdout(io::Stdout);
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsyncWrite for Stdout {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+// Stdin is read by a thread, because it cannot be registered with the reactor
+fn stdin_chunks() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(4);
+    thread::spawn(move || {
+        let mut tx = tx;
+        let mut buf = [0u8; 8192];
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => match tx.send(buf[..n].to_vec()).wait() {
+                    Ok(sender) => tx = sender,
+                    Err(_) => break
+                }
+            }
+        }
+    });
+    rx
+}
+
+// Until the target closes the tunnel. End of stdin is passed as half-close.
+fn pipe(core: &mut Core, stream: ProxyStream) -> io::Result<()> {
+    let upload = stdin_chunks()
+        .map_err(|_| Error::new(ErrorKind::BrokenPipe, "stdin closed"))
+        .fold(&stream, |stream, chunk| write_all(stream, chunk).map(|(stream,_chunk)| stream))
+        .and_then(|stream| stream.shutdown(Shutdown::Write))
+        .then(|_| future::empty::<(),io::Error>());
+    let download = copy(&stream, Stdout(io::stdout()));
+    match core.run(download.select2(upload)) {
+        Ok(_) => Ok(()),
+        Err(Either::A((e,_))) | Err(Either::B((e,_))) => Err(e)
+    }
+}
+
+fn parse_args(matches: &getopts::Matches, free: &[String])
+                                -> io::Result<(Vec<Proxy>,SocksRequestResponse)> {
+    let proxies = matches.opt_strs("proxy").iter()
+                        .map(|s| parse_proxy(s))
+                        .collect::<io::Result<Vec<Proxy>>>()?;
+    if proxies.is_empty() {
+        return Err(invalid("At least one proxy (-x) is required".to_string()));
+    }
+    let target = match free.len() {
+        1 => &free[0],
+        _ => return Err(invalid("Exactly one TARGET is required".to_string()))
+    };
+    let (host, port) = split_host_port(target)?;
+    let request = SocksRequestResponse::request_host(Command::Connect, host, port)?;
+    Ok((proxies, request))
+}
+
+// Runs until the listener fails
+fn forward(matches: &getopts::Matches) -> io::Result<bool> {
+    let (proxies, request) = parse_args(matches, &matches.free[1..])?;
+    let local: SocketAddr = match matches.opt_str("listen") {
+        Some(addr) => split_host_port(&addr)?.to_socket_addrs()?.next()
+                        .ok_or_else(|| invalid(format!("No address for '{}'", addr)))?,
+        None => return Err(invalid("The local address (-L) is required".to_string()))
+    };
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let listener = TcpListener::bind(&local, &handle)?;
+    if !matches.opt_present("quiet") {
+        let via: Vec<String> = proxies.iter().map(describe).collect();
+        eprintln!("forwarding {} to {} via {}", local, request.destination(), via.join(" "));
+    }
+    let forward = PortForward::with_chain(ProxyChain::new(proxies), request);
+    core.run(forward.listen(listener, &handle))?;
+    Ok(true)
+}
+
+fn run(matches: &getopts::Matches) -> io::Result<bool> {
+    if matches.free.first().map(String::as_str) == Some("forward") {
+        return forward(matches);
+    }
+    let (proxies, request) = parse_args(matches, &matches.free)?;
+    let timeout = match matches.opt_str("timeout") {
+        Some(secs) => secs.parse().map_err(|_| invalid(format!("Invalid timeout '{}'", secs)))?,
+        None => 10
+    };
+    let quiet = matches.opt_present("quiet");
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    if !quiet {
+        for (hop, proxy) in proxies.iter().enumerate() {
+            eprintln!("hop {} {}", hop, describe(proxy));
+        }
+        eprintln!("target {}", request.destination());
+    }
+    let chain = ProxyChain::new(proxies.clone());
+    let timer = Timeout::new(Duration::from_secs(timeout), &handle)?;
+    let connect = chain.connect(request, &handle).select2(timer);
+    let stream = match core.run(connect) {
+        Ok(Either::A(((stream,replies),_))) => {
+            if !quiet {
+                for (hop, (proxy, reply)) in proxies.iter().zip(&replies).enumerate() {
+                    print_reply(hop, proxy, reply);
+                }
+                eprintln!("tunnel established");
+            }
+            stream
+        },
+        Ok(Either::B(_)) => {
+            eprintln!("timeout after {} s", timeout);
+            return Ok(false);
+        },
+        Err(Either::A((ChainError { hop, reply, error, .. },_))) => {
+            let proxy = describe(&proxies[hop]);
+            match reply {
+                Some(code) => eprintln!("hop {} {}: reply {:?} ({})",
+                                        hop, proxy, code, code.to_u8()),
+                None => eprintln!("hop {} {}: {}", hop, proxy, error)
+            }
+            return Ok(false);
+        },
+        Err(Either::B((e,_))) => return Err(e)
+    };
+    if matches.opt_present("pipe") {
+        pipe(&mut core, stream)?;
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("socksv5-client: {}\n\n{}", e, opts.usage(USAGE));
+            process::exit(2);
+        }
+    };
+    if matches.opt_present("help") {
+        print!("{}", opts.usage(USAGE));
+        return;
+    }
+    let level = if matches.opt_present("quiet") {
+        Level::ERROR
+    }
+    else if matches.opt_present("verbose") {
+        Level::TRACE
+    }
+    else {
+        Level::DEBUG
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        .with_target(false)
+        .without_time()
+        .init();
+    match run(&matches) {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(ref e) if e.kind() == ErrorKind::InvalidInput => {
+            eprintln!("socksv5-client: {}\n\n{}", e, opts.usage(USAGE));
+            process::exit(2);
+        },
+        Err(e) => {
+            eprintln!("socksv5-client: {}", e);
+            process::exit(1);
+        }
+    }
+}
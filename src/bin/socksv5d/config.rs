@@ -0,0 +1,235 @@
+// Configuration of socksv5d
+// =========================
+//
+// The configuration is read from a TOML file and can be overridden by
+// command line flags. All keys are optional:
+//
+//     listen = ["127.0.0.1:1080", "[::1]:1080"]
+//     users = "/etc/socksv5d/users"       # UserList, requires authentication
+//     acl = "/etc/socksv5d/acl"           # Acl rules
+//     routes = "/etc/socksv5d/routes"     # RoutingTable
+//     upstream = ["192.0.2.10:1080", "http://192.0.2.12:3128 alice secret"]
+//     socks4 = false                      # true requires no users
+//     log_level = "info"
+//     metrics = "127.0.0.1:9100"
+//     watch = 5                           # seconds between checks for changed files
+//
+//     [timeouts]
+//     handshake = 10                      # seconds
+//     idle = 300
+//
+// upstream entries have the format of the proxy lines in routing tables
+// without name. All requests are forwarded to these proxies with failover.
+// upstream and routes exclude each other, as well as socks4 and users.
+// Both select the outbound, so --upstream or --routes on the command line
+// replaces upstream and routes of the file. check validates the result
+// after the flags have been applied.
+//
+// On SIGHUP, or when watch is set and the configuration file or one of the
+// users, acl and routes files has changed, the configuration is read again.
+// listen, log_level, metrics and watch take effect after a restart only.
+//
+
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use getopts::Matches;
+use toml::Value;
+use tracing::Level;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:1080";
+const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    pub users: Option<PathBuf>,
+    pub acl: Option<PathBuf>,
+    pub routes: Option<PathBuf>,
+    pub upstream: Vec<String>,
+    pub socks4: bool,
+    pub log_level: Level,
+    pub metrics: Option<SocketAddr>,
+    pub handshake_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub watch: Option<Duration>
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![DEFAULT_LISTEN.parse().unwrap()],
+            users: None,
+            acl: None,
+            routes: None,
+            upstream: vec!(),
+            socks4: false,
+            log_level: Level::INFO,
+            metrics: None,
+            handshake_timeout: Some(Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS)),
+            idle_timeout: None,
+            watch: None
+        }
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+fn parse_addr(s: &str) -> io::Result<SocketAddr> {
+    s.to_socket_addrs()?.next()
+        .ok_or_else(|| invalid(format!("No address for '{}'", s)))
+}
+
+fn parse_level(s: &str) -> io::Result<Level> {
+    s.parse().map_err(|_| invalid(format!("Invalid log level '{}'", s)))
+}
+
+// Zero seconds disables the timeout
+fn parse_secs(secs: u64) -> Option<Duration> {
+    match secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs))
+    }
+}
+
+fn parse_secs_str(s: &str) -> io::Result<Option<Duration>> {
+    s.parse().map(parse_secs).map_err(|_| invalid(format!("Invalid number of seconds '{}'", s)))
+}
+
+fn as_str<'a>(key: &str, value: &'a Value) -> io::Result<&'a str> {
+    value.as_str().ok_or_else(|| invalid(format!("{} must be a string", key)))
+}
+
+fn as_strings<'a>(key: &str, value: &'a Value) -> io::Result<Vec<&'a str>> {
+    match *value {
+        Value::String(ref s) => Ok(vec![s.as_str()]),
+        Value::Array(ref values) => values.iter().map(|v| as_str(key, v)).collect(),
+        _ => Err(invalid(format!("{} must be a string or a list of strings", key)))
+    }
+}
+
+fn as_secs(key: &str, value: &Value) -> io::Result<Option<Duration>> {
+    match value.as_integer() {
+        Some(secs) if secs >= 0 => Ok(parse_secs(secs as u64)),
+        _ => Err(invalid(format!("{} must be a number of seconds", key)))
+    }
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        content.parse()
+    }
+
+    // Files, which are read with this configuration
+    pub fn files(&self) -> Vec<&Path> {
+        [&self.users, &self.acl, &self.routes].iter()
+            .filter_map(|path| path.as_ref().map(PathBuf::as_path))
+            .collect()
+    }
+
+    // Flags given on the command line replace the values of the file
+    pub fn apply_args(&mut self, matches: &Matches) -> io::Result<()> {
+        let listen = matches.opt_strs("listen");
+        if !listen.is_empty() {
+            self.listen = listen.iter().map(|s| parse_addr(s)).collect::<io::Result<_>>()?;
+        }
+        let upstream = matches.opt_strs("upstream");
+        let routes = matches.opt_str("routes");
+        if !upstream.is_empty() || routes.is_some() {
+            self.upstream = upstream;
+            self.routes = routes.map(PathBuf::from);
+        }
+        if let Some(users) = matches.opt_str("users") {
+            self.users = Some(PathBuf::from(users));
+        }
+        if let Some(acl) = matches.opt_str("acl") {
+            self.acl = Some(PathBuf::from(acl));
+        }
+        if matches.opt_present("socks4") {
+            self.socks4 = true;
+        }
+        if let Some(level) = matches.opt_str("log-level") {
+            self.log_level = parse_level(&level)?;
+        }
+        if let Some(metrics) = matches.opt_str("metrics") {
+            self.metrics = Some(parse_addr(&metrics)?);
+        }
+        if let Some(secs) = matches.opt_str("handshake-timeout") {
+            self.handshake_timeout = parse_secs_str(&secs)?;
+        }
+        if let Some(secs) = matches.opt_str("idle-timeout") {
+            self.idle_timeout = parse_secs_str(&secs)?;
+        }
+        if let Some(secs) = matches.opt_str("watch") {
+            self.watch = parse_secs_str(&secs)?;
+        }
+        Ok(())
+    }
+
+    pub fn check(&self) -> io::Result<()> {
+        if self.listen.is_empty() {
+            return Err(invalid("No listen address".to_string()));
+        }
+        if self.routes.is_some() && !self.upstream.is_empty() {
+            return Err(invalid("upstream and routes exclude each other".to_string()));
+        }
+        // SOCKS4 clients cannot authenticate
+        if self.socks4 && self.users.is_some() {
+            return Err(invalid("socks4 and users exclude each other".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Config {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Config> {
+        let value: Value = s.parse().map_err(|e| invalid(format!("{}", e)))?;
+        let table = value.as_table().ok_or_else(|| invalid("Expected a table".to_string()))?;
+        let mut config = Config::default();
+        for (key, value) in table {
+            match key.as_str() {
+                "listen" => {
+                    config.listen = as_strings(key, value)?.into_iter()
+                                        .map(parse_addr).collect::<io::Result<_>>()?;
+                },
+                "users" => config.users = Some(PathBuf::from(as_str(key, value)?)),
+                "acl" => config.acl = Some(PathBuf::from(as_str(key, value)?)),
+                "routes" => config.routes = Some(PathBuf::from(as_str(key, value)?)),
+                "upstream" => {
+                    config.upstream = as_strings(key, value)?.into_iter()
+                                        .map(|s| s.to_string()).collect();
+                },
+                "socks4" => {
+                    config.socks4 = value.as_bool()
+                                        .ok_or_else(|| invalid("socks4 must be a boolean".to_string()))?;
+                },
+                "log_level" => config.log_level = parse_level(as_str(key, value)?)?,
+                "metrics" => config.metrics = Some(parse_addr(as_str(key, value)?)?),
+                "watch" => config.watch = as_secs(key, value)?,
+                "timeouts" => {
+                    let timeouts = value.as_table()
+                                        .ok_or_else(|| invalid("timeouts must be a table".to_string()))?;
+                    for (key, value) in timeouts {
+                        match key.as_str() {
+                            "handshake" => config.handshake_timeout = as_secs(key, value)?,
+                            "idle" => config.idle_timeout = as_secs(key, value)?,
+                            _ => return Err(invalid(format!("Unknown key 'timeouts.{}'", key)))
+                        }
+                    }
+                },
+                _ => return Err(invalid(format!("Unknown key '{}'", key)))
+            }
+        }
+        Ok(config)
+    }
+}
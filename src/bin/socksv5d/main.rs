@@ -0,0 +1,302 @@
+// socksv5d
+// ========
+//
+// Socks5 server built on the server APIs of the library: each accepted
+// client runs SocksHandshake (with optional Acl, UserList authentication
+// and SOCKS4) under a handshake timeout. The request is then served by a
+// Router, which connects directly, forwards to upstream proxies or follows
+// a routing table. Metrics are collected and can be served on /metrics.
+//
+// The configuration is read from a TOML file (--config) and command line
+// flags, see config.rs. Logs are written to stderr with the given level,
+// completed tunnels are logged at info level. A failed accept (e.g. out of
+// file descriptors) is logged and the listener keeps accepting.
+//
+// The server is held in a ConfigHandle. On SIGHUP or a changed file (with
+// --watch), the configuration is read again and a new server is swapped in,
+// if it is valid. Accepted connections use the new server, established
+// tunnels continue with the old one.
+//
+
+extern crate futures;
+extern crate getopts;
+extern crate tokio_core;
+#[cfg(unix)]
+extern crate tokio_signal;
+extern crate toml;
+extern crate tracing;
+extern crate tracing_subscriber;
+extern crate socksv5_future;
+
+mod config;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
+use futures::{future, stream, Future, Stream};
+use futures::future::Either;
+use getopts::{Matches, Options};
+use tracing::{debug, error, info, warn};
+use socksv5_future::{accept_retry, serve_metrics, socks_handshake, Acl, ConfigHandle, Metrics,
+                     Outbound, Router, RoutingTable, SocksRequestResponse, SystemResolver,
+                     UserList};
+use config::Config;
+
+const USAGE: &str = "Usage: socksv5d [options]";
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("c", "config", "TOML configuration file", "FILE");
+    opts.optmulti("l", "listen", "Listen address, can be repeated (default 127.0.0.1:1080)", "ADDR");
+    opts.optopt("", "users", "Require authentication with the users of this file", "FILE");
+    opts.optopt("", "acl", "Access control rules", "FILE");
+    opts.optopt("", "routes", "Routing table", "FILE");
+    opts.optmulti("u", "upstream", "Forward to this proxy, e.g. 192.0.2.10:1080 or \
+                                    http://192.0.2.12:3128, can be repeated", "PROXY");
+    opts.optflag("", "socks4", "Accept SOCKS4 and SOCKS4A requests");
+    opts.optopt("", "handshake-timeout", "Seconds to receive the request, 0 disables (default 10)", "SECS");
+    opts.optopt("", "idle-timeout", "Seconds without data until a tunnel is closed, 0 disables", "SECS");
+    opts.optopt("", "metrics", "Serve metrics on http://ADDR/metrics", "ADDR");
+    opts.optopt("", "log-level", "error, warn, info, debug or trace (default info)", "LEVEL");
+    opts.optopt("", "watch", "Seconds between checks for changed files, 0 disables", "SECS");
+    opts.optflag("h", "help", "Print this help");
+    opts
+}
+
+fn with_path<T>(path: &Path, result: io::Result<T>) -> io::Result<T> {
+    result.map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+type HandshakeFuture = Box<dyn Future<Item=(TcpStream,SocksRequestResponse), Error=io::Error>>;
+
+fn with_timeout(handshake: HandshakeFuture, timeout: Duration, handle: &Handle) -> HandshakeFuture {
+    let timer = match Timeout::new(timeout, handle) {
+        Ok(timer) => timer,
+        Err(e) => return Box::new(future::err(e))
+    };
+    Box::new(handshake.select2(timer).then(|res| match res {
+        Ok(Either::A((item,_))) => Ok(item),
+        Ok(Either::B(_)) => Err(Error::new(ErrorKind::TimedOut, "Handshake timed out")),
+        Err(Either::A((e,_))) | Err(Either::B((e,_))) => Err(e)
+    }))
+}
+
+// Shared by all connections of one configuration
+struct Server {
+    config: Config,
+    router: Router,
+    acl: Option<Arc<Acl>>,
+    users: Option<Arc<UserList>>,
+    metrics: Arc<Metrics>,
+    socks4: bool,
+    handshake_timeout: Option<Duration>
+}
+
+impl Server {
+    fn new(config: &Config, metrics: Arc<Metrics>) -> io::Result<Server> {
+        let acl = match config.acl {
+            Some(ref path) => Some(Arc::new(with_path(path, Acl::from_file(path))?)),
+            None => None
+        };
+        let users = match config.users {
+            Some(ref path) => Some(Arc::new(with_path(path, UserList::from_file(path))?)),
+            None => None
+        };
+        let table = match config.routes {
+            Some(ref path) => with_path(path, RoutingTable::from_file(path))?,
+            None if config.upstream.is_empty() => RoutingTable::new(Outbound::Direct),
+            None => {
+                let mut text: String = config.upstream.iter()
+                                            .map(|proxy| format!("proxy upstream {}\n", proxy))
+                                            .collect();
+                text.push_str("default via upstream\n");
+                text.parse()?
+            }
+        };
+        let router = Router::new(Arc::new(table), Arc::new(SystemResolver))
+                            .with_metrics(metrics.clone());
+        let router = match acl {
+            Some(ref acl) => router.with_acl(acl.clone()),
+            None => router
+        };
+        let router = match config.idle_timeout {
+            Some(timeout) => router.with_idle_timeout(timeout),
+            None => router
+        };
+        Ok(Server {
+            config: config.clone(),
+            router,
+            acl,
+            users,
+            metrics,
+            socks4: config.socks4,
+            handshake_timeout: config.handshake_timeout
+        })
+    }
+
+    fn serve(&self, stream: TcpStream, peer: SocketAddr, handle: &Handle)
+                                        -> Box<dyn Future<Item=(), Error=()>> {
+        let mut handshake = socks_handshake(stream).with_metrics(self.metrics.clone());
+        if let Some(ref acl) = self.acl {
+            handshake = handshake.with_acl(acl.clone());
+        }
+        if let Some(ref users) = self.users {
+            handshake = handshake.with_authenticator(users.clone());
+        }
+        if self.socks4 {
+            handshake = handshake.with_socks4();
+        }
+        let handshake: HandshakeFuture = match self.handshake_timeout {
+            Some(timeout) => with_timeout(Box::new(handshake), timeout, handle),
+            None => Box::new(handshake)
+        };
+        let router = self.router.clone();
+        let handle = handle.clone();
+        Box::new(handshake
+            .and_then(move |(client,request)| {
+                router.serve_request_from(client, request, Some(peer), &handle)
+            })
+            .then(move |res| {
+                if let Err(e) = res {
+                    debug!(%peer, error = %e, "connection failed");
+                }
+                Ok(())
+            }))
+    }
+}
+
+fn load_config(matches: &Matches) -> io::Result<Config> {
+    let mut config = match matches.opt_str("config") {
+        Some(path) => with_path(Path::new(&path), Config::from_file(&path))?,
+        None => Config::default()
+    };
+    config.apply_args(matches)?;
+    config.check()?;
+    Ok(config)
+}
+
+// Configuration file and the files it refers to
+fn watched_files(matches: &Matches, config: &Config) -> Vec<PathBuf> {
+    matches.opt_str("config").map(PathBuf::from).into_iter()
+        .chain(config.files().into_iter().map(Path::to_path_buf))
+        .collect()
+}
+
+fn modification_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files.iter().map(|path| fs::metadata(path).and_then(|m| m.modified()).ok()).collect()
+}
+
+// Swap in a server for the new configuration, or keep the current one
+fn reload(matches: &Matches, servers: &ConfigHandle<Server>, metrics: &Arc<Metrics>) {
+    let server = load_config(matches).and_then(|config| Server::new(&config, metrics.clone()));
+    match server {
+        Ok(server) => {
+            let old = servers.swap(server);
+            let new = &servers.get().config;
+            if new.listen != old.config.listen || new.metrics != old.config.metrics
+                    || new.log_level != old.config.log_level || new.watch != old.config.watch {
+                warn!("listen, metrics, log_level and watch take effect after a restart");
+            }
+            info!("configuration reloaded");
+        },
+        Err(e) => error!(error = %e, "reload failed, keeping the current configuration")
+    }
+}
+
+enum Trigger {
+    Hangup,
+    Tick
+}
+
+#[cfg(unix)]
+fn hangups() -> Box<dyn Stream<Item=Trigger, Error=io::Error>> {
+    use tokio_signal::unix::{Signal, SIGHUP};
+    Box::new(Signal::new(SIGHUP).flatten_stream().map(|_| Trigger::Hangup))
+}
+
+#[cfg(not(unix))]
+fn hangups() -> Box<dyn Stream<Item=Trigger, Error=io::Error>> {
+    Box::new(stream::empty())
+}
+
+// Reload on SIGHUP and, if watched, on changed files
+fn reloader(matches: Matches, servers: ConfigHandle<Server>, metrics: Arc<Metrics>,
+            handle: &Handle) -> io::Result<Box<dyn Future<Item=(), Error=io::Error>>> {
+    let ticks: Box<dyn Stream<Item=Trigger, Error=io::Error>> = match servers.get().config.watch {
+        Some(period) => Box::new(Interval::new(period, handle)?.map(|_| Trigger::Tick)),
+        None => Box::new(stream::empty())
+    };
+    let mut times = modification_times(&watched_files(&matches, &servers.get().config));
+    Ok(Box::new(hangups().select(ticks).for_each(move |trigger| {
+        let current = modification_times(&watched_files(&matches, &servers.get().config));
+        match trigger {
+            Trigger::Tick if current == times => return Ok(()),
+            Trigger::Tick => info!("files changed, reloading"),
+            Trigger::Hangup => info!("SIGHUP received, reloading")
+        }
+        reload(&matches, &servers, &metrics);
+        times = modification_times(&watched_files(&matches, &servers.get().config));
+        Ok(())
+    })))
+}
+
+fn run(matches: &Matches) -> io::Result<()> {
+    let config = load_config(matches)?;
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .with_writer(io::stderr)
+        .init();
+
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let metrics = Arc::new(Metrics::new());
+    let servers = ConfigHandle::new(Server::new(&config, metrics.clone())?);
+    if let Some(addr) = config.metrics {
+        let listener = TcpListener::bind(&addr, &handle)?;
+        info!(%addr, "serving metrics");
+        handle.spawn(serve_metrics(listener, metrics.clone(), &handle)
+            .map_err(|e| error!(error = %e, "metrics endpoint failed")));
+    }
+    handle.spawn(reloader(matches.clone(), servers.clone(), metrics, &handle)?
+        .map_err(|e| error!(error = %e, "reload failed")));
+    let mut listeners = vec!();
+    for addr in &config.listen {
+        let listener = TcpListener::bind(addr, &handle)?;
+        info!(%addr, "listening");
+        let servers = servers.clone();
+        let handle = handle.clone();
+        listeners.push(accept_retry(listener, &handle).for_each(move |(stream,peer)| {
+            handle.spawn(servers.get().serve(stream, peer, &handle));
+            Ok(())
+        }));
+    }
+    core.run(future::join_all(listeners)).map(|_| ())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = options();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("socksv5d: {}\n\n{}", e, opts.usage(USAGE));
+            process::exit(2);
+        }
+    };
+    if matches.opt_present("help") {
+        print!("{}", opts.usage(USAGE));
+        return;
+    }
+    if let Err(e) = run(&matches) {
+        eprintln!("socksv5d: {}", e);
+        process::exit(1);
+    }
+}
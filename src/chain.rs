@@ -0,0 +1,375 @@
+// Chaining of socks5 proxies
+// ==========================
+//
+// ProxyChain connects to the first proxy and asks every proxy to CONNECT
+// to the next one. The last proxy receives the given request. Each handshake
+// runs over the tunnel established by the previous hops.
+//
+// On success the future delivers the final stream and the replies of all
+// hops. Every hop has to answer with REP_SUCCEEDED, otherwise the chain fails
+// with a ChainError telling the hop, the proxy and the reply code (if any).
+//
+// A proxy is either a socks5 proxy or an HTTP proxy, which is asked with
+// CONNECT. Both kinds can be mixed in a chain.
+//
+// On unix, a proxy can listen on a socket path instead of a TCP address
+// (ProxyAddr::Unix, written as "unix:/path"). Its connection is a
+// ProxyStream like the TCP ones, so chains, upstream pools, Forwarder and
+// Router use it the same way. Such a proxy can only be the first hop of a
+// chain, as no proxy can CONNECT to a socket path.
+//
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use futures::{future, Future, Poll};
+use futures::future::Loop;
+use http::http_connect_handshake;
+use relay::HalfClose;
+#[cfg(unix)]
+use unix::UnixStream;
+use socks_fut::{socks_connect_handshake_with_credentials, Command, Credentials,
+                ReplyCode, SocksRequestResponse};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    Http
+}
+
+pub type ProxyHandshake<S = TcpStream> = Box<dyn Future<Item=(S,SocksRequestResponse), Error=io::Error>>;
+
+pub type ProxyConnect = Box<dyn Future<Item=ProxyStream, Error=io::Error>>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProxyAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+impl From<SocketAddr> for ProxyAddr {
+    fn from(addr: SocketAddr) -> ProxyAddr {
+        ProxyAddr::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProxyAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            ProxyAddr::Unix(ref path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+// "unix:/path" or a socket address
+impl FromStr for ProxyAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<ProxyAddr> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = s.strip_prefix("unix:") {
+                if path.is_empty() {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Empty socket path"));
+                }
+                return Ok(ProxyAddr::Unix(PathBuf::from(path)));
+            }
+        }
+        s.parse::<SocketAddr>()
+         .map(ProxyAddr::Tcp)
+         .map_err(|_| Error::new(ErrorKind::InvalidInput,
+                                 format!("Invalid proxy address '{}'", s)))
+    }
+}
+
+// Connection to a proxy
+pub enum ProxyStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl ProxyStream {
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match *self {
+            ProxyStream::Tcp(ref stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref stream) => stream.shutdown(how)
+        }
+    }
+}
+
+impl Read for ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ProxyStream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref mut stream) => stream.read(buf)
+        }
+    }
+}
+
+impl Write for ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ProxyStream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref mut stream) => stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ProxyStream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref mut stream) => stream.flush()
+        }
+    }
+}
+
+impl AsyncRead for ProxyStream {}
+
+impl AsyncWrite for ProxyStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            ProxyStream::Tcp(ref mut stream) => AsyncWrite::shutdown(stream),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref mut stream) => AsyncWrite::shutdown(stream)
+        }
+    }
+}
+
+impl Read for &ProxyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match **self {
+            ProxyStream::Tcp(ref stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref stream) => (&*stream).read(buf)
+        }
+    }
+}
+
+impl Write for &ProxyStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match **self {
+            ProxyStream::Tcp(ref stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref stream) => (&*stream).write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match **self {
+            ProxyStream::Tcp(ref stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref stream) => (&*stream).flush()
+        }
+    }
+}
+
+impl AsyncRead for &ProxyStream {}
+
+impl AsyncWrite for &ProxyStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
+
+impl HalfClose for ProxyStream {
+    fn poll_shutdown_write(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            ProxyStream::Tcp(ref mut stream) => stream.poll_shutdown_write(),
+            #[cfg(unix)]
+            ProxyStream::Unix(ref mut stream) => stream.poll_shutdown_write()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    pub addr: ProxyAddr,
+    pub credentials: Option<Credentials>,
+    pub kind: ProxyKind
+}
+
+impl Proxy {
+    pub fn new(addr: SocketAddr) -> Proxy {
+        Proxy::with_addr(ProxyAddr::Tcp(addr))
+    }
+
+    // Proxy listening on a socket path
+    #[cfg(unix)]
+    pub fn unix<P: AsRef<Path>>(path: P) -> Proxy {
+        Proxy::with_addr(ProxyAddr::Unix(path.as_ref().to_path_buf()))
+    }
+
+    pub fn with_addr(addr: ProxyAddr) -> Proxy {
+        Proxy {
+            addr,
+            credentials: None,
+            kind: ProxyKind::Socks5
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Credentials) -> Proxy {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn with_kind(mut self, kind: ProxyKind) -> Proxy {
+        self.kind = kind;
+        self
+    }
+
+    // An HTTP proxy knows CONNECT only
+    pub fn supports(&self, command: Command) -> bool {
+        match self.kind {
+            ProxyKind::Socks5 => true,
+            ProxyKind::Http => command == Command::Connect
+        }
+    }
+
+    // Open a connection to this proxy
+    pub fn connect(&self, handle: &Handle) -> ProxyConnect {
+        match self.addr {
+            ProxyAddr::Tcp(ref addr) => Box::new(TcpStream::connect(addr, handle)
+                                                    .map(ProxyStream::Tcp)),
+            #[cfg(unix)]
+            ProxyAddr::Unix(ref path) => Box::new(UnixStream::connect(path)
+                                                     .map(ProxyStream::Unix))
+        }
+    }
+
+    // Send the request to this proxy over stream as per its kind.
+    // The reply is in socks5 format for both kinds.
+    pub fn handshake<S>(&self, stream: S, request: SocksRequestResponse) -> ProxyHandshake<S>
+            where S: AsyncRead + AsyncWrite + 'static {
+        let credentials = self.credentials.clone();
+        match self.kind {
+            ProxyKind::Socks5 => Box::new(socks_connect_handshake_with_credentials(stream, request,
+                                                                                   credentials)),
+            ProxyKind::Http => Box::new(http_connect_handshake(stream, request, credentials))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ChainError {
+    // Index of the failing proxy in the chain
+    pub hop: usize,
+    pub proxy: ProxyAddr,
+    // Reply code, if the proxy has answered the request with a failure
+    pub reply: Option<ReplyCode>,
+    pub error: io::Error
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Proxy chain failed at hop {} ({}): {}", self.hop, self.proxy, self.error)?;
+        if let Some(reply) = self.reply {
+            write!(f, " (reply {:?})", reply)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ChainError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<ChainError> for io::Error {
+    fn from(e: ChainError) -> io::Error {
+        Error::new(e.error.kind(), e.to_string())
+    }
+}
+
+pub type ChainConnect = Box<dyn Future<Item=(ProxyStream,Vec<SocksRequestResponse>), Error=ChainError>>;
+
+#[derive(Clone, Debug)]
+pub struct ProxyChain {
+    pub proxies: Vec<Proxy>
+}
+
+impl ProxyChain {
+    pub fn new(proxies: Vec<Proxy>) -> ProxyChain {
+        ProxyChain { proxies }
+    }
+
+    pub fn connect(&self, request: SocksRequestResponse, handle: &Handle) -> ChainConnect {
+        let proxies = self.proxies.clone();
+        let first = match proxies.first() {
+            Some(proxy) => proxy.clone(),
+            None => return Box::new(future::err(ChainError {
+                hop: 0,
+                proxy: ProxyAddr::Tcp(([0,0,0,0],0).into()),
+                reply: None,
+                error: Error::new(ErrorKind::InvalidInput, "Empty proxy chain")
+            }))
+        };
+        // Every further hop is requested by its predecessor with CONNECT
+        let mut next_hops = vec!();
+        for (hop, proxy) in proxies.iter().enumerate().skip(1) {
+            match proxy.addr {
+                ProxyAddr::Tcp(addr) => next_hops.push(addr),
+                #[cfg(unix)]
+                ProxyAddr::Unix(_) => return Box::new(future::err(ChainError {
+                    hop,
+                    proxy: proxy.addr.clone(),
+                    reply: None,
+                    error: Error::new(ErrorKind::InvalidInput,
+                                      "A unix socket proxy can only be the first hop")
+                }))
+            }
+        }
+        let first_addr = first.addr.clone();
+        Box::new(first.connect(handle)
+            .map_err(move |error| ChainError { hop: 0, proxy: first_addr, reply: None, error })
+            .and_then(move |stream| {
+                future::loop_fn((stream,0,vec!()), move |(stream,hop,mut replies)| {
+                    let proxy = proxies[hop].clone();
+                    let last = hop + 1 == proxies.len();
+                    let hop_request = if last {
+                        request.clone()
+                    }
+                    else {
+                        SocksRequestResponse::request(Command::Connect, &next_hops[hop])
+                    };
+                    proxy.handshake(stream, hop_request)
+                        .then(move |res| {
+                            let (stream,reply) = res.map_err(|error| {
+                                ChainError { hop, proxy: proxy.addr.clone(), reply: None, error }
+                            })?;
+                            let code = reply.reply_code();
+                            if code != ReplyCode::Succeeded {
+                                return Err(ChainError {
+                                    hop,
+                                    proxy: proxy.addr,
+                                    reply: Some(code),
+                                    error: Error::new(ErrorKind::Other, "Proxy request failed")
+                                });
+                            }
+                            replies.push(reply);
+                            if last {
+                                Ok(Loop::Break((stream,replies)))
+                            }
+                            else {
+                                Ok(Loop::Continue((stream,hop+1,replies)))
+                            }
+                        })
+                })
+            }))
+    }
+}
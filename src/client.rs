@@ -0,0 +1,79 @@
+// Client side request building
+// ============================
+//
+// socks_connect_handshake sends the request bytes as given. ClientConfig
+// builds the CONNECT request for a hostname and port:
+//
+// - DnsMode::Remote passes hostnames unchanged as ATYP_DOMAIN, so the
+//   proxy resolves them (socks5h semantics). This is the default.
+// - DnsMode::Local resolves hostnames with the given Resolver and sends
+//   the first address as ATYP_IPV4 or ATYP_IPV6 (socks5 semantics). No
+//   address at all fails with ErrorKind::NotFound.
+//
+// Literal ip addresses are always sent as ATYP_IPV4 or ATYP_IPV6.
+//
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio_core::net::TcpStream;
+use futures::{future, Future};
+use resolver::Resolver;
+use socks_fut::{socks_connect_handshake, Command, SocksRequestResponse};
+
+#[derive(Clone)]
+pub enum DnsMode {
+    Remote,
+    Local(Arc<dyn Resolver>)
+}
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    pub dns: DnsMode
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            dns: DnsMode::Remote
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn local_dns(resolver: Arc<dyn Resolver>) -> ClientConfig {
+        ClientConfig {
+            dns: DnsMode::Local(resolver)
+        }
+    }
+
+    pub fn request(&self, host: &str, port: u16)
+                        -> Box<dyn Future<Item=SocksRequestResponse, Error=io::Error>> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            let addr = SocketAddr::new(ip, port);
+            return Box::new(future::ok(SocksRequestResponse::request(Command::Connect, &addr)));
+        }
+        match self.dns {
+            DnsMode::Remote => {
+                Box::new(future::result(
+                    SocksRequestResponse::request_hostname(Command::Connect, host, port)))
+            },
+            DnsMode::Local(ref resolver) => {
+                Box::new(resolver.resolve(host, port)
+                    .and_then(|addrs| match addrs.first() {
+                        Some(addr) => Ok(SocksRequestResponse::request(Command::Connect, addr)),
+                        None => Err(Error::new(ErrorKind::NotFound, "No address for hostname"))
+                    }))
+            }
+        }
+    }
+}
+
+// Build the request for host and port as per config and perform
+// the handshake with the proxy on stream
+pub fn socks_connect_host(stream: TcpStream, host: &str, port: u16, config: &ClientConfig)
+            -> Box<dyn Future<Item=(TcpStream,SocksRequestResponse), Error=io::Error>> {
+    Box::new(config.request(host, port)
+        .and_then(move |request| socks_connect_handshake(stream, request)))
+}
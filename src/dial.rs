@@ -0,0 +1,239 @@
+// Step 5 of the socks5 handshake on server side
+// =============================================
+//
+// socks_dial takes the client stream and request as delivered by
+// SocksHandshake, establishes the connection to the destination and
+// answers the request. Hostnames are resolved with the given Resolver.
+// The resolved addresses are raced with happy_eyeballs. BND.ADDR of the
+// reply is the destination address, which has won the race.
+//
+// On success the future delivers the client and the destination stream.
+// On failure the client receives the matching reply code and the future
+// fails with the error. Resolution failures are answered with
+// REP_HOST_UNREACHABLE. Replies are sent in the protocol of the request.
+// Early data of the request is sent to the destination before the reply.
+// With with_proxy_header, a PROXY protocol header with the given source
+// is sent to the destination first. With with_metrics, the reply code and
+// the time until the destination is connected are recorded.
+//
+// With with_acl, the resolved addresses of a hostname are checked against
+// the acl (Acl::check_resolved) and only allowed addresses are connected.
+// If no address is allowed, the client receives REP_NOT_ALLOWED. Requests
+// with an ip address are checked by the handshake.
+//
+// Only CONNECT is supported. BIND and UDP ASSOCIATE are answered with
+// REP_CMD_NOT_SUPPORTED.
+//
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{write_all, WriteAll};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use futures::*;
+use futures::Async;
+use acl::{Acl, Action};
+use happy_eyeballs::{happy_eyeballs, HappyEyeballs, DEFAULT_ATTEMPT_DELAY_MS};
+use metrics::Metrics;
+use proxy_protocol::{ProxyHeader, ProxyVersion};
+use resolver::{Resolver, ResolveFuture};
+use socks_fut::{Command, Protocol, ReplyCode, SocksRequestResponse};
+
+enum DialState<S> {
+    Resolve(ResolveFuture),
+    Connect(HappyEyeballs),
+    SendEarlyData(WriteAll<TcpStream,Vec<u8>>,SocketAddr),
+    SendReply(WriteAll<S,Vec<u8>>, Option<TcpStream>),
+    SendFailure(WriteAll<S,Vec<u8>>,ReplyCode),
+    Done
+}
+
+pub struct SocksDial<S = TcpStream> {
+    client: Option<S>,
+    state: DialState<S>,
+    handle: Handle,
+    request: SocksRequestResponse,
+    acl: Option<(Arc<Acl>,Option<IpAddr>)>,
+    protocol: Protocol,
+    early_data: Vec<u8>,
+    proxy_header: Option<(ProxyVersion,SocketAddr)>,
+    attempt_delay: Duration,
+    started: Instant,
+    metrics: Option<Arc<Metrics>>,
+    error: Option<io::Error>
+}
+
+// The client stream can be any stream, e.g. a TLS stream
+pub fn socks_dial<S>(client: S, request: &SocksRequestResponse,
+                     handle: &Handle, resolver: Arc<dyn Resolver>) -> SocksDial<S>
+        where S: AsyncRead + AsyncWrite {
+    let mut dial = SocksDial {
+        client: Some(client),
+        state: DialState::Done,
+        handle: handle.clone(),
+        request: request.clone(),
+        acl: None,
+        protocol: request.protocol,
+        early_data: request.early_data.clone(),
+        proxy_header: None,
+        attempt_delay: Duration::from_millis(DEFAULT_ATTEMPT_DELAY_MS),
+        started: Instant::now(),
+        metrics: None,
+        error: None
+    };
+    dial.state = if request.command() != Command::Connect {
+        dial.fail(ReplyCode::CommandNotSupported,
+                  Error::new(ErrorKind::Other, "Socks5 command not supported"))
+    }
+    else if let Some(addr) = request.socketaddr() {
+        DialState::Connect(happy_eyeballs(vec![addr], handle))
+    }
+    else {
+        match request.hostname().map(str::from_utf8) {
+            Some(Ok(host)) => DialState::Resolve(resolver.resolve(host, request.port())),
+            _ => dial.fail(ReplyCode::HostUnreachable,
+                           Error::new(ErrorKind::InvalidData, "Invalid hostname in request"))
+        }
+    };
+    dial
+}
+
+impl<S: AsyncRead + AsyncWrite> SocksDial<S> {
+    // Delay between the connection attempts to the resolved addresses
+    pub fn with_attempt_delay(mut self, attempt_delay: Duration) -> SocksDial<S> {
+        self.attempt_delay = attempt_delay;
+        self
+    }
+
+    // Announce source as origin of the connection to the destination
+    pub fn with_proxy_header(mut self, version: ProxyVersion, source: SocketAddr) -> SocksDial<S> {
+        self.proxy_header = Some((version, source));
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> SocksDial<S> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Connect only to resolved addresses allowed for the client at source
+    pub fn with_acl(mut self, acl: Arc<Acl>, source: Option<IpAddr>) -> SocksDial<S> {
+        self.acl = Some((acl, source));
+        self
+    }
+
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        match self.acl {
+            Some((ref acl, source)) => {
+                let user = self.request.user.as_deref();
+                acl.check_resolved(source, &self.request, addr.ip(), user) == Action::Allow
+            },
+            None => true
+        }
+    }
+
+    fn fail(&mut self, code: ReplyCode, error: io::Error) -> DialState<S> {
+        self.error = Some(error);
+        let reply = self.protocol.reply(code, None);
+        let client = self.client.take().expect("client stream already consumed");
+        DialState::SendFailure(write_all(client, reply), code)
+    }
+
+    fn succeed(&mut self, target: TcpStream, addr: SocketAddr) -> DialState<S> {
+        let reply = self.protocol.reply(ReplyCode::Succeeded, Some(addr));
+        let client = self.client.take().expect("client stream already consumed");
+        DialState::SendReply(write_all(client, reply), Some(target))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for SocksDial<S> {
+    type Item = (S,TcpStream);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::DialState::*;
+
+        loop {
+            self.state = match self.state {
+                Resolve(ref mut fut) => {
+                    match fut.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(ref addrs)) if addrs.is_empty() => {
+                            self.fail(ReplyCode::HostUnreachable,
+                                      Error::new(ErrorKind::NotFound, "No address for hostname"))
+                        },
+                        Ok(Async::Ready(addrs)) => {
+                            let addrs: Vec<SocketAddr> = addrs.into_iter()
+                                                              .filter(|addr| self.is_allowed(addr))
+                                                              .collect();
+                            if addrs.is_empty() {
+                                self.fail(ReplyCode::NotAllowed,
+                                          Error::new(ErrorKind::PermissionDenied,
+                                                     "Resolved address not allowed"))
+                            }
+                            else {
+                                Connect(happy_eyeballs(addrs, &self.handle)
+                                            .with_attempt_delay(self.attempt_delay))
+                            }
+                        },
+                        Err(e) => self.fail(ReplyCode::HostUnreachable, e)
+                    }
+                },
+                Connect(ref mut fut) => {
+                    match fut.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready((target,addr))) => {
+                            if let Some(ref metrics) = self.metrics {
+                                metrics.record_dial(self.started.elapsed());
+                            }
+                            let mut data = match self.proxy_header {
+                                Some((version, source)) => {
+                                    ProxyHeader::new(source, addr).encode(version)
+                                },
+                                None => vec!()
+                            };
+                            data.append(&mut self.early_data);
+                            if data.is_empty() {
+                                self.succeed(target, addr)
+                            }
+                            else {
+                                SendEarlyData(write_all(target, data), addr)
+                            }
+                        },
+                        Err(e) => self.fail(ReplyCode::from_io_error(&e), e)
+                    }
+                },
+                SendEarlyData(ref mut fut, addr) => {
+                    match fut.poll() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready((target,_buf))) => self.succeed(target, addr),
+                        Err(e) => self.fail(ReplyCode::from_io_error(&e), e)
+                    }
+                },
+                SendReply(ref mut fut, ref mut target) => {
+                    let (client,_buf) = try_ready!(fut.poll());
+                    let target = target.take().expect("poll after completion");
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.record_reply_sent(ReplyCode::Succeeded);
+                    }
+                    self.state = Done;
+                    return Ok(Async::Ready((client,target)));
+                },
+                SendFailure(ref mut fut, code) => {
+                    try_ready!(fut.poll());
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.record_reply_sent(code);
+                    }
+                    self.state = Done;
+                    return Err(self.error.take().expect("poll after completion"));
+                },
+                Done => panic!("poll after completion")
+            }
+        }
+    }
+}
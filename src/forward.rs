@@ -0,0 +1,264 @@
+// Socks5 forwarder
+// ================
+//
+// The Forwarder accepts the socks5 handshake of a client and passes the
+// request unchanged to an upstream socks5 proxy or as CONNECT to an upstream
+// HTTP proxy (see Proxy::handshake). The reply of the upstream
+// proxy, including its reply code, is written back to the client. If no
+// upstream proxy can be reached or the upstream handshake fails without
+// reply, the client receives REP_GENERAL_FAILURE. A request of a SOCKS4
+// client is forwarded as socks5 request and the reply is converted back.
+//
+// The upstream proxy is selected from an UpstreamPool. A command, which no
+// upstream supports (e.g. BIND with HTTP upstreams only), is answered with
+// REP_CMD_NOT_SUPPORTED before any upstream is chosen. If the connection or
+// the handshake to the selected upstream fails, this is reported to the pool
+// and the next upstream is tried, until all upstreams have been tried. A
+// request rejected locally (ErrorKind::InvalidInput) is not reported.
+//
+// After a successful reply, early data of the request is sent upstream and
+// the two streams are relayed until both directions are closed or the
+// optional idle timeout expires. On Linux the relay uses splice(2) for TCP
+// upstreams.
+// Bandwidth limits apply to the relay, per user with the username of the
+// request. With ConnectionLimits, the client is admitted before the
+// handshake and the tunnel after the request. With Metrics, all stages
+// record into the registry, the dial latency is the time until the reply of
+// the upstream proxy. Each completed tunnel is logged as AccessLogEntry.
+//
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_io::io::write_all;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use futures::{future, Future};
+use futures::future::{Either, Loop};
+use access_log::AccessLogEntry;
+use bandwidth::{BandwidthLimiter, Throttle};
+use chain::{Proxy, ProxyStream};
+use limits::{admit_optional, establish_optional, ConnectionLimits};
+use metrics::Metrics;
+use relay::{relay, RelayConfig, RelayStats};
+#[cfg(target_os = "linux")]
+use splice::splice_relay;
+use socks_fut::{socks_handshake, Protocol, ReplyCode, SocksHandshake, SocksRequestResponse};
+use upstream::{Lease, Policy, UpstreamPool};
+
+pub type ForwardFuture = Box<dyn Future<Item=RelayStats, Error=io::Error>>;
+
+type UpstreamFuture = Box<dyn Future<Item=(Lease,ProxyStream,SocksRequestResponse), Error=io::Error>>;
+
+#[derive(Clone)]
+pub struct Forwarder {
+    pub pool: Arc<UpstreamPool>,
+    relay: RelayConfig,
+    limits: Option<Arc<ConnectionLimits>>
+}
+
+impl Forwarder {
+    pub fn new(upstream: SocketAddr) -> Forwarder {
+        Forwarder::with_pool(Arc::new(UpstreamPool::new(vec![Proxy::new(upstream)],
+                                                        Policy::Failover)))
+    }
+
+    pub fn with_pool(pool: Arc<UpstreamPool>) -> Forwarder {
+        Forwarder { pool, relay: RelayConfig::default(), limits: None }
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Forwarder {
+        self.relay.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> Forwarder {
+        self.relay.bandwidth = Some(limiter);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Arc<ConnectionLimits>) -> Forwarder {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Forwarder {
+        self.relay.metrics = Some(metrics);
+        self
+    }
+
+    // Delivers the number of bytes sent from client to upstream and
+    // from upstream to client and the duration of the relay.
+    pub fn serve(&self, client: TcpStream, handle: &Handle) -> ForwardFuture {
+        let pool = self.pool.clone();
+        let handle = handle.clone();
+        let config = self.relay.clone();
+        let limits = self.limits.clone();
+        let metrics = self.relay.metrics.clone();
+        let peer = client.peer_addr().ok();
+        let source = peer.map(|addr| addr.ip());
+        Box::new(admit_optional(&limits, source)
+            .and_then(move |permit| {
+                handshake(client, metrics).map(move |(client,request)| (client,request,permit))
+            })
+            .and_then(move |(client,request,permit)| {
+                establish_optional(&limits, permit, source, client, request)
+            })
+            .and_then(move |(client,request,permit)| {
+                forward_request(client, request, peer, pool, &config, &handle)
+                    .then(move |res| {
+                        drop(permit);
+                        res
+                    })
+            }))
+    }
+}
+
+// Forward an already received request to an upstream of the pool and
+// answer the client with the upstream's reply. peer is the client address
+// for the access log, e.g. from a PROXY protocol header.
+pub fn forward_request(client: TcpStream, request: SocksRequestResponse,
+                       peer: Option<SocketAddr>, pool: Arc<UpstreamPool>, config: &RelayConfig,
+                       handle: &Handle) -> ForwardFuture {
+    let protocol = request.protocol;
+    let early_data = request.early_data.clone();
+    let logged = request.clone();
+    let config = config.clone();
+    let relay_handle = handle.clone();
+    let metrics = config.metrics.clone();
+    let started = Instant::now();
+    Box::new(connect_upstream(pool, request, handle.clone())
+        .then(move |res| Ok((client,res)))
+        .and_then(move |(client,upstream)| match upstream {
+            Ok((lease,stream,reply)) => {
+                let code = reply.reply_code();
+                if let Some(ref metrics) = metrics {
+                    metrics.record_reply_received(code);
+                    if code == ReplyCode::Succeeded {
+                        metrics.record_dial(started.elapsed());
+                    }
+                    metrics.record_reply_sent(code);
+                }
+                let answer = match protocol {
+                    Protocol::Socks5 => reply.bytes,
+                    _ => protocol.reply(code, reply.socketaddr())
+                };
+                Either::A(write_all(client,answer)
+                    .and_then(move |(client,_buf)| {
+                        if code == ReplyCode::Succeeded {
+                            Either::A(write_all(stream,early_data)
+                                .and_then(move |(stream,_buf)| {
+                                    splice(client, stream, peer, &logged, &config, &relay_handle)
+                                })
+                                .then(move |res| {
+                                    drop(lease);
+                                    res
+                                }))
+                        }
+                        else {
+                            Either::B(future::err(io::Error::new(io::ErrorKind::Other,
+                                format!("Upstream proxy replied {:?}", code))))
+                        }
+                    }))
+            },
+            Err(e) => {
+                let code = match e.kind() {
+                    io::ErrorKind::Unsupported => ReplyCode::CommandNotSupported,
+                    _ => ReplyCode::GeneralFailure
+                };
+                if let Some(ref metrics) = metrics {
+                    metrics.record_reply_sent(code);
+                }
+                let reply = protocol.reply(code, None);
+                Either::B(write_all(client,reply)
+                    .and_then(move |_| Err(e)))
+            }
+        }))
+}
+
+// Try the upstreams as selected by the pool, until one accepts the handshake
+fn connect_upstream(pool: Arc<UpstreamPool>, request: SocksRequestResponse, handle: Handle)
+                                                                    -> UpstreamFuture {
+    if !pool.supports(request.command()) {
+        return Box::new(future::err(io::Error::new(io::ErrorKind::Unsupported,
+                                                   "No upstream proxy supports the command")));
+    }
+    Box::new(future::loop_fn(vec!(), move |mut tried: Vec<usize>| {
+        let lease = match UpstreamPool::select(&pool, &request, &tried) {
+            Some(lease) => lease,
+            None => return Either::B(future::err(io::Error::new(io::ErrorKind::Other,
+                                                 "No upstream proxy available")))
+        };
+        tried.push(lease.index);
+        let proxy = lease.proxy.clone();
+        let request = request.clone();
+        Either::A(lease.proxy.connect(&handle)
+            .and_then(move |stream| proxy.handshake(stream, request))
+            .then(move |res| match res {
+                Ok((stream,reply)) => {
+                    lease.report_success();
+                    Ok(Loop::Break((lease,stream,reply)))
+                },
+                // Rejected before anything has been sent, no fault of the upstream
+                Err(e) => if e.kind() == io::ErrorKind::InvalidInput {
+                    Err(e)
+                }
+                else {
+                    lease.report_failure();
+                    Ok(Loop::Continue(tried))
+                }
+            }))
+    }))
+}
+
+pub(crate) fn splice(client: TcpStream, upstream: ProxyStream, peer: Option<SocketAddr>,
+                     request: &SocksRequestResponse, config: &RelayConfig,
+                     handle: &Handle) -> ForwardFuture {
+    let user = request.user.as_deref();
+    let throttle = config.bandwidth.as_ref()
+                         .map(|limiter| Throttle::new(limiter.clone(), user, handle));
+    let relay: ForwardFuture = match upstream {
+        // Zero-copy on Linux
+        #[cfg(target_os = "linux")]
+        ProxyStream::Tcp(upstream) => {
+            let mut relay = splice_relay(client, upstream, handle);
+            if let Some(timeout) = config.idle_timeout {
+                relay = relay.with_idle_timeout(timeout, handle);
+            }
+            if let Some(throttle) = throttle {
+                relay = relay.with_throttle(throttle);
+            }
+            Box::new(relay)
+        },
+        upstream => {
+            let mut relay = relay(client, upstream);
+            if let Some(timeout) = config.idle_timeout {
+                relay = relay.with_idle_timeout(timeout, handle);
+            }
+            if let Some(throttle) = throttle {
+                relay = relay.with_throttle(throttle);
+            }
+            Box::new(relay)
+        }
+    };
+    let metrics = config.metrics.clone();
+    let tunnel = metrics.as_ref().map(Metrics::tunnel);
+    let request = request.clone();
+    Box::new(relay.map(move |stats| {
+        drop(tunnel);
+        if let Some(ref metrics) = metrics {
+            metrics.record_relay(&stats);
+        }
+        AccessLogEntry::new(peer, &request, stats).log();
+        stats
+    }))
+}
+
+// Server handshake, which records into the metrics if given
+pub(crate) fn handshake(client: TcpStream, metrics: Option<Arc<Metrics>>) -> SocksHandshake {
+    match metrics {
+        Some(metrics) => socks_handshake(client).with_metrics(metrics),
+        None => socks_handshake(client)
+    }
+}
@@ -0,0 +1,141 @@
+// Connection racing as per RFC 8305 (Happy Eyeballs v2)
+// =====================================================
+//
+// The addresses are sorted by interleaving the address families, starting
+// with the family of the first address. Thus the order chosen by the
+// resolver (e.g. IpPreference) is respected.
+//
+// The first connection attempt is started immediately. Every further attempt
+// is started after the attempt delay or as soon as the previous attempt has
+// failed, whichever comes first. The attempts in progress are not cancelled.
+// The first established connection wins and all other attempts are dropped.
+//
+// As the Resolver delivers all addresses at once, the resolution delay of
+// RFC 8305 is not applicable.
+//
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::{Handle, Timeout};
+use futures::*;
+use futures::Async;
+
+// Recommended value of RFC 8305
+pub const DEFAULT_ATTEMPT_DELAY_MS: u64 = 250;
+
+pub struct HappyEyeballs {
+    handle: Handle,
+    pending: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr,TcpStreamNew)>,
+    timer: Option<Timeout>,
+    attempt_delay: Duration,
+    error: Option<io::Error>
+}
+
+pub fn happy_eyeballs(addrs: Vec<SocketAddr>, handle: &Handle) -> HappyEyeballs {
+    HappyEyeballs {
+        handle: handle.clone(),
+        pending: interleave(addrs).into(),
+        attempts: vec!(),
+        timer: None,
+        attempt_delay: Duration::from_millis(DEFAULT_ATTEMPT_DELAY_MS),
+        error: None
+    }
+}
+
+// Alternate the address families, starting with the family of the first address
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs
+    };
+    let (mut first, mut second): (VecDeque<_>,VecDeque<_>) =
+                addrs.into_iter().partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut sorted = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return sorted,
+            (a, b) => {
+                sorted.extend(a);
+                sorted.extend(b);
+            }
+        }
+    }
+}
+
+impl HappyEyeballs {
+    pub fn with_attempt_delay(mut self, attempt_delay: Duration) -> HappyEyeballs {
+        self.attempt_delay = attempt_delay;
+        self
+    }
+
+    fn start_next_attempt(&mut self) -> io::Result<bool> {
+        match self.pending.pop_front() {
+            Some(addr) => {
+                self.attempts.push((addr, TcpStream::connect(&addr, &self.handle)));
+                self.timer = if self.pending.is_empty() {
+                    None
+                }
+                else {
+                    Some(Timeout::new(self.attempt_delay, &self.handle)?)
+                };
+                Ok(true)
+            },
+            None => {
+                self.timer = None;
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl Future for HappyEyeballs {
+    // The established connection and the address, which has won the race
+    type Item = (TcpStream,SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        if self.attempts.is_empty() && !self.start_next_attempt()? {
+            return Err(self.error.take().unwrap_or_else(||
+                        Error::new(ErrorKind::NotFound, "No address to connect to")));
+        }
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(stream)) => {
+                        let addr = self.attempts[i].0;
+                        self.attempts.clear();
+                        self.timer = None;
+                        return Ok(Async::Ready((stream,addr)));
+                    },
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        let (_addr,_attempt) = self.attempts.swap_remove(i);
+                        self.error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+            let timer_expired = match self.timer {
+                Some(ref mut timer) => timer.poll()?.is_ready(),
+                None => false
+            };
+            if failed || timer_expired {
+                if self.start_next_attempt()? {
+                    continue;
+                }
+                if self.attempts.is_empty() {
+                    return Err(self.error.take().unwrap_or_else(||
+                                Error::new(ErrorKind::NotFound, "No address to connect to")));
+                }
+            }
+            return Ok(Async::NotReady);
+        }
+    }
+}
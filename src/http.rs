@@ -0,0 +1,452 @@
+// HTTP proxy requests
+// ===================
+//
+// http_proxy_handshake reads the request head of an HTTP proxy client and
+// delivers the equivalent socks5 CONNECT request:
+//
+// - CONNECT host:port opens a tunnel. Once the destination is connected,
+//   the client receives "200 Connection established".
+// - A request with absolute URI (GET http://host/path) is forwarded. The
+//   head is rewritten to origin form with "Connection: close" and is sent
+//   to the destination as early data. The response of the destination is
+//   the answer to the client, so only one request per connection is served.
+//
+// Only the http scheme is supported in absolute URIs. Malformed requests
+// and heads larger than MAX_HEAD_SIZE are answered with "400 Bad Request".
+// With an Authenticator, the client has to send Proxy-Authorization Basic
+// with valid credentials, otherwise it receives "407 Proxy Authentication
+// Required". The username is stored in the request like for socks5, so the
+// acl, routes and bandwidth limits can use it.
+// Failures after the handshake are answered as per Protocol::reply,
+// e.g. "502 Bad Gateway".
+//
+// On client side, http_connect_handshake asks an HTTP proxy to CONNECT to
+// the destination of a socks5 request. With credentials, they are sent as
+// Proxy-Authorization Basic. The status of the response is delivered as
+// socks5 reply (see ReplyCode::from_http_status), so HTTP proxies can be
+// used wherever socks_connect_handshake is used. The response head is read
+// byte by byte, so no data of the tunnel is consumed.
+//
+
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, write_all, ReadExact, WriteAll};
+use tokio_core::net::TcpStream;
+use futures::*;
+use futures::Async;
+use acl::{Acl, Action};
+use auth::Authenticator;
+use socks_fut::{Command, Credentials, Protocol, ReplyCode, SocksRequestResponse};
+
+pub const MAX_HEAD_SIZE: usize = 8192;
+
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+const AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+                               Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+                               Content-Length: 0\r\nConnection: close\r\n\r\n";
+
+// Headers, which apply to the connection to the proxy only
+const HOP_HEADERS: [&str; 4] = ["connection", "keep-alive", "proxy-connection", "proxy-authorization"];
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+enum HttpState {
+    ReadHead(Option<TcpStream>),
+    AnswerError(WriteAll<TcpStream,Vec<u8>>),
+    Done
+}
+
+enum HttpClientState<S> {
+    SendRequest(WriteAll<S,Vec<u8>>),
+    ReadResponse(ReadExact<S,Vec<u8>>),
+    Failed(Option<io::Error>)
+}
+
+pub struct HttpProxyHandshake {
+    head: Vec<u8>,
+    state: HttpState,
+    peer: Option<SocketAddr>,
+    acl: Option<Arc<Acl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    error: Option<io::Error>
+}
+
+pub fn http_proxy_handshake(stream: TcpStream) -> HttpProxyHandshake {
+    HttpProxyHandshake {
+        head: Vec::with_capacity(1024),
+        peer: stream.peer_addr().ok(),
+        state: HttpState::ReadHead(Some(stream)),
+        acl: None,
+        authenticator: None,
+        error: None
+    }
+}
+
+impl HttpProxyHandshake {
+    // Requests denied by the acl are answered with "403 Forbidden"
+    // and the future fails with ErrorKind::PermissionDenied.
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> HttpProxyHandshake {
+        self.acl = Some(acl);
+        self
+    }
+
+    // Requests without valid Proxy-Authorization are answered with
+    // "407 Proxy Authentication Required" and the future fails with
+    // ErrorKind::PermissionDenied.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>)
+                                                            -> HttpProxyHandshake {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    // The verified username, None without authenticator.
+    // Err, if the credentials are missing or wrong.
+    fn authenticate(&self, head: &[u8]) -> Result<Option<String>,()> {
+        let authenticator = match self.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => return Ok(None)
+        };
+        match proxy_credentials(head) {
+            Some(credentials) if authenticator.authenticate(&credentials.username,
+                                                            &credentials.password) => {
+                Ok(Some(credentials.username))
+            },
+            _ => Err(())
+        }
+    }
+
+    fn is_allowed(&self, request: &SocksRequestResponse) -> bool {
+        match self.acl {
+            Some(ref acl) => {
+                let source = self.peer.map(|addr| addr.ip());
+                acl.check(source, request, request.user.as_deref()) == Action::Allow
+            },
+            None => true
+        }
+    }
+
+    // Deliver the complete request or answer the client with the failure
+    fn finish(&mut self, stream: TcpStream, end: usize)
+                        -> Result<(TcpStream,SocksRequestResponse),HttpState> {
+        let mut request = match parse_head(&self.head[..end]) {
+            Ok(request) => request,
+            Err(e) => return Err(self.fail(stream, BAD_REQUEST.to_vec(), e))
+        };
+        request.user = match self.authenticate(&self.head[..end]) {
+            Ok(user) => user,
+            Err(()) => return Err(self.fail(stream, AUTH_REQUIRED.to_vec(),
+                                            Error::new(ErrorKind::PermissionDenied,
+                                                       "HTTP proxy authentication failed")))
+        };
+        if !self.is_allowed(&request) {
+            let answer = request.protocol.reply(ReplyCode::NotAllowed, None);
+            return Err(self.fail(stream, answer,
+                                 Error::new(ErrorKind::PermissionDenied, "HTTP request not allowed")));
+        }
+        // Data following the head belongs to the destination
+        request.early_data.extend_from_slice(&self.head[end..]);
+        Ok((stream,request))
+    }
+
+    fn fail(&mut self, stream: TcpStream, answer: Vec<u8>, error: io::Error) -> HttpState {
+        self.error = Some(error);
+        HttpState::AnswerError(write_all(stream, answer))
+    }
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let data = data.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut n = 0u32;
+    for (i, &c) in data.iter().enumerate() {
+        n = n << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+        if i % 4 == 3 {
+            out.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8]);
+        }
+    }
+    match data.len() % 4 {
+        0 => (),
+        2 => out.push((n >> 4) as u8),
+        3 => out.extend_from_slice(&[(n >> 10) as u8, (n >> 2) as u8]),
+        _ => return None
+    }
+    Some(out)
+}
+
+// Credentials of the header "Proxy-Authorization: Basic <base64>"
+fn proxy_credentials(head: &[u8]) -> Option<Credentials> {
+    let head = str::from_utf8(head).ok()?;
+    let value = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("proxy-authorization") {
+            Some(value.trim())
+        }
+        else {
+            None
+        }
+    })?;
+    let (scheme, token) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(base64_decode(token.trim())?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(Credentials { username: username.to_string(), password: password.to_string() })
+}
+
+// Split "host:port" or "[v6]:port". Without port, the default is taken.
+fn split_authority(authority: &str, default_port: Option<u16>) -> io::Result<(&str,u16)> {
+    let invalid = || Error::new(ErrorKind::InvalidData,
+                                format!("Invalid authority '{}'", authority));
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority.find(']').ok_or_else(invalid)?;
+        (&authority[..end+1], &authority[end+1..])
+    }
+    else {
+        match authority.rfind(':') {
+            Some(i) => (&authority[..i], &authority[i..]),
+            None => (authority, "")
+        }
+    };
+    let port = match (port, default_port) {
+        ("", Some(port)) => port,
+        (p, _) if p.starts_with(':') => p[1..].parse::<u16>().map_err(|_| invalid())?,
+        _ => return Err(invalid())
+    };
+    if host.is_empty() || host.contains('@') {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+// Parse the complete head including the terminating empty line
+fn parse_head(head: &[u8]) -> io::Result<SocksRequestResponse> {
+    let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+    let head = str::from_utf8(head).map_err(|_| invalid("HTTP head is not utf-8"))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/1.") {
+        return Err(invalid("Invalid HTTP request line"));
+    }
+    let (method, target, version) = (parts[0], parts[1], parts[2]);
+    if method == "CONNECT" {
+        let (host, port) = split_authority(target, None)?;
+        let mut request = SocksRequestResponse::request_host(Command::Connect, host, port)?;
+        request.protocol = Protocol::HttpConnect;
+        return Ok(request);
+    }
+    if target.len() < 7 || !target[..7].eq_ignore_ascii_case("http://") {
+        return Err(invalid("HTTP proxy request needs an absolute http URI"));
+    }
+    let rest = &target[7..];
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let path = match &rest[end..] {
+        "" => "/".to_string(),
+        p if p.starts_with('?') => format!("/{}", p),
+        p => p.to_string()
+    };
+    let (host, port) = split_authority(authority, Some(80))?;
+    let mut request = SocksRequestResponse::request_host(Command::Connect, host, port)?;
+    let mut forward = format!("{} {} {}\r\n", method, path, version);
+    let mut has_host = false;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or("").trim().to_ascii_lowercase();
+        if HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        has_host |= name == "host";
+        forward.push_str(line);
+        forward.push_str("\r\n");
+    }
+    if !has_host {
+        forward.push_str(&format!("Host: {}\r\n", authority));
+    }
+    forward.push_str("Connection: close\r\n\r\n");
+    request.protocol = Protocol::HttpForward;
+    request.early_data = forward.into_bytes();
+    Ok(request)
+}
+
+impl Future for HttpProxyHandshake {
+    type Item = (TcpStream,SocksRequestResponse);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::HttpState::*;
+
+        loop {
+            self.state = match self.state {
+                ReadHead(ref mut stream) => {
+                    let mut chunk = [0u8; 1024];
+                    let n = match stream.as_mut().expect("poll after completion").read(&mut chunk) {
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                        Err(e) => return Err(e)
+                    };
+                    if n == 0 {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "Incomplete HTTP request"));
+                    }
+                    let start = self.head.len().saturating_sub(3);
+                    self.head.extend_from_slice(&chunk[..n]);
+                    let end = self.head[start..].windows(4)
+                                    .position(|w| w == b"\r\n\r\n")
+                                    .map(|i| start + i + 4);
+                    let stream = stream.take().expect("poll after completion");
+                    match end {
+                        None if self.head.len() > MAX_HEAD_SIZE => {
+                            self.fail(stream, BAD_REQUEST.to_vec(),
+                                      Error::new(ErrorKind::InvalidData, "HTTP head too large"))
+                        },
+                        None => ReadHead(Some(stream)),
+                        Some(end) => match self.finish(stream, end) {
+                            Ok(done) => {
+                                self.state = Done;
+                                return Ok(Async::Ready(done));
+                            },
+                            Err(state) => state
+                        }
+                    }
+                },
+                AnswerError(ref mut fut) => {
+                    try_ready!(fut.poll());
+                    self.state = Done;
+                    return Err(self.error.take().expect("poll after completion"));
+                },
+                Done => panic!("poll after completion")
+            }
+        }
+    }
+}
+
+pub struct HttpConnectHandshake<S = TcpStream> {
+    head: Vec<u8>,
+    state: HttpClientState<S>
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+              | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+              | (*chunk.get(2).unwrap_or(&0) as u32);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            }
+            else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Hostname as RFC 3986 reg-name or IP-literal, so that it can't inject
+// headers or requests into the CONNECT request
+fn is_valid_host(host: &str) -> bool {
+    let reg_name = |c: char| c.is_ascii_alphanumeric() || "-._~%!$&'()*+,;=".contains(c);
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(literal) => !literal.is_empty()
+                            && literal.chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.'),
+        None => !host.is_empty() && host.chars().all(reg_name)
+    }
+}
+
+// Build the CONNECT request for the destination of a socks5 request
+fn connect_head(request: &SocksRequestResponse, credentials: Option<&Credentials>)
+                                                            -> io::Result<Vec<u8>> {
+    if request.command() != Command::Connect {
+        return Err(Error::new(ErrorKind::InvalidInput, "HTTP proxy supports CONNECT only"));
+    }
+    let authority = match (request.socketaddr(), request.hostname()) {
+        (Some(addr), _) => addr.to_string(),
+        (None, Some(host)) => {
+            let host = str::from_utf8(host).ok()
+                        .filter(|host| is_valid_host(host))
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid hostname"))?;
+            format!("{}:{}", host, request.port())
+        },
+        (None, None) => return Err(Error::new(ErrorKind::InvalidInput,
+                                              "Unknown address type in request"))
+    };
+    let mut head = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(credentials) = credentials {
+        let token = base64(format!("{}:{}", credentials.username, credentials.password).as_bytes());
+        head.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    head.push_str("\r\n");
+    Ok(head.into_bytes())
+}
+
+// Status code of the status line "HTTP/1.x SSS reason"
+fn parse_status(head: &[u8]) -> io::Result<u16> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid HTTP response");
+    let line = head.split(|&c| c == b'\r').next().unwrap_or(&[]);
+    let line = str::from_utf8(line).map_err(|_| invalid())?;
+    let mut parts = line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => {
+            status.parse::<u16>().map_err(|_| invalid())
+        },
+        _ => Err(invalid())
+    }
+}
+
+pub fn http_connect_handshake<S>(stream: S, request: SocksRequestResponse,
+                                 credentials: Option<Credentials>) -> HttpConnectHandshake<S>
+        where S: AsyncRead + AsyncWrite {
+    let state = match connect_head(&request, credentials.as_ref()) {
+        Ok(head) => HttpClientState::SendRequest(write_all(stream, head)),
+        Err(e) => HttpClientState::Failed(Some(e))
+    };
+    HttpConnectHandshake {
+        head: Vec::with_capacity(256),
+        state
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for HttpConnectHandshake<S> {
+    type Item = (S,SocksRequestResponse);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::HttpClientState::*;
+
+        loop {
+            self.state = match self.state {
+                SendRequest(ref mut fut) => {
+                    let (stream,_buf) = try_ready!(fut.poll());
+                    ReadResponse(
+                        read_exact(stream,vec![0u8; 1])
+                    )
+                },
+                ReadResponse(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    self.head.push(buf[0]);
+                    if self.head.ends_with(b"\r\n\r\n") {
+                        let status = parse_status(&self.head)?;
+                        let reply = SocksRequestResponse::reply(ReplyCode::from_http_status(status),
+                                                                None);
+                        return Ok(Async::Ready((stream,reply)));
+                    }
+                    if self.head.len() > MAX_HEAD_SIZE {
+                        return Err(Error::new(ErrorKind::InvalidData, "HTTP response head too large"));
+                    }
+                    ReadResponse(
+                        read_exact(stream,buf)
+                    )
+                },
+                Failed(ref mut error) => {
+                    return Err(error.take().expect("poll after completion"));
+                }
+            }
+        }
+    }
+}
@@ -1,10 +1,81 @@
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
+extern crate tracing;
+#[cfg(unix)]
+extern crate tokio_uds;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(target_os = "linux")]
+extern crate mio;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(feature = "tls")]
+extern crate tokio_tls;
 
 mod socks_fut;
+mod acl;
+mod auth;
+mod resolver;
+mod happy_eyeballs;
+mod dial;
+mod client;
+mod chain;
+mod upstream;
+mod forward;
+mod routing;
+mod http;
+mod sniff;
+mod proxy_protocol;
+mod relay;
+mod bandwidth;
+mod limits;
+mod metrics;
+mod access_log;
+mod port_forward;
+mod reload;
+mod accept;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(target_os = "linux")]
+mod transparent;
 
+#[allow(dead_code)]
+mod v4;
 #[allow(dead_code)]
 mod v5;
 
-pub use socks_fut::*;
\ No newline at end of file
+pub use socks_fut::*;
+pub use acl::*;
+pub use auth::*;
+pub use resolver::*;
+pub use happy_eyeballs::*;
+pub use dial::*;
+pub use client::*;
+pub use chain::*;
+pub use upstream::*;
+pub use forward::*;
+pub use routing::*;
+pub use http::*;
+pub use sniff::*;
+pub use proxy_protocol::*;
+pub use relay::*;
+pub use bandwidth::*;
+pub use limits::*;
+pub use metrics::*;
+pub use access_log::*;
+pub use port_forward::*;
+pub use reload::*;
+pub use accept::*;
+#[cfg(feature = "tls")]
+pub use tls::*;
+#[cfg(unix)]
+pub use unix::*;
+#[cfg(target_os = "linux")]
+pub use splice::*;
+#[cfg(target_os = "linux")]
+pub use transparent::*;
@@ -0,0 +1,476 @@
+// Connection limits
+// =================
+//
+// ConnectionLimits restricts the number of concurrent
+//
+// - handshakes (accepted connections without complete request)
+// - tunnels (connections after the request)
+// - connections per source ip, handshakes and tunnels together
+// - tunnels per user
+//
+// A connection asks for admission before the handshake with admit and gets
+// a Permit. After the handshake, establish turns the Permit into a tunnel.
+// The Permit is held until the connection is closed, dropping it frees the
+// slots.
+//
+// If a limit is hit, the connection is rejected by default. With
+// with_queue, it waits instead until a slot is free. The queue holds at most
+// DEFAULT_MAX_QUEUE (or with_max_queue) connections, further connections are
+// rejected. Every freed slot wakes the longest waiting connection. If that
+// one still can't proceed (e.g. its source ip is at the limit), it goes
+// back to the end of the queue and passes the slot on to the next one, at
+// most once around the queue. A rejected admission
+// is an error and the connection can just be dropped. A rejected tunnel has
+// already sent a request, so it is answered with REP_GENERAL_FAILURE (in the
+// protocol of the request) before the error is delivered.
+//
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio_io::AsyncWrite;
+use tokio_io::io::{write_all, WriteAll};
+use futures::*;
+use futures::Async;
+use futures::task::Task;
+use socks_fut::{ReplyCode, SocksRequestResponse};
+
+// Connections waiting for a slot with with_queue
+pub const DEFAULT_MAX_QUEUE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    Reject,
+    Queue
+}
+
+#[derive(Default)]
+struct Counters {
+    handshakes: usize,
+    tunnels: usize,
+    per_ip: HashMap<IpAddr,usize>,
+    per_user: HashMap<String,usize>,
+    // Queued connections by ticket, the longest waiting first
+    waiting: VecDeque<(usize,Task)>,
+    next_ticket: usize,
+    // Woken connections, which passed their slot on since the last release
+    passed: usize
+}
+
+impl Counters {
+    fn is_queued(&self, ticket: usize) -> bool {
+        self.waiting.iter().any(|&(t,_)| t == ticket)
+    }
+
+    fn dequeue(&mut self, ticket: usize) {
+        self.waiting.retain(|&(t,_)| t != ticket);
+    }
+
+    fn wake_next(&mut self) -> Option<Task> {
+        self.waiting.pop_front().map(|(_,task)| task)
+    }
+}
+
+fn below<K: Eq + Hash>(map: &HashMap<K,usize>, key: Option<&K>, max: Option<usize>) -> bool {
+    match (key, max) {
+        (Some(key), Some(max)) => map.get(key).cloned().unwrap_or(0) < max,
+        _ => true
+    }
+}
+
+fn increment<K: Eq + Hash + Clone>(map: &mut HashMap<K,usize>, key: Option<&K>) {
+    if let Some(key) = key {
+        *map.entry(key.clone()).or_insert(0) += 1;
+    }
+}
+
+fn decrement<K: Eq + Hash>(map: &mut HashMap<K,usize>, key: Option<&K>) {
+    if let Some(key) = key {
+        let remove = match map.get_mut(key) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            },
+            None => false
+        };
+        if remove {
+            map.remove(key);
+        }
+    }
+}
+
+pub struct ConnectionLimits {
+    max_handshakes: Option<usize>,
+    max_tunnels: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_per_user: Option<usize>,
+    overflow: Overflow,
+    max_queue: usize,
+    counters: Mutex<Counters>
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
+        ConnectionLimits::new()
+    }
+}
+
+impl ConnectionLimits {
+    // Without limits
+    pub fn new() -> ConnectionLimits {
+        ConnectionLimits {
+            max_handshakes: None,
+            max_tunnels: None,
+            max_per_ip: None,
+            max_per_user: None,
+            overflow: Overflow::Reject,
+            max_queue: DEFAULT_MAX_QUEUE,
+            counters: Mutex::new(Counters::default())
+        }
+    }
+
+    pub fn with_max_handshakes(mut self, max: usize) -> ConnectionLimits {
+        self.max_handshakes = Some(max);
+        self
+    }
+
+    pub fn with_max_tunnels(mut self, max: usize) -> ConnectionLimits {
+        self.max_tunnels = Some(max);
+        self
+    }
+
+    pub fn with_max_per_ip(mut self, max: usize) -> ConnectionLimits {
+        self.max_per_ip = Some(max);
+        self
+    }
+
+    pub fn with_max_per_user(mut self, max: usize) -> ConnectionLimits {
+        self.max_per_user = Some(max);
+        self
+    }
+
+    // Wait for a free slot instead of rejecting the connection
+    pub fn with_queue(mut self) -> ConnectionLimits {
+        self.overflow = Overflow::Queue;
+        self
+    }
+
+    // Wait for a free slot with at most max connections in the queue
+    pub fn with_max_queue(mut self, max: usize) -> ConnectionLimits {
+        self.overflow = Overflow::Queue;
+        self.max_queue = max;
+        self
+    }
+
+    pub fn handshakes(&self) -> usize {
+        self.counters.lock().unwrap().handshakes
+    }
+
+    pub fn tunnels(&self) -> usize {
+        self.counters.lock().unwrap().tunnels
+    }
+
+    pub fn queued(&self) -> usize {
+        self.counters.lock().unwrap().waiting.len()
+    }
+
+    // Admission of an accepted connection from the given source
+    pub fn admit(limits: &Arc<ConnectionLimits>, peer: Option<IpAddr>) -> Admit {
+        Admit {
+            limits: limits.clone(),
+            peer,
+            ticket: None
+        }
+    }
+
+    // Admission of the tunnel for a received request. Without permit, the
+    // source ip is checked as well, e.g. if the handshake has been
+    // performed by the caller.
+    pub fn establish<S: AsyncWrite>(limits: &Arc<ConnectionLimits>, permit: Option<Permit>,
+                                    peer: Option<IpAddr>, client: S,
+                                    request: SocksRequestResponse) -> Establish<S> {
+        let permit = permit.unwrap_or_else(|| Permit {
+            limits: limits.clone(),
+            ip: peer,
+            user: None,
+            state: PermitState::Pending
+        });
+        Establish {
+            limits: limits.clone(),
+            ticket: None,
+            state: EstablishState::Waiting(Some((client, request, permit)))
+        }
+    }
+
+    // Take the slots or queue the current task to retry. The ticket
+    // identifies the queued connection between polls.
+    fn try_take<F>(&self, counters: &mut Counters, check: F, what: &str,
+                   ticket: &mut Option<usize>) -> io::Result<(bool,Option<Task>)>
+            where F: Fn(&Counters) -> bool {
+        if check(counters) {
+            if let Some(ticket) = ticket.take() {
+                counters.dequeue(ticket);
+            }
+            counters.passed = 0;
+            return Ok((true, None));
+        }
+        if self.overflow == Overflow::Reject {
+            return Err(Error::new(ErrorKind::Other, format!("Too many {}", what)));
+        }
+        match *ticket {
+            // Polled again without wakeup, keep the place in the queue
+            Some(t) if counters.is_queued(t) => {
+                for waiting in counters.waiting.iter_mut().filter(|w| w.0 == t) {
+                    waiting.1 = task::current();
+                }
+                Ok((false, None))
+            },
+            // Woken, but the slot doesn't fit: requeue and pass it on
+            Some(t) => {
+                counters.waiting.push_back((t, task::current()));
+                let next = if counters.passed < counters.waiting.len() - 1 {
+                    counters.passed += 1;
+                    counters.wake_next()
+                } else {
+                    None
+                };
+                Ok((false, next))
+            },
+            None if counters.waiting.len() >= self.max_queue =>
+                Err(Error::new(ErrorKind::Other, format!("Too many queued {}", what))),
+            None => {
+                let t = counters.next_ticket;
+                counters.next_ticket = t.wrapping_add(1);
+                counters.waiting.push_back((t, task::current()));
+                *ticket = Some(t);
+                Ok((false, None))
+            }
+        }
+    }
+
+    // A queued connection gives up. If it has already been woken, the slot
+    // goes to the next one.
+    fn leave(&self, ticket: Option<usize>) {
+        let next = match ticket {
+            Some(ticket) => {
+                let mut counters = self.counters.lock().unwrap();
+                if counters.is_queued(ticket) {
+                    counters.dequeue(ticket);
+                    None
+                } else {
+                    counters.wake_next()
+                }
+            },
+            None => None
+        };
+        if let Some(task) = next {
+            task.notify();
+        }
+    }
+
+    fn release(&self, permit: &Permit) {
+        let next = {
+            let mut counters = self.counters.lock().unwrap();
+            match permit.state {
+                PermitState::Pending => return,
+                PermitState::Handshake => counters.handshakes -= 1,
+                PermitState::Tunnel => {
+                    counters.tunnels -= 1;
+                    decrement(&mut counters.per_user, permit.user.as_ref());
+                }
+            }
+            decrement(&mut counters.per_ip, permit.ip.as_ref());
+            counters.passed = 0;
+            counters.wake_next()
+        };
+        if let Some(task) = next {
+            task.notify();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PermitState {
+    // Not yet counted
+    Pending,
+    Handshake,
+    Tunnel
+}
+
+// Slots of one connection, which are freed on drop
+pub struct Permit {
+    limits: Arc<ConnectionLimits>,
+    ip: Option<IpAddr>,
+    user: Option<String>,
+    state: PermitState
+}
+
+impl Permit {
+    pub fn is_tunnel(&self) -> bool {
+        self.state == PermitState::Tunnel
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limits.release(self);
+    }
+}
+
+pub struct Admit {
+    limits: Arc<ConnectionLimits>,
+    peer: Option<IpAddr>,
+    ticket: Option<usize>
+}
+
+impl Drop for Admit {
+    fn drop(&mut self) {
+        self.limits.leave(self.ticket.take());
+    }
+}
+
+impl Future for Admit {
+    type Item = Permit;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Permit>, io::Error> {
+        let limits = &self.limits;
+        let peer = self.peer;
+        let mut counters = limits.counters.lock().unwrap();
+        let free = |c: &Counters| {
+            c.handshakes < limits.max_handshakes.unwrap_or(usize::MAX)
+                && below(&c.per_ip, peer.as_ref(), limits.max_per_ip)
+        };
+        let (taken, next) = limits.try_take(&mut counters, free, "connections", &mut self.ticket)?;
+        if !taken {
+            drop(counters);
+            if let Some(task) = next {
+                task.notify();
+            }
+            return Ok(Async::NotReady);
+        }
+        counters.handshakes += 1;
+        increment(&mut counters.per_ip, peer.as_ref());
+        Ok(Async::Ready(Permit {
+            limits: limits.clone(),
+            ip: peer,
+            user: None,
+            state: PermitState::Handshake
+        }))
+    }
+}
+
+enum EstablishState<S> {
+    Waiting(Option<(S,SocksRequestResponse,Permit)>),
+    AnswerRejected(WriteAll<S,Vec<u8>>,Option<io::Error>)
+}
+
+pub struct Establish<S> {
+    limits: Arc<ConnectionLimits>,
+    ticket: Option<usize>,
+    state: EstablishState<S>
+}
+
+impl<S> Drop for Establish<S> {
+    fn drop(&mut self) {
+        self.limits.leave(self.ticket.take());
+    }
+}
+
+impl<S: AsyncWrite> Future for Establish<S> {
+    type Item = (S,SocksRequestResponse,Permit);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::EstablishState::*;
+
+        loop {
+            self.state = match self.state {
+                Waiting(ref mut waiting) => {
+                    let (client,request,mut permit) = waiting.take().expect("poll after completion");
+                    let limits = self.limits.clone();
+                    let ticket = &mut self.ticket;
+                    let (result, next) = {
+                        let mut counters = limits.counters.lock().unwrap();
+                        let user = request.user.clone();
+                        let pending = permit.state == PermitState::Pending;
+                        let free = |c: &Counters| {
+                            c.tunnels < limits.max_tunnels.unwrap_or(usize::MAX)
+                                && below(&c.per_user, user.as_ref(), limits.max_per_user)
+                                && (!pending || below(&c.per_ip, permit.ip.as_ref(), limits.max_per_ip))
+                        };
+                        let (result, mut next) = match limits.try_take(&mut counters, free, "tunnels", ticket) {
+                            Ok((taken, next)) => (Ok(taken), next),
+                            Err(e) => (Err(e), None)
+                        };
+                        if let Ok(true) = result {
+                            match permit.state {
+                                PermitState::Pending => increment(&mut counters.per_ip, permit.ip.as_ref()),
+                                // The handshake slot is free for a queued admission
+                                PermitState::Handshake => {
+                                    counters.handshakes -= 1;
+                                    next = counters.wake_next();
+                                },
+                                PermitState::Tunnel => {
+                                    counters.tunnels -= 1;
+                                    decrement(&mut counters.per_user, permit.user.as_ref());
+                                }
+                            }
+                            counters.tunnels += 1;
+                            increment(&mut counters.per_user, user.as_ref());
+                            permit.user = user;
+                            permit.state = PermitState::Tunnel;
+                        }
+                        (result, next)
+                    };
+                    if let Some(task) = next {
+                        task.notify();
+                    }
+                    match result {
+                        Ok(true) => return Ok(Async::Ready((client,request,permit))),
+                        Ok(false) => {
+                            *waiting = Some((client,request,permit));
+                            return Ok(Async::NotReady);
+                        },
+                        Err(e) => {
+                            let reply = request.protocol.reply(ReplyCode::GeneralFailure, None);
+                            AnswerRejected(write_all(client,reply), Some(e))
+                        }
+                    }
+                },
+                AnswerRejected(ref mut fut, ref mut error) => {
+                    try_ready!(fut.poll());
+                    return Err(error.take().expect("poll after completion"));
+                }
+            }
+        }
+    }
+}
+
+pub(crate) type PermitFuture = Box<dyn Future<Item=Option<Permit>, Error=io::Error>>;
+pub(crate) type EstablishFuture<S> = Box<dyn Future<Item=(S,SocksRequestResponse,Option<Permit>),
+                                                    Error=io::Error>>;
+
+// Admission, if limits are configured
+pub(crate) fn admit_optional(limits: &Option<Arc<ConnectionLimits>>,
+                             peer: Option<IpAddr>) -> PermitFuture {
+    match *limits {
+        Some(ref limits) => Box::new(ConnectionLimits::admit(limits, peer).map(Some)),
+        None => Box::new(future::ok(None))
+    }
+}
+
+// Establish the tunnel, if limits are configured
+pub(crate) fn establish_optional<S>(limits: &Option<Arc<ConnectionLimits>>, permit: Option<Permit>,
+                                    peer: Option<IpAddr>, client: S,
+                                    request: SocksRequestResponse) -> EstablishFuture<S>
+        where S: AsyncWrite + 'static {
+    match *limits {
+        Some(ref limits) => Box::new(ConnectionLimits::establish(limits, permit, peer, client, request)
+                                        .map(|(client,request,permit)| (client,request,Some(permit)))),
+        None => Box::new(future::ok((client,request,permit)))
+    }
+}
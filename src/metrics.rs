@@ -0,0 +1,298 @@
+// Metrics
+// =======
+//
+// Metrics is a registry of counters, one gauge and one histogram about the
+// server. It can be shared by all connections and is filled by the futures,
+// which are given the registry with with_metrics:
+//
+// - socks_handshakes_total: server handshakes by outcome, failed handshakes
+//   by error kind (SocksHandshake)
+// - socks_auth_methods_total: authentication methods selected for socks5
+//   clients (SocksHandshake)
+// - socks_commands_total: commands of the received requests (SocksHandshake)
+// - socks_replies_sent_total: reply codes sent to clients
+// - socks_replies_received_total: reply codes received from upstream proxies
+// - socks_dial_duration_seconds: time until the destination or the upstream
+//   proxy has accepted the request
+// - socks_relayed_bytes_total: bytes relayed per direction, counted when a
+//   tunnel is closed
+// - socks_active_tunnels: tunnels in the relay phase
+//
+// Forwarder and Router take the registry with with_metrics and pass it to
+// all stages. render delivers the registry in the Prometheus text format.
+// serve_metrics answers "GET /metrics" with it on a separate listener.
+//
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_io::io::{read, write_all};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use http::MAX_HEAD_SIZE;
+use relay::RelayStats;
+use socks_fut::{Command, ReplyCode};
+use v5;
+
+// Upper bounds of the dial latency buckets in seconds
+const DIAL_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    // Observations per bucket, the last one is +Inf
+    counts: Vec<u64>,
+    sum: f64
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: vec![0; DIAL_BUCKETS.len() + 1],
+            sum: 0.0
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let index = DIAL_BUCKETS.iter().position(|&le| value <= le)
+                                .unwrap_or(DIAL_BUCKETS.len());
+        self.counts[index] += 1;
+        self.sum += value;
+    }
+}
+
+// Counters are keyed by their labels in text format, e.g. code="succeeded"
+#[derive(Default)]
+struct Registry {
+    handshakes: BTreeMap<String,u64>,
+    auth_methods: BTreeMap<String,u64>,
+    commands: BTreeMap<String,u64>,
+    replies_sent: BTreeMap<String,u64>,
+    replies_received: BTreeMap<String,u64>,
+    relayed_bytes: BTreeMap<String,u64>,
+    active_tunnels: u64,
+    dial: Option<Histogram>
+}
+
+fn count(map: &mut BTreeMap<String,u64>, labels: String, n: u64) {
+    *map.entry(labels).or_insert(0) += n;
+}
+
+// ErrorKind in snake case, e.g. PermissionDenied as permission_denied
+fn error_label(error: &io::Error) -> String {
+    let mut label = String::new();
+    for (i, c) in format!("{:?}", error.kind()).chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            label.push('_');
+        }
+        label.extend(c.to_lowercase());
+    }
+    label
+}
+
+// Same names as in routing tables
+pub(crate) fn reply_label(code: ReplyCode) -> String {
+    match code {
+        ReplyCode::Succeeded               => "succeeded".to_string(),
+        ReplyCode::GeneralFailure          => "general_failure".to_string(),
+        ReplyCode::NotAllowed              => "not_allowed".to_string(),
+        ReplyCode::NetworkUnreachable      => "network_unreachable".to_string(),
+        ReplyCode::HostUnreachable         => "host_unreachable".to_string(),
+        ReplyCode::ConnectionRefused       => "connection_refused".to_string(),
+        ReplyCode::TtlExpired              => "ttl_expired".to_string(),
+        ReplyCode::CommandNotSupported     => "command_not_supported".to_string(),
+        ReplyCode::AddressTypeNotSupported => "address_type_not_supported".to_string(),
+        ReplyCode::Unknown(rep)            => rep.to_string()
+    }
+}
+
+pub(crate) fn command_label(command: Command) -> &'static str {
+    match command {
+        Command::Connect      => "connect",
+        Command::Bind         => "bind",
+        Command::UdpAssociate => "udp_associate",
+        Command::Unknown(_)   => "unknown"
+    }
+}
+
+pub(crate) fn method_label(method: u8) -> &'static str {
+    match method {
+        v5::METH_NO_AUTH              => "no_auth",
+        v5::METH_GSSAPI               => "gssapi",
+        v5::METH_USER_PASS            => "username_password",
+        v5::METH_NO_ACCEPTABLE_METHOD => "no_acceptable_method",
+        _                             => "unknown"
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, map: &BTreeMap<String,u64>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (labels, value) in map {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    // Outcome of a server handshake, the error of a failed one
+    pub fn record_handshake(&self, error: Option<&io::Error>) {
+        let labels = match error {
+            Some(e) => format!("outcome=\"error\",error=\"{}\"", error_label(e)),
+            None => "outcome=\"success\"".to_string()
+        };
+        count(&mut self.registry.lock().unwrap().handshakes, labels, 1);
+    }
+
+    // Authentication method as sent to the client
+    pub(crate) fn record_auth_method(&self, method: u8) {
+        let labels = format!("method=\"{}\"", method_label(method));
+        count(&mut self.registry.lock().unwrap().auth_methods, labels, 1);
+    }
+
+    pub fn record_command(&self, command: Command) {
+        let labels = format!("command=\"{}\"", command_label(command));
+        count(&mut self.registry.lock().unwrap().commands, labels, 1);
+    }
+
+    pub fn record_reply_sent(&self, code: ReplyCode) {
+        let labels = format!("code=\"{}\"", reply_label(code));
+        count(&mut self.registry.lock().unwrap().replies_sent, labels, 1);
+    }
+
+    pub fn record_reply_received(&self, code: ReplyCode) {
+        let labels = format!("code=\"{}\"", reply_label(code));
+        count(&mut self.registry.lock().unwrap().replies_received, labels, 1);
+    }
+
+    pub fn record_dial(&self, latency: Duration) {
+        self.registry.lock().unwrap().dial.get_or_insert_with(Histogram::new)
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn record_relay(&self, stats: &RelayStats) {
+        let mut registry = self.registry.lock().unwrap();
+        count(&mut registry.relayed_bytes, "direction=\"upload\"".to_string(), stats.sent);
+        count(&mut registry.relayed_bytes, "direction=\"download\"".to_string(), stats.received);
+    }
+
+    // Count an active tunnel until the returned guard is dropped
+    pub fn tunnel(metrics: &Arc<Metrics>) -> ActiveTunnel {
+        metrics.registry.lock().unwrap().active_tunnels += 1;
+        ActiveTunnel { metrics: metrics.clone() }
+    }
+
+    pub fn active_tunnels(&self) -> u64 {
+        self.registry.lock().unwrap().active_tunnels
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+        render_counter(&mut out, "socks_handshakes_total",
+                       "Server handshakes by outcome and error kind", &registry.handshakes);
+        render_counter(&mut out, "socks_auth_methods_total",
+                       "Authentication methods selected for socks5 clients", &registry.auth_methods);
+        render_counter(&mut out, "socks_commands_total",
+                       "Commands of received requests", &registry.commands);
+        render_counter(&mut out, "socks_replies_sent_total",
+                       "Reply codes sent to clients", &registry.replies_sent);
+        render_counter(&mut out, "socks_replies_received_total",
+                       "Reply codes received from upstream proxies", &registry.replies_received);
+        render_counter(&mut out, "socks_relayed_bytes_total",
+                       "Bytes relayed by closed tunnels", &registry.relayed_bytes);
+        let _ = writeln!(out, "# HELP socks_active_tunnels Tunnels in the relay phase");
+        let _ = writeln!(out, "# TYPE socks_active_tunnels gauge");
+        let _ = writeln!(out, "socks_active_tunnels {}", registry.active_tunnels);
+        let name = "socks_dial_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time until the destination accepted the request", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        if let Some(ref dial) = registry.dial {
+            let mut cumulative = 0;
+            for (le, n) in DIAL_BUCKETS.iter().zip(&dial.counts) {
+                cumulative += n;
+                let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+            }
+            cumulative += dial.counts[DIAL_BUCKETS.len()];
+            let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+            let _ = writeln!(out, "{}_sum {}", name, dial.sum);
+            let _ = writeln!(out, "{}_count {}", name, cumulative);
+        }
+        out
+    }
+}
+
+pub struct ActiveTunnel {
+    metrics: Arc<Metrics>
+}
+
+impl Drop for ActiveTunnel {
+    fn drop(&mut self) {
+        self.metrics.registry.lock().unwrap().active_tunnels -= 1;
+    }
+}
+
+pub type MetricsServer = Box<dyn Future<Item=(), Error=io::Error>>;
+
+// Answer "GET /metrics" on each accepted connection and close it
+pub fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>, handle: &Handle) -> MetricsServer {
+    let handle = handle.clone();
+    Box::new(listener.incoming().for_each(move |(stream,_addr)| {
+        handle.spawn(answer_metrics(stream, metrics.clone()).then(|_| Ok(())));
+        Ok(())
+    }))
+}
+
+fn answer_metrics(stream: TcpStream, metrics: Arc<Metrics>) -> MetricsServer {
+    Box::new(read_head(stream)
+        .and_then(move |(stream,head)| {
+            let head = String::from_utf8_lossy(&head);
+            let mut words = head.split_whitespace();
+            let response = match (words.next(), words.next()) {
+                (Some("GET"), Some("/metrics")) => {
+                    let body = metrics.render();
+                    format!("HTTP/1.1 200 OK\r\n\
+                             Content-Type: text/plain; version=0.0.4\r\n\
+                             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(), body)
+                },
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_string()
+            };
+            write_all(stream, response.into_bytes())
+        })
+        .map(|_| ()))
+}
+
+// Read until the end of the request head
+fn read_head(stream: TcpStream) -> Box<dyn Future<Item=(TcpStream,Vec<u8>), Error=io::Error>> {
+    Box::new(future::loop_fn((stream,vec!()), |(stream,mut head): (TcpStream,Vec<u8>)| {
+        read(stream, vec![0u8; 1024]).and_then(move |(stream,buf,n)| {
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Incomplete request head"));
+            }
+            head.extend_from_slice(&buf[..n]);
+            if head.windows(4).any(|w| w == b"\r\n\r\n") {
+                Ok(Loop::Break((stream,head)))
+            }
+            else if head.len() > MAX_HEAD_SIZE {
+                Err(Error::new(ErrorKind::InvalidData, "Request head too large"))
+            }
+            else {
+                Ok(Loop::Continue((stream,head)))
+            }
+        })
+    }))
+}
@@ -0,0 +1,100 @@
+// Local port forwarding
+// =====================
+//
+// PortForward exposes a fixed target on a local port, like `ssh -L`: for
+// each accepted connection a tunnel to the target is requested from the
+// configured proxy (socks_connect_handshake, or CONNECT for an HTTP proxy)
+// and the local connection is relayed through it. With a ProxyChain the
+// tunnel runs through all proxies of the chain.
+//
+// A failing tunnel closes the local connection. listen keeps accepting
+// after a failed accept (see accept_retry). The relay honours idle
+// timeout, bandwidth limits and metrics like Forwarder and Router, and each
+// completed tunnel is logged as AccessLogEntry.
+//
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use futures::{Future, Stream};
+use tracing::debug;
+use accept::accept_retry;
+use bandwidth::BandwidthLimiter;
+use chain::{Proxy, ProxyChain};
+use forward::{splice, ForwardFuture};
+use metrics::Metrics;
+use relay::RelayConfig;
+use socks_fut::SocksRequestResponse;
+
+pub type PortForwardServer = Box<dyn Future<Item=(), Error=io::Error>>;
+
+#[derive(Clone)]
+pub struct PortForward {
+    pub chain: ProxyChain,
+    pub target: SocksRequestResponse,
+    relay: RelayConfig
+}
+
+impl PortForward {
+    pub fn new(proxy: Proxy, target: SocksRequestResponse) -> PortForward {
+        PortForward::with_chain(ProxyChain::new(vec![proxy]), target)
+    }
+
+    pub fn with_chain(chain: ProxyChain, target: SocksRequestResponse) -> PortForward {
+        PortForward { chain, target, relay: RelayConfig::default() }
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> PortForward {
+        self.relay.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> PortForward {
+        self.relay.bandwidth = Some(limiter);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> PortForward {
+        self.relay.metrics = Some(metrics);
+        self
+    }
+
+    // Tunnel one local connection to the target
+    pub fn serve(&self, client: TcpStream, handle: &Handle) -> ForwardFuture {
+        tunnel(&self.chain, self.target.clone(), client, &self.relay, handle)
+    }
+
+    // Accept local connections and serve each of them
+    pub fn listen(&self, listener: TcpListener, handle: &Handle) -> PortForwardServer {
+        let forward = self.clone();
+        let handle = handle.clone();
+        Box::new(accept_retry(listener, &handle).for_each(move |(stream,peer)| {
+            handle.spawn(forward.serve(stream, &handle).then(move |res| {
+                if let Err(e) = res {
+                    debug!(%peer, error = %e, "port forward failed");
+                }
+                Ok(())
+            }));
+            Ok(())
+        }))
+    }
+}
+
+// Request target from the chain and relay client through the tunnel
+pub(crate) fn tunnel(chain: &ProxyChain, target: SocksRequestResponse, client: TcpStream,
+                     config: &RelayConfig, handle: &Handle) -> ForwardFuture {
+    let config = config.clone();
+    let handle = handle.clone();
+    let started = Instant::now();
+    let peer = client.peer_addr().ok();
+    Box::new(chain.connect(target.clone(), &handle)
+        .map_err(io::Error::from)
+        .and_then(move |(stream,_replies)| {
+            if let Some(ref metrics) = config.metrics {
+                metrics.record_dial(started.elapsed());
+            }
+            splice(client, stream, peer, &target, &config, &handle)
+        }))
+}
@@ -0,0 +1,231 @@
+// PROXY protocol
+// ==============
+//
+// Behind a TCP load balancer, peer_addr() of an accepted stream is the
+// balancer. With the PROXY protocol (version 1 or 2, as specified by
+// HAProxy) the balancer sends the original addresses ahead of the data.
+//
+// read_proxy_header reads exactly one header of either version from the
+// stream and delivers the stream with the addresses. It delivers None
+// for "PROXY UNKNOWN" and the LOCAL command of version 2, e.g. health
+// checks of the balancer. A stream without header is an error, because
+// the header must only be accepted from trusted balancers.
+//
+// The source address is meant for SocksHandshake::with_peer, so acl rules
+// and routes match the original client. ProxyHeader::encode builds a header
+// for an outbound connection, see SocksDial::with_proxy_header.
+//
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{read_exact, ReadExact};
+use futures::*;
+use futures::Async;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Longest version 1 header including CRLF
+const V1_MAX_SIZE: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+// Signature, version/command, family and length
+const V2_FIXED_SIZE: usize = 16;
+
+const V2_VERSION: u8 = 0x20;
+const V2_CMD_LOCAL: u8 = 0x00;
+const V2_CMD_PROXY: u8 = 0x01;
+const V2_FAM_TCP4: u8 = 0x11;
+const V2_FAM_TCP6: u8 = 0x21;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyVersion {
+    V1,
+    V2
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr
+}
+
+fn invalid(msg: &str) -> io::Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
+
+impl ProxyHeader {
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> ProxyHeader {
+        ProxyHeader { source, destination }
+    }
+
+    // Mixed address families are sent as ipv4-mapped ipv6 addresses
+    pub fn encode(&self, version: ProxyVersion) -> Vec<u8> {
+        let (src, dst) = (self.source, self.destination);
+        match version {
+            ProxyVersion::V1 => {
+                let header = match (src.ip(), dst.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => format!("TCP4 {} {}", s, d),
+                    (s, d) => format!("TCP6 {} {}", to_ipv6(s), to_ipv6(d))
+                };
+                format!("PROXY {} {} {}\r\n", header, src.port(), dst.port()).into_bytes()
+            },
+            ProxyVersion::V2 => {
+                let mut bytes = V2_SIGNATURE.to_vec();
+                bytes.push(V2_VERSION | V2_CMD_PROXY);
+                let mut addresses = vec!();
+                match (src.ip(), dst.ip()) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        bytes.push(V2_FAM_TCP4);
+                        addresses.extend_from_slice(&s.octets());
+                        addresses.extend_from_slice(&d.octets());
+                    },
+                    (s, d) => {
+                        bytes.push(V2_FAM_TCP6);
+                        addresses.extend_from_slice(&to_ipv6(s).octets());
+                        addresses.extend_from_slice(&to_ipv6(d).octets());
+                    }
+                }
+                addresses.extend_from_slice(&src.port().to_be_bytes());
+                addresses.extend_from_slice(&dst.port().to_be_bytes());
+                bytes.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                bytes.extend_from_slice(&addresses);
+                bytes
+            }
+        }
+    }
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n" without CRLF
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    let line = str::from_utf8(line).map_err(|_| invalid("Invalid PROXY header"))?;
+    let words: Vec<&str> = line.split(' ').collect();
+    match words.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if words.len() == 6 => (),
+        _ => return Err(invalid("Invalid PROXY header"))
+    }
+    let ip = |s: &str| s.parse::<IpAddr>().map_err(|_| invalid("Invalid address in PROXY header"));
+    let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("Invalid port in PROXY header"));
+    Ok(Some(ProxyHeader {
+        source: SocketAddr::new(ip(words[2])?, port(words[4])?),
+        destination: SocketAddr::new(ip(words[3])?, port(words[5])?)
+    }))
+}
+
+// Addresses of a version 2 header. TLVs after the addresses are ignored.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    if command == V2_CMD_LOCAL {
+        return Ok(None);
+    }
+    if command != V2_CMD_PROXY {
+        return Err(invalid("Unknown command in PROXY header"));
+    }
+    let port = |i: usize| u16::from_be_bytes([addresses[i], addresses[i+1]]);
+    match family {
+        V2_FAM_TCP4 if addresses.len() >= 12 => {
+            let ip = |i: usize| IpAddr::V4(Ipv4Addr::new(addresses[i], addresses[i+1],
+                                                        addresses[i+2], addresses[i+3]));
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10))
+            }))
+        },
+        V2_FAM_TCP6 if addresses.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addresses[i..i+16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34))
+            }))
+        },
+        // Other families (e.g. unix or udp) carry no tcp addresses
+        _ => Ok(None)
+    }
+}
+
+enum HeaderState<S> {
+    ReadStart(ReadExact<S,Vec<u8>>),
+    ReadV1(ReadExact<S,Vec<u8>>),
+    ReadV2Fixed(ReadExact<S,Vec<u8>>),
+    ReadV2Addresses(ReadExact<S,Vec<u8>>)
+}
+
+pub struct ReadProxyHeader<S> {
+    header: Vec<u8>,
+    state: HeaderState<S>
+}
+
+pub fn read_proxy_header<S: AsyncRead + AsyncWrite>(stream: S) -> ReadProxyHeader<S> {
+    ReadProxyHeader {
+        header: Vec::with_capacity(V1_MAX_SIZE),
+        // Common length of the version 1 prefix and the version 2 fixed part
+        state: HeaderState::ReadStart(read_exact(stream, vec![0u8; 8]))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Future for ReadProxyHeader<S> {
+    type Item = (S,Option<ProxyHeader>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::HeaderState::*;
+
+        loop {
+            self.state = match self.state {
+                ReadStart(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    self.header.extend_from_slice(&buf);
+                    if buf.starts_with(V1_PREFIX) {
+                        ReadV1(read_exact(stream, vec![0u8; 1]))
+                    }
+                    else if buf[..] == V2_SIGNATURE[..8] {
+                        ReadV2Fixed(read_exact(stream, vec![0u8; V2_FIXED_SIZE - 8]))
+                    }
+                    else {
+                        return Err(invalid("Missing PROXY header"));
+                    }
+                },
+                ReadV1(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    self.header.push(buf[0]);
+                    if self.header.ends_with(b"\r\n") {
+                        let n = self.header.len();
+                        let header = parse_v1(&self.header[..n-2])?;
+                        return Ok(Async::Ready((stream,header)));
+                    }
+                    if self.header.len() >= V1_MAX_SIZE {
+                        return Err(invalid("PROXY header too long"));
+                    }
+                    ReadV1(read_exact(stream, buf))
+                },
+                ReadV2Fixed(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    self.header.extend_from_slice(&buf);
+                    if self.header[..12] != V2_SIGNATURE[..] || self.header[12] & 0xf0 != V2_VERSION {
+                        return Err(invalid("Invalid PROXY header"));
+                    }
+                    let len = u16::from_be_bytes([self.header[14], self.header[15]]) as usize;
+                    ReadV2Addresses(read_exact(stream, vec![0u8; len]))
+                },
+                ReadV2Addresses(ref mut fut) => {
+                    let (stream,addresses) = try_ready!(fut.poll());
+                    let fixed = mem::take(&mut self.header);
+                    let header = parse_v2(fixed[12] & 0x0f, fixed[13], &addresses)?;
+                    return Ok(Async::Ready((stream,header)));
+                }
+            }
+        }
+    }
+}
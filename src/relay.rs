@@ -0,0 +1,285 @@
+// Bidirectional relay
+// ===================
+//
+// After the handshake, relay copies the data between client and target in
+// both directions until both directions are closed. On end of file in one
+// direction, the write side of the other stream is shut down, so the peer
+// sees the end of file as well, while the opposite direction continues
+// (half-close). AsyncWrite::shutdown of a tokio-core TcpStream does not
+// close anything, therefore the streams implement HalfClose.
+//
+// With a Throttle (see bandwidth.rs), each direction moves only as many
+// bytes as the bandwidth limits allow.
+//
+// With an idle timeout, the relay finishes, if no data has been moved in
+// either direction for this duration. The relay delivers RelayStats with
+// the bytes sent from client to target, the bytes received from target to
+// client and the duration, e.g. for billing and logs.
+//
+// An I/O error of either stream (e.g. a connection reset) finishes the
+// relay as well. It still delivers the RelayStats of the bytes moved so
+// far, with the kind of the error, instead of failing.
+//
+
+use std::cmp;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use futures::*;
+use futures::Async;
+use bandwidth::{BandwidthLimiter, Throttle, Way};
+use metrics::Metrics;
+
+const BUFFER_SIZE: usize = 8192;
+
+// Close the write direction of a stream and keep reading
+pub trait HalfClose {
+    fn poll_shutdown_write(&mut self) -> Poll<(), io::Error>;
+}
+
+impl HalfClose for TcpStream {
+    fn poll_shutdown_write(&mut self) -> Poll<(), io::Error> {
+        TcpStream::shutdown(self, Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for ::tokio_uds::UnixStream {
+    fn poll_shutdown_write(&mut self) -> Poll<(), io::Error> {
+        ::tokio_uds::UnixStream::shutdown(self, Shutdown::Write)?;
+        Ok(Async::Ready(()))
+    }
+}
+
+// Send close_notify and then close the underlying stream
+#[cfg(feature = "tls")]
+impl<S: HalfClose + io::Read + io::Write> HalfClose for ::tokio_tls::TlsStream<S> {
+    fn poll_shutdown_write(&mut self) -> Poll<(), io::Error> {
+        match self.get_mut().shutdown() {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e)
+        }
+        self.get_mut().get_mut().poll_shutdown_write()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelayStats {
+    // Bytes from client to target
+    pub sent: u64,
+    // Bytes from target to client
+    pub received: u64,
+    pub duration: Duration,
+    // Finished by the idle timeout instead of end of file on both sides
+    pub timed_out: bool,
+    // Finished by an I/O error of either stream
+    pub error: Option<ErrorKind>
+}
+
+struct Direction {
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    closed: bool,
+    amount: u64
+}
+
+impl Direction {
+    fn new() -> Direction {
+        Direction {
+            buf: vec![0u8; BUFFER_SIZE].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+            eof: false,
+            closed: false,
+            amount: 0
+        }
+    }
+
+    // Copy as much as possible without blocking and without reading more
+    // than the budget. Delivers, if any data has been moved.
+    fn transfer<R: AsyncRead, W: AsyncWrite + HalfClose>(&mut self, reader: &mut R,
+                                                          writer: &mut W,
+                                                          budget: &mut u64) -> io::Result<bool> {
+        let mut progress = false;
+        loop {
+            if self.pos == self.cap && !self.eof {
+                if *budget == 0 {
+                    return Ok(progress);
+                }
+                let len = cmp::min(self.buf.len() as u64, *budget) as usize;
+                match reader.read(&mut self.buf[..len]) {
+                    Ok(0) => self.eof = true,
+                    Ok(n) => {
+                        self.pos = 0;
+                        self.cap = n;
+                        *budget -= n as u64;
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(progress),
+                    Err(e) => return Err(e)
+                }
+            }
+            while self.pos < self.cap {
+                match writer.write(&self.buf[self.pos..self.cap]) {
+                    Ok(0) => return Err(Error::new(ErrorKind::WriteZero,
+                                                   "Write zero bytes into relay target")),
+                    Ok(n) => {
+                        self.pos += n;
+                        self.amount += n as u64;
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(progress),
+                    Err(e) => return Err(e)
+                }
+            }
+            if self.eof {
+                if !self.closed {
+                    match writer.flush() {
+                        Ok(()) => (),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(progress),
+                        Err(e) => return Err(e)
+                    }
+                    if let Async::Ready(()) = writer.poll_shutdown_write()? {
+                        self.closed = true;
+                    }
+                }
+                return Ok(progress);
+            }
+        }
+    }
+}
+
+// Expires, if no progress has been reported for the timeout
+pub(crate) struct IdleTimer {
+    timeout: Option<(Duration,Handle)>,
+    timer: Option<Timeout>
+}
+
+impl IdleTimer {
+    pub(crate) fn new() -> IdleTimer {
+        IdleTimer { timeout: None, timer: None }
+    }
+
+    pub(crate) fn set(&mut self, timeout: Duration, handle: &Handle) {
+        self.timeout = Some((timeout, handle.clone()));
+    }
+
+    pub(crate) fn poll(&mut self, progress: bool) -> Poll<(), io::Error> {
+        if let Some((timeout, ref handle)) = self.timeout {
+            match self.timer {
+                Some(ref mut timer) if progress => timer.reset(Instant::now() + timeout),
+                Some(_) => (),
+                None => self.timer = Some(Timeout::new(timeout, handle)?)
+            }
+        }
+        match self.timer {
+            Some(ref mut timer) => timer.poll(),
+            None => Ok(Async::NotReady)
+        }
+    }
+}
+
+// Settings of the relay after the handshake, e.g. for Forwarder and Router
+#[derive(Clone, Default)]
+pub struct RelayConfig {
+    pub idle_timeout: Option<Duration>,
+    pub bandwidth: Option<Arc<BandwidthLimiter>>,
+    pub metrics: Option<Arc<Metrics>>
+}
+
+pub struct Relay<A,B> {
+    client: A,
+    target: B,
+    upstream: Direction,
+    downstream: Direction,
+    started: Instant,
+    idle: IdleTimer,
+    throttle: Option<Throttle>
+}
+
+pub fn relay<A,B>(client: A, target: B) -> Relay<A,B>
+        where A: AsyncRead + AsyncWrite + HalfClose, B: AsyncRead + AsyncWrite + HalfClose {
+    Relay {
+        client,
+        target,
+        upstream: Direction::new(),
+        downstream: Direction::new(),
+        started: Instant::now(),
+        idle: IdleTimer::new(),
+        throttle: None
+    }
+}
+
+impl<A,B> Relay<A,B> {
+    pub fn with_throttle(mut self, throttle: Throttle) -> Relay<A,B> {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration, handle: &Handle) -> Relay<A,B> {
+        self.idle.set(timeout, handle);
+        self
+    }
+
+    fn stats(&self, timed_out: bool, error: Option<ErrorKind>) -> RelayStats {
+        RelayStats {
+            sent: self.upstream.amount,
+            received: self.downstream.amount,
+            duration: self.started.elapsed(),
+            timed_out,
+            error
+        }
+    }
+}
+
+impl<A,B> Relay<A,B>
+        where A: AsyncRead + AsyncWrite + HalfClose, B: AsyncRead + AsyncWrite + HalfClose {
+    // Delivers true, if finished by the idle timeout
+    fn poll_relay(&mut self) -> Poll<bool, io::Error> {
+        let (mut up_budget, mut down_budget) = match self.throttle {
+            Some(ref mut throttle) => (throttle.allowance(Way::Upload),
+                                       throttle.allowance(Way::Download)),
+            None => (u64::MAX, u64::MAX)
+        };
+        let (up_before, down_before) = (up_budget, down_budget);
+        let up = self.upstream.transfer(&mut self.client, &mut self.target, &mut up_budget)?;
+        let down = self.downstream.transfer(&mut self.target, &mut self.client, &mut down_budget)?;
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.consume(Way::Upload, up_before - up_budget);
+            throttle.consume(Way::Download, down_before - down_budget);
+            let up_limited = up_budget == 0 && !self.upstream.eof;
+            let down_limited = down_budget == 0 && !self.downstream.eof;
+            if up_limited || down_limited {
+                throttle.wait(up_limited, down_limited)?;
+            }
+        }
+        if self.upstream.closed && self.downstream.closed {
+            return Ok(Async::Ready(false));
+        }
+        try_ready!(self.idle.poll(up || down));
+        Ok(Async::Ready(true))
+    }
+}
+
+impl<A,B> Future for Relay<A,B>
+        where A: AsyncRead + AsyncWrite + HalfClose, B: AsyncRead + AsyncWrite + HalfClose {
+    type Item = RelayStats;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        match self.poll_relay() {
+            Ok(Async::Ready(timed_out)) => Ok(Async::Ready(self.stats(timed_out, None))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Ok(Async::Ready(self.stats(false, Some(e.kind()))))
+        }
+    }
+}
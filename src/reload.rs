@@ -0,0 +1,51 @@
+// Hot-reloadable configuration
+// ============================
+//
+// ConfigHandle holds the current version of a configuration, e.g. an Acl,
+// a UserList, a Router or a server's set of them, and can be swapped at
+// runtime. A connection takes the current version with get() when it
+// starts and keeps this Arc until it ends. So new handshakes use the new
+// configuration, while established tunnels continue with the old one and
+// are not dropped.
+//
+// Clones of a ConfigHandle share the same slot, swapping through one clone
+// is seen by all.
+//
+
+use std::mem;
+use std::sync::{Arc, RwLock};
+
+pub struct ConfigHandle<T> {
+    current: Arc<RwLock<Arc<T>>>
+}
+
+impl<T> Clone for ConfigHandle<T> {
+    fn clone(&self) -> ConfigHandle<T> {
+        ConfigHandle { current: self.current.clone() }
+    }
+}
+
+impl<T> ConfigHandle<T> {
+    pub fn new(config: T) -> ConfigHandle<T> {
+        ConfigHandle::from_arc(Arc::new(config))
+    }
+
+    pub fn from_arc(config: Arc<T>) -> ConfigHandle<T> {
+        ConfigHandle { current: Arc::new(RwLock::new(config)) }
+    }
+
+    // The configuration for a new connection
+    pub fn get(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    // Replace the configuration and deliver the previous one
+    pub fn swap(&self, config: T) -> Arc<T> {
+        self.swap_arc(Arc::new(config))
+    }
+
+    pub fn swap_arc(&self, config: Arc<T>) -> Arc<T> {
+        let mut current = self.current.write().unwrap();
+        mem::replace(&mut *current, config)
+    }
+}
@@ -0,0 +1,282 @@
+// Name resolution for ATYP_DOMAIN requests
+// ========================================
+//
+// The Resolver trait is used by socks_dial to turn a hostname into socket
+// addresses. Resolvers can be stacked:
+//
+//     let resolver = StaticResolver::new(Some(Arc::new(
+//                         CachingResolver::new(SystemResolver, Duration::from_secs(60)))))
+//                         .insert("proxy.local", "127.0.0.1".parse().unwrap());
+//
+// An empty address list is reported as error.
+//
+// SystemResolver shares a fixed pool of RESOLVER_THREADS worker threads.
+// At most RESOLVER_QUEUE lookups wait for a worker, further ones fail at
+// once instead of piling up threads. CachingResolver keeps at most
+// max_entries names (DEFAULT_CACHE_ENTRIES unless set with with_max_entries):
+// expired entries are swept when the cache is full, and if it is still full
+// the entry expiring first is evicted.
+//
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use futures::{future, Future};
+use futures::sync::oneshot;
+
+pub type ResolveFuture = Box<dyn Future<Item=Vec<SocketAddr>, Error=io::Error>>;
+
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        (**self).resolve(host, port)
+    }
+}
+
+fn not_found(host: &str) -> io::Error {
+    Error::new(ErrorKind::NotFound, format!("Cannot resolve '{}'", host))
+}
+
+pub const RESOLVER_THREADS: usize = 8;
+pub const RESOLVER_QUEUE: usize = 256;
+
+type Lookup = (String, u16, oneshot::Sender<io::Result<Vec<SocketAddr>>>);
+
+// Start the worker threads on first use
+fn lookup_queue() -> &'static SyncSender<Lookup> {
+    static QUEUE: OnceLock<SyncSender<Lookup>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = sync_channel::<Lookup>(RESOLVER_QUEUE);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..RESOLVER_THREADS {
+            let rx = rx.clone();
+            thread::spawn(move || lookup_worker(&rx));
+        }
+        tx
+    })
+}
+
+fn lookup_worker(rx: &Mutex<Receiver<Lookup>>) {
+    loop {
+        let job = rx.lock().unwrap().recv();
+        let (name, port, tx) = match job {
+            Ok(job) => job,
+            Err(_) => return
+        };
+        let result = (name.as_str(), port).to_socket_addrs()
+                            .map(|addrs| addrs.collect::<Vec<_>>());
+        let _ = tx.send(result);
+    }
+}
+
+// Uses the blocking resolver of the operating system in a shared pool of
+// worker threads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let (tx, rx) = oneshot::channel();
+        match lookup_queue().try_send((host.to_string(), port, tx)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                return Box::new(future::err(Error::new(ErrorKind::Other,
+                                                       "Too many pending lookups")))
+            },
+            Err(TrySendError::Disconnected(_)) => {
+                return Box::new(future::err(Error::new(ErrorKind::Other,
+                                                       "Resolver threads died")))
+            }
+        }
+        let host = host.to_string();
+        Box::new(rx
+            .map_err(|_| Error::new(ErrorKind::Other, "Resolver thread died"))
+            .and_then(move |result| match result {
+                Ok(ref addrs) if addrs.is_empty() => Err(not_found(&host)),
+                result => result
+            }))
+    }
+}
+
+pub const DEFAULT_CACHE_ENTRIES: usize = 4096;
+
+type Cache = HashMap<String,(Instant,Vec<IpAddr>)>;
+
+// Make room for one more entry
+fn evict(cache: &mut Cache, max_entries: usize) {
+    if cache.len() < max_entries {
+        return;
+    }
+    let now = Instant::now();
+    cache.retain(|_, &mut (expires, _)| expires > now);
+    while cache.len() >= max_entries {
+        let first = cache.iter()
+                         .min_by_key(|&(_, &(expires, _))| expires)
+                         .map(|(key, _)| key.clone());
+        match first {
+            Some(key) => { cache.remove(&key); },
+            None => break
+        }
+    }
+}
+
+// Caches successful lookups of the inner resolver for the given time
+pub struct CachingResolver<R> {
+    inner: R,
+    ttl: Duration,
+    max_entries: usize,
+    cache: Arc<Mutex<Cache>>
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    pub fn new(inner: R, ttl: Duration) -> CachingResolver<R> {
+        CachingResolver {
+            inner,
+            ttl,
+            max_entries: DEFAULT_CACHE_ENTRIES,
+            cache: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> CachingResolver<R> {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    // Number of cached names, including expired ones not swept yet
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let key = host.to_ascii_lowercase();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            let hit = match cache.get(&key) {
+                Some(&(expires, ref ips)) if expires > Instant::now() => Some(ips.clone()),
+                _ => None
+            };
+            match hit {
+                Some(ips) => {
+                    let addrs = ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                    return Box::new(future::ok(addrs));
+                },
+                None => { cache.remove(&key); }
+            }
+        }
+        let cache = self.cache.clone();
+        let max_entries = self.max_entries;
+        let expires = Instant::now() + self.ttl;
+        Box::new(self.inner.resolve(host, port)
+            .map(move |addrs| {
+                let ips = addrs.iter().map(|addr| addr.ip()).collect();
+                let mut cache = cache.lock().unwrap();
+                if !cache.contains_key(&key) {
+                    evict(&mut cache, max_entries);
+                }
+                cache.insert(key, (expires, ips));
+                addrs
+            }))
+    }
+}
+
+// Fixed host to address map. Unknown hosts are passed to the fallback
+// resolver, if any.
+pub struct StaticResolver {
+    hosts: HashMap<String,Vec<IpAddr>>,
+    fallback: Option<Arc<dyn Resolver>>
+}
+
+impl StaticResolver {
+    pub fn new(fallback: Option<Arc<dyn Resolver>>) -> StaticResolver {
+        StaticResolver {
+            hosts: HashMap::new(),
+            fallback
+        }
+    }
+
+    pub fn insert(mut self, host: &str, ip: IpAddr) -> StaticResolver {
+        self.hosts.entry(host.to_ascii_lowercase()).or_default().push(ip);
+        self
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        match self.hosts.get(&host.to_ascii_lowercase()) {
+            Some(ips) => {
+                let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+                Box::new(future::ok(addrs))
+            },
+            None => match self.fallback {
+                Some(ref resolver) => resolver.resolve(host, port),
+                None => Box::new(future::err(not_found(host)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpPreference {
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only
+}
+
+impl IpPreference {
+    // Stable reordering resp. filtering of the resolved addresses
+    pub fn apply(self, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match self {
+            IpPreference::Any => (),
+            IpPreference::PreferIpv4 => addrs.sort_by_key(|addr| addr.is_ipv6()),
+            IpPreference::PreferIpv6 => addrs.sort_by_key(|addr| addr.is_ipv4()),
+            IpPreference::Ipv4Only => addrs.retain(|addr| addr.is_ipv4()),
+            IpPreference::Ipv6Only => addrs.retain(|addr| addr.is_ipv6())
+        }
+        addrs
+    }
+}
+
+pub struct PreferenceResolver<R> {
+    inner: R,
+    preference: IpPreference
+}
+
+impl<R: Resolver> PreferenceResolver<R> {
+    pub fn new(inner: R, preference: IpPreference) -> PreferenceResolver<R> {
+        PreferenceResolver { inner, preference }
+    }
+}
+
+impl<R: Resolver> Resolver for PreferenceResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> ResolveFuture {
+        let preference = self.preference;
+        let host = host.to_string();
+        Box::new(self.inner.resolve(&host, port)
+            .and_then(move |addrs| {
+                let addrs = preference.apply(addrs);
+                if addrs.is_empty() {
+                    Err(not_found(&host))
+                }
+                else {
+                    Ok(addrs)
+                }
+            }))
+    }
+}
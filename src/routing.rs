@@ -0,0 +1,382 @@
+// Rule based routing of socks5 requests
+// =====================================
+//
+// A RoutingTable is an ordered list of routes. The first route, whose
+// condition matches the request, selects the outbound:
+//
+// - Direct: socks_dial to the destination
+// - Upstream: forward the request to a named group of upstream proxies
+// - Reject: answer the request with the given reply code
+//
+// If no route matches, the default outbound is taken (direct, if not set).
+//
+// The table can be loaded from a text file. Each non-empty line, which does
+// not start with '#', is one of:
+//
+//     proxy NAME ADDR [USERNAME PASSWORD]
+//     direct [CONDITION]
+//     via NAME [CONDITION]
+//     reject CODE [CONDITION]
+//     default direct|via NAME|reject CODE
+//
+// CONDITION has the same format as in acl rules. Several proxy lines with
+// the same name form an UpstreamPool with failover policy. ADDR with prefix
+// http:// is an HTTP proxy, otherwise a socks5 proxy. ADDR is a socket
+// address or, on unix, unix:PATH of a proxy listening on a socket path.
+// The reply code is given by name (e.g. not_allowed, host_unreachable) or
+// as number.
+//
+// Routes are selected before a hostname is resolved, so CIDR conditions
+// (to ADDR/PREFIX) match only requests with an ip address: a hostname,
+// which resolves into 10.0.0.0/8, doesn't match "to 10.0.0.0/8". Use
+// domain patterns for names. Example:
+//
+//     proxy a 192.0.2.10:1080
+//     proxy b 192.0.2.11:1080 alice secret
+//     proxy b http://192.0.2.12:3128
+//     direct domain .corp.example
+//     via a to 10.0.0.0/8
+//     reject not_allowed port 25
+//     default via b
+//
+// The Router serves a client with the handshake and the selected outbound.
+// serve_request takes a request received by another handshake, so the
+// Router can be combined with accept_any. Client and destination are
+// relayed until both directions are closed or the idle timeout expires,
+// optionally with bandwidth and connection limits. With Metrics, the
+// handshake, the dial or the upstream and the relay record into the
+// registry. Each completed tunnel is logged as AccessLogEntry. With an Acl,
+// direct requests connect only to resolved addresses allowed by the acl
+// (see socks_dial).
+//
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_io::io::write_all;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use futures::Future;
+use acl::{Acl, Condition};
+use bandwidth::BandwidthLimiter;
+use chain::{Proxy, ProxyAddr, ProxyKind, ProxyStream};
+use dial::socks_dial;
+use limits::{admit_optional, establish_optional, ConnectionLimits, Permit};
+use metrics::Metrics;
+use forward::{forward_request, handshake, splice, ForwardFuture};
+use relay::{RelayConfig, RelayStats};
+use resolver::Resolver;
+use socks_fut::{Credentials, ReplyCode, SocksRequestResponse};
+use upstream::{Policy, UpstreamPool};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outbound {
+    Direct,
+    Upstream(String),
+    Reject(ReplyCode)
+}
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub outbound: Outbound,
+    pub condition: Condition
+}
+
+pub struct RoutingTable {
+    pub routes: Vec<Route>,
+    pub default: Outbound,
+    upstreams: HashMap<String,Arc<UpstreamPool>>
+}
+
+impl RoutingTable {
+    pub fn new(default: Outbound) -> RoutingTable {
+        RoutingTable {
+            routes: vec!(),
+            default,
+            upstreams: HashMap::new()
+        }
+    }
+
+    pub fn add_upstream(&mut self, name: &str, pool: Arc<UpstreamPool>) {
+        self.upstreams.insert(name.to_string(), pool);
+    }
+
+    pub fn upstream(&self, name: &str) -> Option<Arc<UpstreamPool>> {
+        self.upstreams.get(name).cloned()
+    }
+
+    pub fn push(&mut self, route: Route) {
+        self.routes.push(route);
+    }
+
+    pub fn route(&self, source: Option<IpAddr>,
+                        request: &SocksRequestResponse,
+                        user: Option<&str>) -> &Outbound {
+        self.routes.iter()
+            .find(|route| route.condition.matches(source, request, user))
+            .map(|route| &route.outbound)
+            .unwrap_or(&self.default)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<RoutingTable> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        content.parse()
+    }
+}
+
+fn parse_reply_code(s: &str) -> io::Result<ReplyCode> {
+    Ok(match s {
+        "general_failure"            => ReplyCode::GeneralFailure,
+        "not_allowed"                => ReplyCode::NotAllowed,
+        "network_unreachable"        => ReplyCode::NetworkUnreachable,
+        "host_unreachable"           => ReplyCode::HostUnreachable,
+        "connection_refused"         => ReplyCode::ConnectionRefused,
+        "ttl_expired"                => ReplyCode::TtlExpired,
+        "command_not_supported"      => ReplyCode::CommandNotSupported,
+        "address_type_not_supported" => ReplyCode::AddressTypeNotSupported,
+        _ => match s.parse::<u8>() {
+            Ok(rep) if rep != 0 => ReplyCode::from_u8(rep),
+            _ => return Err(Error::new(ErrorKind::InvalidInput,
+                                       format!("Invalid reply code '{}'", s)))
+        }
+    })
+}
+
+// Parse the outbound at the start of words and leave the condition
+fn parse_outbound<'a, I>(words: &mut I) -> io::Result<Outbound>
+        where I: Iterator<Item=&'a str> {
+    let missing = || Error::new(ErrorKind::InvalidInput, "Missing value");
+    match words.next() {
+        Some("direct") => Ok(Outbound::Direct),
+        Some("via") => Ok(Outbound::Upstream(words.next().ok_or_else(missing)?.to_string())),
+        Some("reject") => Ok(Outbound::Reject(parse_reply_code(words.next().ok_or_else(missing)?)?)),
+        Some(word) => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown outbound '{}'", word))),
+        None => Err(missing())
+    }
+}
+
+impl FromStr for RoutingTable {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<RoutingTable> {
+        let mut table = RoutingTable::new(Outbound::Direct);
+        let mut proxies: Vec<(String,Proxy)> = vec!();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at_line = |e: io::Error| Error::new(e.kind(), format!("line {}: {}", n + 1, e));
+            let invalid = |msg: &str| at_line(Error::new(ErrorKind::InvalidInput, msg.to_string()));
+            let mut words = line.split_whitespace();
+            match line.split_whitespace().next() {
+                Some("proxy") => {
+                    words.next();
+                    let parts: Vec<&str> = words.collect();
+                    let proxy = match parts.len() {
+                        2 | 4 => {
+                            let (kind, addr) = match parts[1].strip_prefix("http://") {
+                                Some(addr) => (ProxyKind::Http, addr),
+                                None => (ProxyKind::Socks5, parts[1])
+                            };
+                            let addr = addr.parse::<ProxyAddr>()
+                                            .map_err(|_| invalid("Invalid proxy address"))?;
+                            let proxy = Proxy::with_addr(addr).with_kind(kind);
+                            if parts.len() == 4 {
+                                proxy.with_credentials(Credentials::new(parts[2], parts[3]))
+                            }
+                            else {
+                                proxy
+                            }
+                        },
+                        _ => return Err(invalid("proxy needs NAME ADDR [USERNAME PASSWORD]"))
+                    };
+                    proxies.push((parts[0].to_string(), proxy));
+                },
+                Some("default") => {
+                    words.next();
+                    table.default = parse_outbound(&mut words).map_err(at_line)?;
+                    if words.next().is_some() {
+                        return Err(invalid("default takes no condition"));
+                    }
+                },
+                _ => {
+                    let outbound = parse_outbound(&mut words).map_err(at_line)?;
+                    let condition = Condition::parse_words(words).map_err(at_line)?;
+                    table.push(Route { outbound, condition });
+                }
+            }
+        }
+        let mut names: Vec<&String> = vec!();
+        for (name,_) in &proxies {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        for name in names {
+            let group = proxies.iter()
+                            .filter(|&(n,_)| n == name)
+                            .map(|(_,proxy)| proxy.clone())
+                            .collect();
+            table.add_upstream(name, Arc::new(UpstreamPool::new(group, Policy::Failover)));
+        }
+        let outbounds = table.routes.iter().map(|route| &route.outbound)
+                                           .chain(Some(&table.default));
+        for outbound in outbounds {
+            if let Outbound::Upstream(ref name) = *outbound {
+                if !table.upstreams.contains_key(name) {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("Unknown proxy '{}'", name)));
+                }
+            }
+        }
+        Ok(table)
+    }
+}
+
+#[derive(Clone)]
+pub struct Router {
+    pub table: Arc<RoutingTable>,
+    pub resolver: Arc<dyn Resolver>,
+    relay: RelayConfig,
+    limits: Option<Arc<ConnectionLimits>>,
+    acl: Option<Arc<Acl>>
+}
+
+impl Router {
+    pub fn new(table: Arc<RoutingTable>, resolver: Arc<dyn Resolver>) -> Router {
+        Router { table, resolver, relay: RelayConfig::default(), limits: None, acl: None }
+    }
+
+    // Check the resolved addresses of direct requests
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Router {
+        self.acl = Some(acl);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Router {
+        self.relay.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> Router {
+        self.relay.bandwidth = Some(limiter);
+        self
+    }
+
+    // serve admits the client before the handshake, serve_request only
+    // the tunnel including the check of the source ip.
+    pub fn with_limits(mut self, limits: Arc<ConnectionLimits>) -> Router {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Router {
+        self.relay.metrics = Some(metrics);
+        self
+    }
+
+    // Delivers the number of bytes sent from client to destination and
+    // from destination to client and the duration of the relay.
+    pub fn serve(&self, client: TcpStream, handle: &Handle) -> ForwardFuture {
+        let router = self.clone();
+        let handle = handle.clone();
+        let peer = client.peer_addr().ok();
+        let metrics = self.relay.metrics.clone();
+        Box::new(admit_optional(&self.limits, peer.map(|addr| addr.ip()))
+            .and_then(move |permit| {
+                handshake(client, metrics).map(move |(client,request)| (client,request,permit))
+            })
+            .and_then(move |(client,request,permit)| {
+                router.serve_admitted(client, request, peer, permit, &handle)
+            }))
+    }
+
+    // Route a request, which has been received by any server handshake
+    // (e.g. accept_any).
+    pub fn serve_request(&self, client: TcpStream, request: SocksRequestResponse,
+                         handle: &Handle) -> ForwardFuture {
+        let peer = client.peer_addr().ok();
+        self.serve_request_from(client, request, peer, handle)
+    }
+
+    // Route with the given client address, e.g. from a PROXY protocol header
+    pub fn serve_request_from(&self, client: TcpStream, request: SocksRequestResponse,
+                              peer: Option<SocketAddr>, handle: &Handle) -> ForwardFuture {
+        self.serve_admitted(client, request, peer, None, handle)
+    }
+
+    // Rejected requests do not need a tunnel
+    fn serve_admitted(&self, client: TcpStream, request: SocksRequestResponse,
+                      peer: Option<SocketAddr>, permit: Option<Permit>,
+                      handle: &Handle) -> ForwardFuture {
+        let source = peer.map(|addr| addr.ip());
+        let outbound = self.table.route(source, &request, request.user.as_deref()).clone();
+        if let Outbound::Reject(_) = outbound {
+            return self.route_request(client, request, &outbound, peer, handle);
+        }
+        let router = self.clone();
+        let handle = handle.clone();
+        Box::new(establish_optional(&self.limits, permit, source, client, request)
+            .and_then(move |(client,request,permit)| {
+                router.route_request(client, request, &outbound, peer, &handle)
+                    .then(move |res| {
+                        drop(permit);
+                        res
+                    })
+            }))
+    }
+
+    fn route_request(&self, client: TcpStream, request: SocksRequestResponse, outbound: &Outbound,
+                     peer: Option<SocketAddr>, handle: &Handle) -> ForwardFuture {
+        let config = &self.relay;
+        let source = peer.map(|addr| addr.ip());
+        match *outbound {
+            Outbound::Direct => {
+                let handle = handle.clone();
+                let logged = request.clone();
+                let config = config.clone();
+                let dial = socks_dial(client, &request, &handle, self.resolver.clone());
+                let dial = match config.metrics {
+                    Some(ref metrics) => dial.with_metrics(metrics.clone()),
+                    None => dial
+                };
+                let dial = match self.acl {
+                    Some(ref acl) => dial.with_acl(acl.clone(), source),
+                    None => dial
+                };
+                Box::new(dial
+                    .and_then(move |(client,target)| {
+                        splice(client, ProxyStream::Tcp(target), peer, &logged, &config, &handle)
+                    }))
+            },
+            Outbound::Upstream(ref name) => {
+                // Names are checked on load, a missing pool means a table built by hand
+                match self.table.upstream(name) {
+                    Some(pool) => forward_request(client, request, peer, pool, config, handle),
+                    None => self.route_request(client, request,
+                                               &Outbound::Reject(ReplyCode::GeneralFailure),
+                                               peer, handle)
+                }
+            },
+            Outbound::Reject(code) => {
+                if let Some(ref metrics) = config.metrics {
+                    metrics.record_reply_sent(code);
+                }
+                let reply = request.protocol.reply(code, None);
+                Box::new(write_all(client,reply)
+                    .and_then(move |_| {
+                        Err::<RelayStats,_>(Error::new(ErrorKind::PermissionDenied,
+                                       format!("Socks5 request rejected with {:?}", code)))
+                    }))
+            }
+        }
+    }
+}
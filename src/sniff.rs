@@ -0,0 +1,132 @@
+// Protocol detection on server side
+// =================================
+//
+// accept_any peeks the first byte of the client stream and runs the
+// matching server handshake:
+//
+// - 0x05: socks5 (SocksHandshake)
+// - 0x04: SOCKS4 and SOCKS4A (SocksHandshake with socks4), only if enabled
+//   with with_socks4, like for SocksHandshake
+// - 'A' to 'Z': HTTP proxy request (HttpProxyHandshake)
+//
+// This way socks and HTTP proxy clients can share one listening port.
+// The acl and the authenticator are passed to the selected handshake:
+// socks5 clients authenticate with username/password, HTTP clients with
+// Proxy-Authorization Basic, SOCKS4 clients are rejected.
+// All handshakes deliver a SocksRequestResponse, whose protocol tells
+// socks_dial, forward_request and the Router how to answer the client.
+//
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio_core::net::TcpStream;
+use futures::*;
+use futures::Async;
+use acl::Acl;
+use auth::Authenticator;
+use http::{http_proxy_handshake, HttpProxyHandshake};
+use socks_fut::{socks_handshake, SocksHandshake, SocksRequestResponse};
+use v4;
+use v5;
+
+enum SniffState {
+    Peek(Option<TcpStream>),
+    Socks(SocksHandshake),
+    Http(HttpProxyHandshake)
+}
+
+pub struct AcceptAny {
+    state: SniffState,
+    acl: Option<Arc<Acl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    socks4: bool
+}
+
+pub fn accept_any(stream: TcpStream) -> AcceptAny {
+    AcceptAny {
+        state: SniffState::Peek(Some(stream)),
+        acl: None,
+        authenticator: None,
+        socks4: false
+    }
+}
+
+impl AcceptAny {
+    // The acl is applied by the selected handshake
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> AcceptAny {
+        self.acl = Some(acl);
+        self
+    }
+
+    // Require authentication from socks5 and HTTP clients
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> AcceptAny {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    // Accept SOCKS4 and SOCKS4A clients besides socks5 and HTTP
+    pub fn with_socks4(mut self) -> AcceptAny {
+        self.socks4 = true;
+        self
+    }
+
+    fn socks(&self, stream: TcpStream) -> SocksHandshake {
+        let mut handshake = socks_handshake(stream);
+        if self.socks4 {
+            handshake = handshake.with_socks4();
+        }
+        if let Some(ref acl) = self.acl {
+            handshake = handshake.with_acl(acl.clone());
+        }
+        match self.authenticator {
+            Some(ref authenticator) => handshake.with_authenticator(authenticator.clone()),
+            None => handshake
+        }
+    }
+
+    fn http(&self, stream: TcpStream) -> HttpProxyHandshake {
+        let mut handshake = http_proxy_handshake(stream);
+        if let Some(ref acl) = self.acl {
+            handshake = handshake.with_acl(acl.clone());
+        }
+        match self.authenticator {
+            Some(ref authenticator) => handshake.with_authenticator(authenticator.clone()),
+            None => handshake
+        }
+    }
+}
+
+impl Future for AcceptAny {
+    type Item = (TcpStream,SocksRequestResponse);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        use self::SniffState::*;
+
+        loop {
+            self.state = match self.state {
+                Peek(ref mut stream) => {
+                    let mut first = [0u8; 1];
+                    let n = match stream.as_ref().expect("poll after completion").peek(&mut first) {
+                        Ok(n) => n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                        Err(e) => return Err(e)
+                    };
+                    if n == 0 {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed by client"));
+                    }
+                    let stream = stream.take().expect("poll after completion");
+                    match first[0] {
+                        v5::VERSION => Socks(self.socks(stream)),
+                        v4::VERSION if self.socks4 => Socks(self.socks(stream)),
+                        b'A'..=b'Z' => Http(self.http(stream)),
+                        _ => return Err(Error::new(ErrorKind::Other, "Unknown proxy protocol"))
+                    }
+                },
+                Socks(ref mut fut) => return fut.poll(),
+                Http(ref mut fut) => return fut.poll()
+            }
+        }
+    }
+}
@@ -4,7 +4,27 @@
 // As per RFC 1928, this is not a compliant implementation, because
 // GSSAPI authentication method is not supported.
 //
-// TODO: Return failures for socks5 requests
+// On client side, username/password authentication as per RFC 1929
+// is offered, if credentials are given.
+//
+// On server side, username/password authentication is required, if an
+// Authenticator is given (see auth.rs).
+//
+// With Metrics, the server handshake records its outcome, the selected
+// authentication method, the command and the REP_NOT_ALLOWED replies.
+//
+// Both handshakes run in a tracing span with the peer address (server) or
+// the destination (client). State transitions are traced, the selected
+// authentication method, the destination and the reply code are logged at
+// debug level.
+//
+// On server side, SOCKS4 and SOCKS4A requests can be accepted as well.
+// They are converted into the socks5 request format, so the result is
+// handled the same way. The USERID of SOCKS4 is not verified, therefore it
+// is not taken as user and SOCKS4 requests are not allowed, if an
+// Authenticator is given. Replies are encoded in the protocol of the request,
+// which can also be one of the HTTP proxy requests (see http.rs).
+//
 // TODO: create a struct for socksv5_request message with
 //       get functions for port, cmd,....
 //
@@ -13,26 +33,78 @@ use std::io;
 use std::io::{Error, ErrorKind};
 use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::io::{read_exact, write_all, ReadExact, WriteAll};
 use tokio_core::net::{TcpStream};
 use futures::*;
 use futures::Async;
+use tracing::{debug, debug_span, field, trace, Span};
+use acl::{Acl, Action};
+use auth::Authenticator;
+use metrics::{command_label, method_label, reply_label, Metrics};
+use v4;
 use v5;
 
-enum ServerState {
-    WaitClientAuthentication(ReadExact<TcpStream,Vec<u8>>),
-    ReadAuthenticationMethods(ReadExact<TcpStream,Vec<u8>>),
-    AnswerNoAuthentication(WriteAll<TcpStream,Vec<u8>>),
-    WaitClientRequest(ReadExact<TcpStream,Vec<u8>>)
+enum ServerState<S> {
+    WaitClientAuthentication(ReadExact<S,Vec<u8>>),
+    ReadAuthenticationMethods(ReadExact<S,Vec<u8>>),
+    AnswerMethod(WriteAll<S,Vec<u8>>),
+    ReadUserPassHeader(ReadExact<S,Vec<u8>>),
+    ReadUsername(ReadExact<S,Vec<u8>>),
+    ReadPassword(ReadExact<S,Vec<u8>>),
+    AnswerCredentials(WriteAll<S,Vec<u8>>),
+    WaitClientRequest(ReadExact<S,Vec<u8>>),
+    ReadSocks4Request(ReadExact<S,Vec<u8>>),
+    ReadSocks4UserId(ReadExact<S,Vec<u8>>),
+    ReadSocks4Hostname(ReadExact<S,Vec<u8>>),
+    AnswerNotAllowed(WriteAll<S,Vec<u8>>)
+}
+
+impl<S> ServerState<S> {
+    fn name(&self) -> &'static str {
+        use self::ServerState::*;
+        match *self {
+            WaitClientAuthentication(_)  => "WaitClientAuthentication",
+            ReadAuthenticationMethods(_) => "ReadAuthenticationMethods",
+            AnswerMethod(_)              => "AnswerMethod",
+            ReadUserPassHeader(_)        => "ReadUserPassHeader",
+            ReadUsername(_)              => "ReadUsername",
+            ReadPassword(_)              => "ReadPassword",
+            AnswerCredentials(_)         => "AnswerCredentials",
+            WaitClientRequest(_)         => "WaitClientRequest",
+            ReadSocks4Request(_)         => "ReadSocks4Request",
+            ReadSocks4UserId(_)          => "ReadSocks4UserId",
+            ReadSocks4Hostname(_)        => "ReadSocks4Hostname",
+            AnswerNotAllowed(_)          => "AnswerNotAllowed"
+        }
+    }
 }
 
-enum ClientState {
-    WaitSentAuthentication(WriteAll<TcpStream,Vec<u8>>),
-    WaitAuthenticationMethod(ReadExact<TcpStream,Vec<u8>>),
-    WaitSentRequest(WriteAll<TcpStream,Vec<u8>>),
-    WaitReply(ReadExact<TcpStream,Vec<u8>>)
+enum ClientState<S> {
+    WaitSentAuthentication(WriteAll<S,Vec<u8>>),
+    WaitAuthenticationMethod(ReadExact<S,Vec<u8>>),
+    WaitSentCredentials(WriteAll<S,Vec<u8>>),
+    WaitCredentialsStatus(ReadExact<S,Vec<u8>>),
+    WaitSentRequest(WriteAll<S,Vec<u8>>),
+    WaitReply(ReadExact<S,Vec<u8>>)
 }
 
+impl<S> ClientState<S> {
+    fn name(&self) -> &'static str {
+        use self::ClientState::*;
+        match *self {
+            WaitSentAuthentication(_)   => "WaitSentAuthentication",
+            WaitAuthenticationMethod(_) => "WaitAuthenticationMethod",
+            WaitSentCredentials(_)      => "WaitSentCredentials",
+            WaitCredentialsStatus(_)    => "WaitCredentialsStatus",
+            WaitSentRequest(_)          => "WaitSentRequest",
+            WaitReply(_)                => "WaitReply"
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Command {
     Connect,
     Bind,
@@ -40,11 +112,246 @@ pub enum Command {
     Unknown(u8)
 }
 
+impl Command {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Command::Connect      => v5::CMD_CONNECT,
+            Command::Bind         => v5::CMD_BIND,
+            Command::UdpAssociate => v5::CMD_UDP_ASSOCIATE,
+            Command::Unknown(cmd) => cmd
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.to_string(),
+            password: password.to_string()
+        }
+    }
+
+    // Username/password request as per RFC 1929
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let user = self.username.as_bytes();
+        let pass = self.password.as_bytes();
+        if user.is_empty() || user.len() > 255 || pass.is_empty() || pass.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Username and password must have 1 to 255 bytes"));
+        }
+        let mut bytes = vec![v5::USER_PASS_VERSION, user.len() as u8];
+        bytes.extend_from_slice(user);
+        bytes.push(pass.len() as u8);
+        bytes.extend_from_slice(pass);
+        Ok(bytes)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyCode {
+    Succeeded,
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    Unknown(u8)
+}
+
+impl ReplyCode {
+    pub fn from_u8(rep: u8) -> ReplyCode {
+        match rep {
+            v5::REP_SUCCEEDED           => ReplyCode::Succeeded,
+            v5::REP_GENERAL_FAILURE     => ReplyCode::GeneralFailure,
+            v5::REP_NOT_ALLOWED         => ReplyCode::NotAllowed,
+            v5::REP_NETWORK_UNREACHABLE => ReplyCode::NetworkUnreachable,
+            v5::REP_HOST_UNREACHABLE    => ReplyCode::HostUnreachable,
+            v5::REP_CONNECTION_REFUSED  => ReplyCode::ConnectionRefused,
+            v5::REP_TTL_EXPIRED         => ReplyCode::TtlExpired,
+            v5::REP_CMD_NOT_SUPPORTED   => ReplyCode::CommandNotSupported,
+            v5::REP_ATYP_NOT_SUPPORTED  => ReplyCode::AddressTypeNotSupported,
+            rep                         => ReplyCode::Unknown(rep)
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ReplyCode::Succeeded               => v5::REP_SUCCEEDED,
+            ReplyCode::GeneralFailure          => v5::REP_GENERAL_FAILURE,
+            ReplyCode::NotAllowed              => v5::REP_NOT_ALLOWED,
+            ReplyCode::NetworkUnreachable      => v5::REP_NETWORK_UNREACHABLE,
+            ReplyCode::HostUnreachable         => v5::REP_HOST_UNREACHABLE,
+            ReplyCode::ConnectionRefused       => v5::REP_CONNECTION_REFUSED,
+            ReplyCode::TtlExpired              => v5::REP_TTL_EXPIRED,
+            ReplyCode::CommandNotSupported     => v5::REP_CMD_NOT_SUPPORTED,
+            ReplyCode::AddressTypeNotSupported => v5::REP_ATYP_NOT_SUPPORTED,
+            ReplyCode::Unknown(rep)            => rep
+        }
+    }
+
+    // Reply code for a failed connection attempt to the destination
+    pub fn from_io_error(error: &io::Error) -> ReplyCode {
+        match error.kind() {
+            ErrorKind::ConnectionRefused  => ReplyCode::ConnectionRefused,
+            ErrorKind::NetworkUnreachable => ReplyCode::NetworkUnreachable,
+            ErrorKind::HostUnreachable    => ReplyCode::HostUnreachable,
+            ErrorKind::NotFound           => ReplyCode::HostUnreachable,
+            ErrorKind::TimedOut           => ReplyCode::TtlExpired,
+            ErrorKind::PermissionDenied   => ReplyCode::NotAllowed,
+            _                             => ReplyCode::GeneralFailure
+        }
+    }
+
+    // Reply code for the status of an HTTP CONNECT response
+    pub fn from_http_status(status: u16) -> ReplyCode {
+        match status {
+            200..=299 => ReplyCode::Succeeded,
+            403 | 407 => ReplyCode::NotAllowed,
+            501       => ReplyCode::CommandNotSupported,
+            502       => ReplyCode::HostUnreachable,
+            504       => ReplyCode::TtlExpired,
+            _         => ReplyCode::GeneralFailure
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Socks5,
+    Socks4,
+    // HTTP CONNECT tunnel
+    HttpConnect,
+    // HTTP request with absolute URI, which is forwarded to the destination
+    HttpForward
+}
+
+impl Protocol {
+    // Encode a server reply in this protocol.
+    // SOCKS4 knows only granted or rejected and ipv4 bind addresses.
+    pub fn reply(self, code: ReplyCode, bind: Option<SocketAddr>) -> Vec<u8> {
+        match self {
+            Protocol::Socks5 => SocksRequestResponse::reply(code, bind).bytes,
+            Protocol::Socks4 => {
+                let rep = if code == ReplyCode::Succeeded {
+                        v4::REP_GRANTED
+                    }
+                    else {
+                        v4::REP_REJECTED
+                    };
+                let mut bytes = vec![v4::REPLY_VERSION, rep, 0, 0, 0, 0, 0, 0];
+                if let Some(SocketAddr::V4(addr)) = bind {
+                    bytes[2] = (addr.port() >> 8) as u8;
+                    bytes[3] = addr.port() as u8;
+                    bytes[4..8].copy_from_slice(&addr.ip().octets());
+                }
+                bytes
+            },
+            Protocol::HttpConnect | Protocol::HttpForward => {
+                // On success of a forwarded request, the destination answers
+                let status = match code {
+                    ReplyCode::Succeeded if self == Protocol::HttpForward => return vec!(),
+                    ReplyCode::Succeeded => {
+                        return b"HTTP/1.1 200 Connection established\r\n\r\n".to_vec();
+                    },
+                    ReplyCode::NotAllowed => "403 Forbidden",
+                    ReplyCode::TtlExpired => "504 Gateway Timeout",
+                    ReplyCode::CommandNotSupported |
+                    ReplyCode::AddressTypeNotSupported => "501 Not Implemented",
+                    _ => "502 Bad Gateway"
+                };
+                format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status).into_bytes()
+            }
+        }
+    }
+}
+
+// Request or reply in socks5 format. A request received from a SOCKS4
+// or HTTP client is converted and keeps the protocol and the user.
+#[derive(Clone)]
 pub struct SocksRequestResponse {
-    pub bytes: Vec<u8>
+    pub bytes: Vec<u8>,
+    pub protocol: Protocol,
+    pub user: Option<String>,
+    // Data of the client, which has been received with the request and
+    // has to be sent to the destination, once connected.
+    pub early_data: Vec<u8>
 }
 
 impl SocksRequestResponse {
+    pub fn new(bytes: Vec<u8>) -> SocksRequestResponse {
+        SocksRequestResponse {
+            bytes,
+            protocol: Protocol::Socks5,
+            user: None,
+            early_data: vec!()
+        }
+    }
+
+    // Build a server reply. Without bind address, 0.0.0.0:0 is sent.
+    pub fn reply(code: ReplyCode, bind: Option<SocketAddr>) -> SocksRequestResponse {
+        let bind = bind.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0,0,0,0)), 0));
+        SocksRequestResponse::with_socketaddr(code.to_u8(), &bind)
+    }
+
+    // Build a client request for an ip address
+    pub fn request(command: Command, addr: &SocketAddr) -> SocksRequestResponse {
+        SocksRequestResponse::with_socketaddr(command.to_u8(), addr)
+    }
+
+    // Build a client request for a hostname. The name is limited to 255 bytes.
+    pub fn request_hostname(command: Command, host: &str, port: u16)
+                                            -> io::Result<SocksRequestResponse> {
+        if host.is_empty() || host.len() > 255 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid hostname length"));
+        }
+        let mut bytes = vec![v5::VERSION, command.to_u8(), 0, v5::ATYP_DOMAIN, host.len() as u8];
+        bytes.extend_from_slice(host.as_bytes());
+        bytes.push((port >> 8) as u8);
+        bytes.push(port as u8);
+        Ok(SocksRequestResponse::new(bytes))
+    }
+
+    // Build a client request for a hostname or a literal ip address,
+    // which may be enclosed in brackets.
+    pub fn request_host(command: Command, host: &str, port: u16)
+                                            -> io::Result<SocksRequestResponse> {
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => Ok(SocksRequestResponse::request(command, &SocketAddr::new(ip, port))),
+            Err(_) => SocksRequestResponse::request_hostname(command, host, port)
+        }
+    }
+
+    fn with_socketaddr(cmd_or_rep: u8, addr: &SocketAddr) -> SocksRequestResponse {
+        let mut bytes = vec![v5::VERSION, cmd_or_rep, 0];
+        match *addr {
+            SocketAddr::V4(ref addr) => {
+                bytes.push(v5::ATYP_IPV4);
+                bytes.extend_from_slice(&addr.ip().octets());
+            },
+            SocketAddr::V6(ref addr) => {
+                bytes.push(v5::ATYP_IPV6);
+                bytes.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        bytes.push((addr.port() >> 8) as u8);
+        bytes.push(addr.port() as u8);
+        SocksRequestResponse::new(bytes)
+    }
+    // Only meaningful for a reply
+    pub fn reply_code(&self) -> ReplyCode {
+        ReplyCode::from_u8(self.bytes[1])
+    }
+
     pub fn port(&self) -> u16 {
         let n = self.bytes.len();
         ((self.bytes[n-2] as u16) << 8) | (self.bytes[n-1] as u16)
@@ -75,10 +382,7 @@ impl SocksRequestResponse {
     }
 
     pub fn socketaddr(&self) -> Option<SocketAddr> {
-        match self.ipaddr() {
-            Some(ip) => Some(SocketAddr::new(ip, self.port())),
-            None => None
-        }
+        self.ipaddr().map(|ip| SocketAddr::new(ip, self.port()))
     }
 
     pub fn hostname(&self) -> Option<&[u8]> {
@@ -91,6 +395,15 @@ impl SocksRequestResponse {
         }
     }
 
+    // Host or address with port, e.g. for logs
+    pub fn destination(&self) -> String {
+        match (self.socketaddr(), self.hostname()) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(host)) => format!("{}:{}", String::from_utf8_lossy(host), self.port()),
+            (None, None) => format!("?:{}", self.port())
+        }
+    }
+
     pub fn command(&self) -> Command {
         match self.bytes[1] {
             v5::CMD_CONNECT       => Command::Connect,
@@ -99,84 +412,362 @@ impl SocksRequestResponse {
             cmd                   => Command::Unknown(cmd)
         }
     }
-
-    pub fn clone(&self) -> SocksRequestResponse {
-        SocksRequestResponse {
-            bytes: self.bytes.clone()
-        }
-    }
 }
 
-pub struct SocksHandshake {
+pub struct SocksHandshake<S = TcpStream> {
     request: SocksRequestResponse,
-    state: ServerState
+    state: ServerState<S>,
+    peer: Option<SocketAddr>,
+    acl: Option<Arc<Acl>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    metrics: Option<Arc<Metrics>>,
+    socks4: bool,
+    span: Span,
+    // NUL terminated field of a SOCKS4 request, which is read
+    field: Vec<u8>
 }
 
-pub struct SocksConnectHandshake {
+pub struct SocksConnectHandshake<S = TcpStream> {
     request: SocksRequestResponse,
-    state: ClientState,
-    response: SocksRequestResponse
+    credentials: Option<Credentials>,
+    state: ClientState<S>,
+    response: SocksRequestResponse,
+    span: Span
 }
 
 pub fn socks_handshake(stream: TcpStream) -> SocksHandshake {
+    match stream.peer_addr() {
+        Ok(peer) => socks_handshake_over(stream).with_peer(peer),
+        Err(_) => socks_handshake_over(stream)
+    }
+}
+
+// Server handshake over any stream, e.g. a TLS stream. The peer address
+// for acl checks has to be given with with_peer.
+pub fn socks_handshake_over<S: AsyncRead + AsyncWrite>(stream: S) -> SocksHandshake<S> {
     SocksHandshake { 
-        request: SocksRequestResponse {
-            bytes: Vec::with_capacity(v5::MAX_REQUEST_SIZE)
-        },
+        request: SocksRequestResponse::new(Vec::with_capacity(v5::MAX_REQUEST_SIZE)),
+        peer: None,
         state: ServerState::WaitClientAuthentication(
             read_exact(stream,vec!(0u8;2))
-        )
+        ),
+        acl: None,
+        authenticator: None,
+        metrics: None,
+        socks4: false,
+        span: debug_span!("socks_handshake", peer = field::Empty),
+        field: vec!()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> SocksHandshake<S> {
+    // Requests denied by the acl are answered with REP_NOT_ALLOWED
+    // and the future fails with ErrorKind::PermissionDenied.
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> SocksHandshake<S> {
+        self.acl = Some(acl);
+        self
+    }
+
+    // Require username/password authentication of socks5 clients.
+    // SOCKS4 requests are answered with REP_NOT_ALLOWED.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> SocksHandshake<S> {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> SocksHandshake<S> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    // Accept SOCKS4 and SOCKS4A requests besides socks5, detected by
+    // the version in the first byte.
+    pub fn with_socks4(mut self) -> SocksHandshake<S> {
+        self.socks4 = true;
+        self
+    }
+
+    // Source address of the client for acl checks
+    pub fn with_peer(mut self, peer: SocketAddr) -> SocksHandshake<S> {
+        self.span.record("peer", field::display(peer));
+        self.peer = Some(peer);
+        self
+    }
+
+    fn is_allowed(&self) -> bool {
+        // SOCKS4 has no authentication
+        if self.authenticator.is_some() && self.request.protocol == Protocol::Socks4 {
+            return false;
+        }
+        match self.acl {
+            Some(ref acl) => {
+                let source = self.peer.map(|addr| addr.ip());
+                acl.check(source, &self.request, self.request.user.as_deref()) == Action::Allow
+            },
+            None => true
+        }
+    }
+
+    // Deliver the complete request or answer it with REP_NOT_ALLOWED
+    fn finish(&mut self, stream: S) -> Result<(S,SocksRequestResponse),ServerState<S>> {
+        if let Some(ref metrics) = self.metrics {
+            metrics.record_command(self.request.command());
+        }
+        if self.is_allowed() {
+            let sr = mem::replace(&mut self.request, SocksRequestResponse::new(vec!()));
+            Ok((stream,sr))
+        }
+        else {
+            if let Some(ref metrics) = self.metrics {
+                metrics.record_reply_sent(ReplyCode::NotAllowed);
+            }
+            debug!(target = %self.request.destination(), reply = %reply_label(ReplyCode::NotAllowed),
+                   "request not allowed");
+            let reply = self.request.protocol.reply(ReplyCode::NotAllowed, None);
+            Err(ServerState::AnswerNotAllowed(write_all(stream,reply)))
+        }
+    }
+
+    // Append one byte to the NUL terminated field. Delivers the field,
+    // if the terminating NUL has been read.
+    fn read_field(&mut self, byte: u8) -> io::Result<Option<Vec<u8>>> {
+        if byte == 0 {
+            return Ok(Some(mem::take(&mut self.field)));
+        }
+        if self.field.len() == v4::MAX_FIELD_SIZE {
+            return Err(Error::new(ErrorKind::Other, "Field in socks4 request too long"));
+        }
+        self.field.push(byte);
+        Ok(None)
+    }
+
+    // Replace the received SOCKS4 header with the equivalent socks5 request
+    fn convert_socks4(&mut self, hostname: Option<Vec<u8>>) {
+        let header = mem::take(&mut self.request.bytes);
+        let mut bytes = vec![v5::VERSION, header[1], 0];
+        match hostname {
+            Some(name) => {
+                bytes.push(v5::ATYP_DOMAIN);
+                bytes.push(name.len() as u8);
+                bytes.extend_from_slice(&name);
+            },
+            None => {
+                bytes.push(v5::ATYP_IPV4);
+                bytes.extend_from_slice(&header[4..8]);
+            }
+        }
+        bytes.extend_from_slice(&header[2..4]);
+        self.request.bytes = bytes;
+        self.request.protocol = Protocol::Socks4;
     }
 }
 
-pub fn socks_connect_handshake(stream: TcpStream,request: SocksRequestResponse)
-                                                         -> SocksConnectHandshake {
+pub fn socks_connect_handshake<S>(stream: S,request: SocksRequestResponse)
+                                                         -> SocksConnectHandshake<S>
+        where S: AsyncRead + AsyncWrite {
+    socks_connect_handshake_with_credentials(stream,request,None)
+}
+
+// With credentials, the proxy may select 'no authentication'
+// or 'username/password'.
+pub fn socks_connect_handshake_with_credentials<S>(stream: S,
+                                                   request: SocksRequestResponse,
+                                                   credentials: Option<Credentials>)
+                                                         -> SocksConnectHandshake<S>
+        where S: AsyncRead + AsyncWrite {
+    let methods = match credentials {
+        Some(_) => vec![v5::VERSION,2u8,v5::METH_NO_AUTH,v5::METH_USER_PASS],
+        None => vec![v5::VERSION,1u8,v5::METH_NO_AUTH]
+    };
     SocksConnectHandshake { 
-        request,
+        credentials,
         state: ClientState::WaitSentAuthentication(
-            write_all(stream,vec![v5::VERSION,1u8,v5::METH_NO_AUTH])
+            write_all(stream,methods)
         ),
-        response: SocksRequestResponse {
-            bytes: Vec::with_capacity(v5::MAX_REQUEST_SIZE)
-        }
+        span: debug_span!("socks_connect_handshake", target = %request.destination()),
+        request,
+        response: SocksRequestResponse::new(Vec::with_capacity(v5::MAX_REQUEST_SIZE))
     }
 }
 
-impl Future for SocksHandshake {
-    type Item = (TcpStream,SocksRequestResponse);
+impl<S: AsyncRead + AsyncWrite> Future for SocksHandshake<S> {
+    type Item = (S,SocksRequestResponse);
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let result = self.poll_handshake();
+        match result {
+            Ok(Async::NotReady) => (),
+            Ok(Async::Ready((_, ref request))) => {
+                debug!(target = %request.destination(), command = command_label(request.command()),
+                       user = ?request.user, "handshake done");
+            },
+            Err(ref e) => debug!(state = self.state.name(), error = %e, "handshake failed")
+        }
+        if let Some(ref metrics) = self.metrics {
+            match result {
+                Ok(Async::NotReady) => (),
+                Ok(Async::Ready(_)) => metrics.record_handshake(None),
+                Err(ref e) => metrics.record_handshake(Some(e))
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> SocksHandshake<S> {
+    fn poll_handshake(&mut self) -> Result<Async<(S,SocksRequestResponse)>, io::Error> {
         use self::ServerState::*;
 
         loop {
+            let from = self.state.name();
             self.state = match self.state {
                 WaitClientAuthentication(ref mut fut) => {
                     let (stream,buf) = try_ready!(fut.poll());
-                    if (buf[0] != v5::VERSION) || (buf[1] == 0) {
+                    if self.socks4 && buf[0] == v4::VERSION {
+                        self.request.bytes.extend_from_slice(&buf);
+                        ReadSocks4Request(
+                            read_exact(stream,vec![0u8; v4::REQUEST_SIZE - 2])
+                        )
+                    }
+                    else if (buf[0] != v5::VERSION) || (buf[1] == 0) {
                         return Err(Error::new(ErrorKind::Other, "Not Socks5 protocol"));
                     }
-                    ReadAuthenticationMethods(
-                        read_exact(stream,vec![0u8; buf[1] as usize])
+                    else {
+                        ReadAuthenticationMethods(
+                            read_exact(stream,vec![0u8; buf[1] as usize])
+                        )
+                    }
+                }
+                ReadSocks4Request(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    self.request.bytes.extend_from_slice(&buf);
+                    ReadSocks4UserId(
+                        read_exact(stream,vec![0u8; 1])
                     )
                 }
+                ReadSocks4UserId(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    match self.read_field(buf[0])? {
+                        None => ReadSocks4UserId(
+                            read_exact(stream,buf)
+                        ),
+                        Some(userid) => {
+                            // Chosen by the client, so it is not the user
+                            debug!(userid = %String::from_utf8_lossy(&userid), "socks4 userid ignored");
+                            // SOCKS4A: destination ip 0.0.0.x with x != 0
+                            let ip = &self.request.bytes[4..8];
+                            if ip[0] == 0 && ip[1] == 0 && ip[2] == 0 && ip[3] != 0 {
+                                ReadSocks4Hostname(
+                                    read_exact(stream,buf)
+                                )
+                            }
+                            else {
+                                self.convert_socks4(None);
+                                match self.finish(stream) {
+                                    Ok(item) => return Ok(Async::Ready(item)),
+                                    Err(state) => state
+                                }
+                            }
+                        }
+                    }
+                }
+                ReadSocks4Hostname(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    match self.read_field(buf[0])? {
+                        None => ReadSocks4Hostname(
+                            read_exact(stream,buf)
+                        ),
+                        Some(ref hostname) if hostname.is_empty() => {
+                            return Err(Error::new(ErrorKind::Other,
+                                                  "Empty hostname in socks4a request"));
+                        },
+                        Some(hostname) => {
+                            self.convert_socks4(Some(hostname));
+                            match self.finish(stream) {
+                                Ok(item) => return Ok(Async::Ready(item)),
+                                Err(state) => state
+                            }
+                        }
+                    }
+                }
                 ReadAuthenticationMethods(ref mut fut) => {
                     let (stream,buf) = try_ready!(fut.poll());
-                    let answer = if buf.contains(&v5::METH_NO_AUTH) {
-                            v5::METH_NO_AUTH
+                    let method = match self.authenticator {
+                        Some(_) => v5::METH_USER_PASS,
+                        None => v5::METH_NO_AUTH
+                    };
+                    let answer = if buf.contains(&method) {
+                            method
                         }
                         else {
                             v5::METH_NO_ACCEPTABLE_METHOD
                         };
-                    AnswerNoAuthentication(
+                    debug!(method = method_label(answer), "authentication method selected");
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.record_auth_method(answer);
+                    }
+                    AnswerMethod(
                         write_all(stream, vec![v5::VERSION, answer])
                     )
                 }
-                AnswerNoAuthentication(ref mut fut) => {
+                AnswerMethod(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    match buf[1] {
+                        v5::METH_NO_AUTH => WaitClientRequest(
+                            read_exact(stream,vec![0u8; v5::MIN_REQUEST_SIZE])
+                        ),
+                        v5::METH_USER_PASS => ReadUserPassHeader(
+                            read_exact(stream,vec![0u8; 2])
+                        ),
+                        _ => return Err(Error::new(ErrorKind::Other,
+                                    "No acceptable authentication method offered"))
+                    }
+                }
+                ReadUserPassHeader(ref mut fut) => {
                     let (stream,buf) = try_ready!(fut.poll());
-                    if buf[1] == v5::METH_NO_ACCEPTABLE_METHOD {
+                    if buf[0] != v5::USER_PASS_VERSION {
                         return Err(Error::new(ErrorKind::Other,
-                                    "Only 'no authentication' supported"));
+                                    "Unknown username/password version"));
+                    }
+                    // Username and the length of the password
+                    ReadUsername(
+                        read_exact(stream,vec![0u8; buf[1] as usize + 1])
+                    )
+                }
+                ReadUsername(ref mut fut) => {
+                    let (stream,mut buf) = try_ready!(fut.poll());
+                    let password_len = buf.pop().unwrap_or(0);
+                    self.request.user = Some(String::from_utf8_lossy(&buf).into_owned());
+                    ReadPassword(
+                        read_exact(stream,vec![0u8; password_len as usize])
+                    )
+                }
+                ReadPassword(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    let password = String::from_utf8_lossy(&buf);
+                    let accepted = match (self.authenticator.as_ref(), self.request.user.as_ref()) {
+                        (Some(authenticator), Some(user)) => authenticator.authenticate(user, &password),
+                        _ => false
+                    };
+                    let status = if accepted {
+                            v5::USER_PASS_SUCCEEDED
+                        }
+                        else {
+                            v5::USER_PASS_FAILURE
+                        };
+                    debug!(user = ?self.request.user, accepted, "username/password authentication");
+                    AnswerCredentials(
+                        write_all(stream, vec![v5::USER_PASS_VERSION, status])
+                    )
+                }
+                AnswerCredentials(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    if buf[1] != v5::USER_PASS_SUCCEEDED {
+                        return Err(Error::new(ErrorKind::PermissionDenied,
+                                    "Username/password authentication failed"));
                     }
                     WaitClientRequest(
                         read_exact(stream,vec![0u8; v5::MIN_REQUEST_SIZE])
@@ -196,32 +787,64 @@ impl Future for SocksHandshake {
                         match self.request.bytes[3] {
                             v5::ATYP_IPV4   => 4,
                             v5::ATYP_IPV6   => 16,
-                            v5::ATYP_DOMAIN => self.request.bytes[4]+1,
+                            v5::ATYP_DOMAIN if self.request.bytes[4] > 0 => {
+                                self.request.bytes[4] as usize + 1
+                            },
+                            v5::ATYP_DOMAIN => return Err(Error::new(ErrorKind::Other,
+                                            "Empty hostname in socks5 request")),
                             _ => return Err(Error::new(ErrorKind::Other, 
                                             "Unknown address type in socks5 request"))
                         };
-                    let delta = (dst_len as usize) + 6 - self.request.bytes.len();
-                    if delta == 0 {
-                        let sr = mem::replace(&mut self.request,SocksRequestResponse{ bytes:vec!()});
-                        return Ok(Async::Ready(((stream,sr))));
+                    let delta = dst_len + 6 - self.request.bytes.len();
+                    if delta != 0 {
+                        WaitClientRequest(
+                            read_exact(stream,vec![0u8; delta])
+                        )
+                    }
+                    else {
+                        match self.finish(stream) {
+                            Ok(item) => return Ok(Async::Ready(item)),
+                            Err(state) => state
+                        }
                     }
-                    WaitClientRequest(
-                        read_exact(stream,vec![0u8; delta])
-                    )
                 }
-            }
+                AnswerNotAllowed(ref mut fut) => {
+                    try_ready!(fut.poll());
+                    return Err(Error::new(ErrorKind::PermissionDenied,
+                                "Socks5 request not allowed"));
+                }
+            };
+            trace!(from, to = self.state.name(), "state transition");
         }
     }
 }
 
-impl Future for SocksConnectHandshake {
-    type Item = (TcpStream,SocksRequestResponse);
+impl<S: AsyncRead + AsyncWrite> Future for SocksConnectHandshake<S> {
+    type Item = (S,SocksRequestResponse);
     type Error = io::Error;
 
     fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let result = self.poll_handshake();
+        match result {
+            Ok(Async::NotReady) => (),
+            Ok(Async::Ready((_, ref reply))) => {
+                debug!(reply = %reply_label(reply.reply_code()), bind = %reply.destination(),
+                       "handshake done");
+            },
+            Err(ref e) => debug!(state = self.state.name(), error = %e, "handshake failed")
+        }
+        result
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> SocksConnectHandshake<S> {
+    fn poll_handshake(&mut self) -> Result<Async<(S,SocksRequestResponse)>, io::Error> {
         use self::ClientState::*;
 
         loop {
+            let from = self.state.name();
             self.state = match self.state {
                 WaitSentAuthentication(ref mut fut) => {
                     let (stream,_buf) = try_ready!(fut.poll());
@@ -231,9 +854,33 @@ impl Future for SocksConnectHandshake {
                 },
                 WaitAuthenticationMethod(ref mut fut) => {
                     let (stream,buf) = try_ready!(fut.poll());
-                    if (buf[0] != v5::VERSION) || (buf[1] != 0) {
+                    if buf[0] != v5::VERSION {
                         return Err(Error::new(ErrorKind::Other, "No Socks5 proxy found"));
                     }
+                    debug!(method = method_label(buf[1]), "authentication method selected by proxy");
+                    match (buf[1], self.credentials.as_ref()) {
+                        (v5::METH_NO_AUTH, _) => WaitSentRequest(
+                            write_all(stream,self.request.bytes.clone())
+                        ),
+                        (v5::METH_USER_PASS, Some(credentials)) => WaitSentCredentials(
+                            write_all(stream,credentials.to_bytes()?)
+                        ),
+                        _ => return Err(Error::new(ErrorKind::PermissionDenied,
+                                        "No acceptable authentication method"))
+                    }
+                },
+                WaitSentCredentials(ref mut fut) => {
+                    let (stream,_buf) = try_ready!(fut.poll());
+                    WaitCredentialsStatus(
+                        read_exact(stream,vec![0u8; 2])
+                    )
+                },
+                WaitCredentialsStatus(ref mut fut) => {
+                    let (stream,buf) = try_ready!(fut.poll());
+                    if buf[1] != v5::USER_PASS_SUCCEEDED {
+                        return Err(Error::new(ErrorKind::PermissionDenied,
+                                        "Username/password authentication failed"));
+                    }
                     WaitSentRequest(
                        write_all(stream,self.request.bytes.clone())
                     )
@@ -254,27 +901,29 @@ impl Future for SocksConnectHandshake {
                         return Err(Error::new(ErrorKind::Other, 
                                 "Reserved field in socks5 response is not 0x00"))
                     };
-                    if self.response.bytes[2] != self.response.bytes[2] {
-                        return Err(Error::new(ErrorKind::Other, "Response command differs from request"))
-                    };
                     let dst_len =
                         match self.response.bytes[3] {
                             v5::ATYP_IPV4   => 4,
                             v5::ATYP_IPV6   => 16,
-                            v5::ATYP_DOMAIN => self.response.bytes[4]+1,
+                            v5::ATYP_DOMAIN if self.response.bytes[4] > 0 => {
+                                self.response.bytes[4] as usize + 1
+                            },
+                            v5::ATYP_DOMAIN => return Err(Error::new(ErrorKind::Other,
+                                                "Empty hostname in socks5 response")),
                             _ => return Err(Error::new(ErrorKind::Other, 
                                                 "Unknown address typ in socks5 response"))
                         };
-                    let delta = (dst_len as usize) + 6 - self.response.bytes.len();
+                    let delta = dst_len + 6 - self.response.bytes.len();
                     if delta == 0 {
-                        let sr = mem::replace(&mut self.response,SocksRequestResponse{ bytes:vec!()});
+                        let sr = mem::replace(&mut self.response,SocksRequestResponse::new(vec!()));
                         return Ok(Async::Ready((stream,sr)));
                     }
                     WaitReply(
                         read_exact(stream,vec![0u8; delta])
                     )
                 }
-            }
+            };
+            trace!(from, to = self.state.name(), "state transition");
         }
     }
 }
@@ -0,0 +1,298 @@
+// Zero-copy relay on Linux
+// ========================
+//
+// splice_relay moves the data between two TCP streams with splice(2)
+// through a pipe per direction, so the bytes are not copied to user space.
+// Half-close, idle timeout, throttle and RelayStats, also on I/O errors,
+// are the same as for relay.
+//
+// For the readiness of the sockets, a duplicate of each file descriptor is
+// registered with the reactor. If the pipes or the registration cannot be
+// created (e.g. out of file descriptors), the buffered relay is used.
+// Other stream types (TLS, unix) always use relay.
+//
+
+use std::cmp;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::mem::ManuallyDrop;
+use std::net;
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::time::{Duration, Instant};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, PollEvented};
+use futures::*;
+use futures::Async;
+use libc;
+use mio;
+use bandwidth::{Throttle, Way};
+use relay::{relay, IdleTimer, Relay, RelayStats};
+
+// Default capacity of a pipe
+const PIPE_SIZE: usize = 65536;
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd
+}
+
+impl Pipe {
+    fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Pipe { read: fds[0], write: fds[1] })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(from, ptr::null_mut(), to, ptr::null_mut(), len,
+                     libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+// The stream and the readiness of its duplicated file descriptor
+struct Socket {
+    stream: TcpStream,
+    events: PollEvented<mio::net::TcpStream>
+}
+
+fn register(stream: &TcpStream, handle: &Handle) -> io::Result<PollEvented<mio::net::TcpStream>> {
+    // Borrow the file descriptor without closing it
+    let borrowed = ManuallyDrop::new(unsafe { net::TcpStream::from_raw_fd(stream.as_raw_fd()) });
+    let duplicate = mio::net::TcpStream::from_stream(borrowed.try_clone()?)?;
+    PollEvented::new(duplicate, handle)
+}
+
+impl Socket {
+    fn fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+struct Direction {
+    pipe: Pipe,
+    pending: usize,
+    eof: bool,
+    closed: bool,
+    amount: u64
+}
+
+impl Direction {
+    fn new() -> io::Result<Direction> {
+        Ok(Direction {
+            pipe: Pipe::new()?,
+            pending: 0,
+            eof: false,
+            closed: false,
+            amount: 0
+        })
+    }
+
+    // Move as much as possible without blocking and without reading more
+    // than the budget. Delivers, if any data has been moved.
+    fn transfer(&mut self, from: &Socket, to: &Socket, budget: &mut u64) -> io::Result<bool> {
+        let mut progress = false;
+        loop {
+            if self.pending == 0 && !self.eof {
+                if *budget == 0 {
+                    return Ok(progress);
+                }
+                if let Async::NotReady = from.events.poll_read() {
+                    return Ok(progress);
+                }
+                let len = cmp::min(PIPE_SIZE as u64, *budget) as usize;
+                match splice(from.fd(), self.pipe.write, len) {
+                    Ok(0) => self.eof = true,
+                    Ok(n) => {
+                        self.pending = n;
+                        *budget -= n as u64;
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        from.events.need_read();
+                        return Ok(progress);
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+            while self.pending > 0 {
+                if let Async::NotReady = to.events.poll_write() {
+                    return Ok(progress);
+                }
+                match splice(self.pipe.read, to.fd(), self.pending) {
+                    Ok(0) => return Err(Error::new(ErrorKind::WriteZero,
+                                                   "Write zero bytes into relay target")),
+                    Ok(n) => {
+                        self.pending -= n;
+                        self.amount += n as u64;
+                        progress = true;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        to.events.need_write();
+                        return Ok(progress);
+                    },
+                    Err(e) => return Err(e)
+                }
+            }
+            if self.eof {
+                if !self.closed {
+                    to.stream.shutdown(Shutdown::Write)?;
+                    self.closed = true;
+                }
+                return Ok(progress);
+            }
+        }
+    }
+}
+
+struct Splice {
+    client: Socket,
+    target: Socket,
+    upstream: Direction,
+    downstream: Direction
+}
+
+enum SpliceState {
+    Splice(Box<Splice>),
+    Buffered(Box<Relay<TcpStream,TcpStream>>)
+}
+
+pub struct SpliceRelay {
+    state: SpliceState,
+    started: Instant,
+    idle: IdleTimer,
+    throttle: Option<Throttle>
+}
+
+pub fn splice_relay(client: TcpStream, target: TcpStream, handle: &Handle) -> SpliceRelay {
+    let setup = (Direction::new(), Direction::new(),
+                 register(&client, handle), register(&target, handle));
+    let state = match setup {
+        (Ok(upstream), Ok(downstream), Ok(client_events), Ok(target_events)) => {
+            SpliceState::Splice(Box::new(Splice {
+                client: Socket { stream: client, events: client_events },
+                target: Socket { stream: target, events: target_events },
+                upstream,
+                downstream
+            }))
+        },
+        _ => SpliceState::Buffered(Box::new(relay(client, target)))
+    };
+    SpliceRelay {
+        state,
+        started: Instant::now(),
+        idle: IdleTimer::new(),
+        throttle: None
+    }
+}
+
+impl SpliceRelay {
+    pub fn with_idle_timeout(mut self, timeout: Duration, handle: &Handle) -> SpliceRelay {
+        self.state = match self.state {
+            SpliceState::Buffered(relay) => {
+                SpliceState::Buffered(Box::new(relay.with_idle_timeout(timeout, handle)))
+            },
+            state => state
+        };
+        self.idle.set(timeout, handle);
+        self
+    }
+
+    pub fn with_throttle(mut self, throttle: Throttle) -> SpliceRelay {
+        let mut throttle = Some(throttle);
+        self.state = match self.state {
+            SpliceState::Buffered(relay) => match throttle.take() {
+                Some(throttle) => SpliceState::Buffered(Box::new(relay.with_throttle(throttle))),
+                None => SpliceState::Buffered(relay)
+            },
+            state => state
+        };
+        self.throttle = throttle;
+        self
+    }
+
+    // True, if the pipes are used
+    pub fn is_zero_copy(&self) -> bool {
+        match self.state {
+            SpliceState::Splice(_) => true,
+            SpliceState::Buffered(_) => false
+        }
+    }
+}
+
+impl Future for SpliceRelay {
+    type Item = RelayStats;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        let (timed_out, error) = match self.state {
+            SpliceState::Splice(_) => match self.poll_splice() {
+                Ok(Async::Ready(timed_out)) => (timed_out, None),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => (false, Some(e.kind()))
+            },
+            SpliceState::Buffered(ref mut relay) => return relay.poll()
+        };
+        let (sent, received) = match self.state {
+            SpliceState::Splice(ref splice) => (splice.upstream.amount, splice.downstream.amount),
+            SpliceState::Buffered(_) => (0, 0)
+        };
+        Ok(Async::Ready(RelayStats {
+            sent,
+            received,
+            duration: self.started.elapsed(),
+            timed_out,
+            error
+        }))
+    }
+}
+
+impl SpliceRelay {
+    // Delivers true, if finished by the idle timeout
+    fn poll_splice(&mut self) -> Poll<bool, io::Error> {
+        let splice = match self.state {
+            SpliceState::Splice(ref mut splice) => splice,
+            SpliceState::Buffered(_) => return Ok(Async::Ready(false))
+        };
+        let (mut up_budget, mut down_budget) = match self.throttle {
+            Some(ref mut throttle) => (throttle.allowance(Way::Upload),
+                                       throttle.allowance(Way::Download)),
+            None => (u64::MAX, u64::MAX)
+        };
+        let (up_before, down_before) = (up_budget, down_budget);
+        let up = splice.upstream.transfer(&splice.client, &splice.target, &mut up_budget)?;
+        let down = splice.downstream.transfer(&splice.target, &splice.client, &mut down_budget)?;
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.consume(Way::Upload, up_before - up_budget);
+            throttle.consume(Way::Download, down_before - down_budget);
+            let up_limited = up_budget == 0 && !splice.upstream.eof;
+            let down_limited = down_budget == 0 && !splice.downstream.eof;
+            if up_limited || down_limited {
+                throttle.wait(up_limited, down_limited)?;
+            }
+        }
+        let closed = splice.upstream.closed && splice.downstream.closed;
+        if !closed {
+            try_ready!(self.idle.poll(up || down));
+        }
+        Ok(Async::Ready(!closed))
+    }
+}
@@ -0,0 +1,89 @@
+// Socks over TLS
+// ==============
+//
+// With the cargo feature "tls", the handshakes can run inside TLS, so
+// username/password credentials are never sent in plain text.
+//
+// On server side, a TlsAcceptor is built from a PEM certificate (chain)
+// and a PKCS#8 PEM key. socks_tls_handshake accepts TLS and runs the
+// SocksHandshake over the TLS stream with the peer address of the TCP
+// connection for acl checks. The delivered stream can be passed to
+// socks_dial like a plain TcpStream.
+//
+// On client side, a TlsConnector verifies the certificate of the proxy
+// against the system roots and the optionally given PEM root certificate.
+// socks_tls_connect_handshake establishes TLS for the proxy's domain and
+// runs the SocksConnectHandshake over it.
+//
+
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use tokio_core::net::TcpStream;
+use futures::Future;
+use native_tls;
+use socks_fut::{socks_connect_handshake_with_credentials, socks_handshake_over,
+                Credentials, SocksHandshake, SocksRequestResponse};
+
+pub use tokio_tls::{TlsAcceptor, TlsConnector, TlsStream};
+
+pub type TlsAccept = Box<dyn Future<Item=TlsStream<TcpStream>, Error=io::Error>>;
+pub type TlsHandshake = Box<dyn Future<Item=(TlsStream<TcpStream>,SocksRequestResponse), Error=io::Error>>;
+
+fn tls_error(e: native_tls::Error) -> io::Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let mut content = vec!();
+    File::open(path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+pub fn tls_acceptor(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<TlsAcceptor> {
+    let identity = native_tls::Identity::from_pkcs8(cert_pem, key_pem).map_err(tls_error)?;
+    let acceptor = native_tls::TlsAcceptor::new(identity).map_err(tls_error)?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+pub fn tls_acceptor_from_files<P: AsRef<Path>>(cert: P, key: P) -> io::Result<TlsAcceptor> {
+    tls_acceptor(&read_file(cert)?, &read_file(key)?)
+}
+
+// Without root certificate only the system roots are trusted
+pub fn tls_connector(root_pem: Option<&[u8]>) -> io::Result<TlsConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(pem) = root_pem {
+        builder.add_root_certificate(native_tls::Certificate::from_pem(pem).map_err(tls_error)?);
+    }
+    Ok(TlsConnector::from(builder.build().map_err(tls_error)?))
+}
+
+// Accept TLS only. The socks handshake can be configured by the caller
+// with socks_handshake_over(stream).with_peer(peer).
+pub fn tls_accept(stream: TcpStream, acceptor: &TlsAcceptor) -> TlsAccept {
+    Box::new(acceptor.accept(stream).map_err(tls_error))
+}
+
+pub fn socks_tls_handshake(stream: TcpStream, acceptor: &TlsAcceptor) -> TlsHandshake {
+    let peer = stream.peer_addr();
+    Box::new(tls_accept(stream, acceptor)
+        .and_then(move |stream| {
+            let handshake: SocksHandshake<_> = socks_handshake_over(stream);
+            match peer {
+                Ok(peer) => handshake.with_peer(peer),
+                Err(_) => handshake
+            }
+        }))
+}
+
+pub fn socks_tls_connect_handshake(stream: TcpStream, domain: &str, connector: &TlsConnector,
+                                   request: SocksRequestResponse,
+                                   credentials: Option<Credentials>) -> TlsHandshake {
+    Box::new(connector.connect(domain, stream)
+        .map_err(tls_error)
+        .and_then(move |stream| {
+            socks_connect_handshake_with_credentials(stream, request, credentials)
+        }))
+}
@@ -0,0 +1,133 @@
+// Transparent proxy on Linux
+// ==========================
+//
+// For applications without proxy support, the traffic is redirected to a
+// local port by the kernel, e.g.
+//
+//     iptables -t nat -A OUTPUT -p tcp --dport 443 -j REDIRECT --to-ports 1081
+//
+// The accepted connection still knows its original destination, which is
+// read with getsockopt(SO_ORIGINAL_DST) (IP6T_SO_ORIGINAL_DST for IPv6).
+// TransparentProxy builds a CONNECT request for this address, requests it
+// from the upstream socks5 proxy (or chain) and relays like PortForward.
+//
+// A connection, which has not been redirected, fails: either the kernel
+// knows no original destination or the destination is the listener itself,
+// which would loop. listen keeps accepting after a failed accept (see
+// accept_retry).
+//
+
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Handle;
+use futures::{future, Future, Stream};
+use libc;
+use tracing::debug;
+use accept::accept_retry;
+use bandwidth::BandwidthLimiter;
+use chain::{Proxy, ProxyChain};
+use forward::ForwardFuture;
+use metrics::Metrics;
+use port_forward::tunnel;
+use relay::RelayConfig;
+use socks_fut::{Command, SocksRequestResponse};
+
+pub type TransparentServer = Box<dyn Future<Item=(), Error=io::Error>>;
+
+fn getsockopt<T>(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, level, name, &mut value as *mut T as *mut libc::c_void, &mut len)
+    };
+    if res < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// Destination of a connection before its redirection by netfilter
+pub fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let fd = stream.as_raw_fd();
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => {
+            let addr: libc::sockaddr_in = getsockopt(fd, libc::SOL_IP, libc::SO_ORIGINAL_DST)?;
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        },
+        SocketAddr::V6(_) => {
+            let addr: libc::sockaddr_in6 = getsockopt(fd, libc::SOL_IPV6,
+                                                      libc::IP6T_SO_ORIGINAL_DST)?;
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port),
+                                                addr.sin6_flowinfo, addr.sin6_scope_id)))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TransparentProxy {
+    pub chain: ProxyChain,
+    relay: RelayConfig
+}
+
+impl TransparentProxy {
+    pub fn new(proxy: Proxy) -> TransparentProxy {
+        TransparentProxy::with_chain(ProxyChain::new(vec![proxy]))
+    }
+
+    pub fn with_chain(chain: ProxyChain) -> TransparentProxy {
+        TransparentProxy { chain, relay: RelayConfig::default() }
+    }
+
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> TransparentProxy {
+        self.relay.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, limiter: Arc<BandwidthLimiter>) -> TransparentProxy {
+        self.relay.bandwidth = Some(limiter);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> TransparentProxy {
+        self.relay.metrics = Some(metrics);
+        self
+    }
+
+    // Tunnel one redirected connection to its original destination
+    pub fn serve(&self, client: TcpStream, handle: &Handle) -> ForwardFuture {
+        let destination = match original_destination(&client) {
+            Ok(addr) if Some(addr) == client.local_addr().ok() => {
+                return Box::new(future::err(Error::new(ErrorKind::InvalidInput,
+                                                       "Connection has not been redirected")))
+            },
+            Ok(addr) => addr,
+            Err(e) => return Box::new(future::err(e))
+        };
+        debug!(%destination, "original destination");
+        let request = SocksRequestResponse::request(Command::Connect, &destination);
+        tunnel(&self.chain, request, client, &self.relay, handle)
+    }
+
+    // Accept redirected connections and serve each of them
+    pub fn listen(&self, listener: TcpListener, handle: &Handle) -> TransparentServer {
+        let proxy = self.clone();
+        let handle = handle.clone();
+        Box::new(accept_retry(listener, &handle).for_each(move |(stream,peer)| {
+            handle.spawn(proxy.serve(stream, &handle).then(move |res| {
+                if let Err(e) = res {
+                    debug!(%peer, error = %e, "transparent proxy failed");
+                }
+                Ok(())
+            }));
+            Ok(())
+        }))
+    }
+}
@@ -0,0 +1,139 @@
+// Unix domain sockets
+// ===================
+//
+// UnixSocksListener is a stream of clients connected to a socket path.
+// Each client can run the usual server handshake with socks_handshake_over.
+// There is no peer ip address, so acl rules with source never match.
+//
+// A stale socket file at the path is removed before binding. A socket,
+// which accepts connections, or any other file is left alone and binding
+// fails. The socket file is removed, when the listener is dropped.
+//
+// The socket is bound inside a new directory with mode 0700 next to the
+// path, gets its mode (DEFAULT_SOCKET_MODE or the one of bind_with_mode)
+// and only then is linked to the path. So nobody can connect, before the
+// mode is set. set_permissions changes the mode later on.
+//
+// On client side, socks_connect_unix runs the SocksConnectHandshake with
+// a proxy listening on a socket path. Proxy::unix makes such a proxy usable
+// in chains, upstream pools, Forwarder and Router.
+//
+
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio_uds::UnixListener;
+use futures::*;
+use socks_fut::{socks_connect_handshake_with_credentials, Credentials, SocksRequestResponse};
+
+pub use tokio_uds::UnixStream;
+
+pub type UnixConnect = Box<dyn Future<Item=(UnixStream,SocksRequestResponse), Error=io::Error>>;
+
+// Owner only
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+pub struct UnixSocksListener {
+    path: PathBuf,
+    listener: UnixListener
+}
+
+// Remove the socket file, if no one listens on it anymore
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::new(ErrorKind::AlreadyExists,
+                              format!("{} exists and is no socket", path.display())));
+    }
+    match net::UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(ErrorKind::AddrInUse,
+                                format!("{} is in use", path.display()))),
+        Err(_) => fs::remove_file(path)
+    }
+}
+
+// New directory with mode 0700 next to path
+fn private_dir(path: &Path) -> io::Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new(".")
+    };
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned())
+                               .unwrap_or_default();
+    let dir = parent.join(format!(".{}.{}.{}", name, process::id(),
+                                  COUNTER.fetch_add(1, Ordering::SeqCst)));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+// Bind in the private directory, set the mode and link the socket to path.
+// Linking fails, if path has been created meanwhile.
+fn bind_private(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let dir = private_dir(path)?;
+    let socket = dir.join("socket");
+    let res = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&socket);
+    let _ = fs::remove_dir(&dir);
+    res
+}
+
+impl UnixSocksListener {
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixSocksListener> {
+        UnixSocksListener::bind_with_mode(path, DEFAULT_SOCKET_MODE)
+    }
+
+    // Bind with the mode of the socket file, e.g. 0o660
+    pub fn bind_with_mode<P: AsRef<Path>>(path: P, mode: u32) -> io::Result<UnixSocksListener> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale_socket(&path)?;
+        let listener = bind_private(&path, mode)?;
+        Ok(UnixSocksListener { path, listener })
+    }
+
+    // Mode of the socket file, e.g. 0o660
+    pub fn set_permissions(&self, mode: u32) -> io::Result<()> {
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Stream for UnixSocksListener {
+    type Item = UnixStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<UnixStream>, io::Error> {
+        let (stream,_addr) = try_ready!(self.listener.poll_accept());
+        Ok(Async::Ready(Some(stream)))
+    }
+}
+
+impl Drop for UnixSocksListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub fn socks_connect_unix<P: AsRef<Path>>(path: P, request: SocksRequestResponse,
+                                          credentials: Option<Credentials>) -> UnixConnect {
+    Box::new(UnixStream::connect(path)
+        .and_then(move |stream| {
+            socks_connect_handshake_with_credentials(stream, request, credentials)
+        }))
+}
@@ -0,0 +1,282 @@
+// Selection of upstream socks5 proxies
+// ====================================
+//
+// An UpstreamPool selects one of several upstream proxies by policy:
+//
+// - RoundRobin: one after the other
+// - LeastConnections: the one with the fewest active connections
+// - ConsistentHash: by hashing the destination of the request onto a ring,
+//   so the same destination uses the same upstream as long as it is up
+// - Failover: always the first one, which is up
+//
+// Only upstreams supporting the command of the request are selected, e.g.
+// an HTTP proxy is never chosen for BIND or UDP ASSOCIATE.
+//
+// Health is tracked passively: the caller reports the outcome of each
+// connection attempt through the Lease, but only failures of the network or
+// of the upstream itself, not requests rejected locally. After max_failures
+// consecutive failures an upstream is marked down. A down upstream is not selected,
+// until the retry time has passed or an active health check succeeded.
+// If all upstreams are down, all of them are candidates again.
+//
+// Active health checks send a probe request with the handshake of the
+// proxy's kind to every upstream in a fixed interval.
+//
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Interval, Timeout};
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use chain::Proxy;
+use socks_fut::{Command, ReplyCode, SocksRequestResponse};
+
+// Virtual nodes per upstream on the hash ring
+const RING_REPLICAS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    LeastConnections,
+    ConsistentHash,
+    Failover
+}
+
+struct UpstreamState {
+    proxy: Proxy,
+    active: usize,
+    failures: u32,
+    down_since: Option<Instant>
+}
+
+struct PoolState {
+    upstreams: Vec<UpstreamState>,
+    next: usize
+}
+
+pub struct UpstreamPool {
+    policy: Policy,
+    max_failures: u32,
+    retry_after: Duration,
+    ring: Vec<(u64,usize)>,
+    state: Mutex<PoolState>
+}
+
+fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl UpstreamPool {
+    pub fn new(proxies: Vec<Proxy>, policy: Policy) -> UpstreamPool {
+        let mut ring = vec!();
+        for (i, proxy) in proxies.iter().enumerate() {
+            for replica in 0..RING_REPLICAS {
+                ring.push((hash(&(&proxy.addr, replica)), i));
+            }
+        }
+        ring.sort();
+        UpstreamPool {
+            policy,
+            max_failures: 3,
+            retry_after: Duration::from_secs(30),
+            ring,
+            state: Mutex::new(PoolState {
+                upstreams: proxies.into_iter().map(|proxy| UpstreamState {
+                    proxy,
+                    active: 0,
+                    failures: 0,
+                    down_since: None
+                }).collect(),
+                next: 0
+            })
+        }
+    }
+
+    // Consecutive failures, after which an upstream is marked down
+    pub fn with_max_failures(mut self, max_failures: u32) -> UpstreamPool {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    // Time after which a down upstream is tried again
+    pub fn with_retry_after(mut self, retry_after: Duration) -> UpstreamPool {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().upstreams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_up(&self, index: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        self.available(&state.upstreams[index], Instant::now())
+    }
+
+    // True, if any upstream supports the command
+    pub fn supports(&self, command: Command) -> bool {
+        self.state.lock().unwrap().upstreams.iter().any(|u| u.proxy.supports(command))
+    }
+
+    fn available(&self, upstream: &UpstreamState, now: Instant) -> bool {
+        match upstream.down_since {
+            Some(since) => now.duration_since(since) >= self.retry_after,
+            None => true
+        }
+    }
+
+    // Select an upstream for the request, skipping the excluded indices and
+    // upstreams not supporting the command. None, if there is no candidate left.
+    pub fn select(pool: &Arc<UpstreamPool>, request: &SocksRequestResponse,
+                  exclude: &[usize]) -> Option<Lease> {
+        let mut state = pool.state.lock().unwrap();
+        let now = Instant::now();
+        let command = request.command();
+        let supported: Vec<usize> = (0..state.upstreams.len())
+                    .filter(|i| !exclude.contains(i))
+                    .filter(|&i| state.upstreams[i].proxy.supports(command))
+                    .collect();
+        let mut candidates: Vec<usize> = supported.iter().cloned()
+                    .filter(|&i| pool.available(&state.upstreams[i], now))
+                    .collect();
+        if candidates.is_empty() {
+            candidates = supported;
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match pool.policy {
+            Policy::RoundRobin => {
+                let n = state.upstreams.len();
+                let start = state.next;
+                let index = (0..n).map(|k| (start + k) % n)
+                                .find(|i| candidates.contains(i))
+                                .unwrap_or(candidates[0]);
+                state.next = (index + 1) % n;
+                index
+            },
+            Policy::LeastConnections => {
+                *candidates.iter().min_by_key(|&&i| state.upstreams[i].active).unwrap()
+            },
+            Policy::ConsistentHash => {
+                let n = request.bytes.len();
+                let key = hash(&request.bytes[3..n]);
+                let start = pool.ring.iter().position(|&(h,_)| h >= key).unwrap_or(0);
+                let len = pool.ring.len();
+                (0..len).map(|k| pool.ring[(start + k) % len].1)
+                        .find(|i| candidates.contains(i))
+                        .unwrap_or(candidates[0])
+            },
+            Policy::Failover => candidates[0]
+        };
+        state.upstreams[index].active += 1;
+        Some(Lease {
+            pool: pool.clone(),
+            index,
+            proxy: state.upstreams[index].proxy.clone()
+        })
+    }
+
+    pub fn report_success(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        let upstream = &mut state.upstreams[index];
+        upstream.failures = 0;
+        upstream.down_since = None;
+    }
+
+    pub fn report_failure(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        let upstream = &mut state.upstreams[index];
+        upstream.failures += 1;
+        if upstream.failures >= self.max_failures {
+            upstream.down_since = Some(Instant::now());
+        }
+    }
+
+    fn proxies(&self) -> Vec<Proxy> {
+        self.state.lock().unwrap().upstreams.iter().map(|u| u.proxy.clone()).collect()
+    }
+}
+
+// A selected upstream. Counts as active connection until dropped.
+pub struct Lease {
+    pool: Arc<UpstreamPool>,
+    pub index: usize,
+    pub proxy: Proxy
+}
+
+impl Lease {
+    pub fn report_success(&self) {
+        self.pool.report_success(self.index);
+    }
+
+    pub fn report_failure(&self) {
+        self.pool.report_failure(self.index);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        state.upstreams[self.index].active -= 1;
+    }
+}
+
+// Probe every upstream once. An upstream is healthy, if the probe request
+// is answered with REP_SUCCEEDED within the timeout.
+pub fn check_upstreams(pool: Arc<UpstreamPool>, probe: SocksRequestResponse,
+                       timeout: Duration, handle: &Handle)
+                            -> Box<dyn Future<Item=(), Error=io::Error>> {
+    let checks = pool.proxies().into_iter().enumerate().map(|(index, proxy)| {
+        let pool = pool.clone();
+        let probe = probe.clone();
+        let check = proxy.connect(handle)
+            .and_then(move |stream| proxy.handshake(stream, probe))
+            .and_then(|(_stream,reply)| {
+                if reply.reply_code() == ReplyCode::Succeeded {
+                    Ok(())
+                }
+                else {
+                    Err(Error::new(ErrorKind::Other, "Probe request failed"))
+                }
+            });
+        let timer = Timeout::new(timeout, handle);
+        future::result(timer)
+            .and_then(move |timer| check.select2(timer).then(|res| match res {
+                Ok(Either::A(_)) => Ok(true),
+                _ => Ok(false)
+            }))
+            .map(move |healthy| {
+                if healthy {
+                    pool.report_success(index);
+                }
+                else {
+                    pool.report_failure(index);
+                }
+            })
+    }).collect::<Vec<_>>();
+    Box::new(future::join_all(checks).map(|_| ()))
+}
+
+// Run check_upstreams in the given interval. The future never completes.
+pub fn health_check(pool: Arc<UpstreamPool>, probe: SocksRequestResponse,
+                    interval: Duration, timeout: Duration, handle: &Handle)
+                            -> Box<dyn Future<Item=(), Error=io::Error>> {
+    let handle = handle.clone();
+    Box::new(future::result(Interval::new(interval, &handle))
+        .and_then(move |ticks| {
+            ticks.for_each(move |_| {
+                check_upstreams(pool.clone(), probe.clone(), timeout, &handle)
+            })
+        }))
+}
@@ -0,0 +1,18 @@
+#[allow(dead_code)]
+// as per SOCKS4 protocol and SOCKS4A extension
+pub const VERSION: u8 = 4;
+// Version field of a reply
+pub const REPLY_VERSION: u8 = 0;
+
+pub const CMD_CONNECT: u8 = 1;
+pub const CMD_BIND: u8 = 2;
+
+pub const REP_GRANTED: u8 = 90;
+pub const REP_REJECTED: u8 = 91;
+pub const REP_IDENTD_UNREACHABLE: u8 = 92;
+pub const REP_IDENTD_MISMATCH: u8 = 93;
+
+// VN, CD, DSTPORT and DSTIP
+pub const REQUEST_SIZE: usize = 8;
+// Longest accepted USERID or SOCKS4A hostname without terminating NUL
+pub const MAX_FIELD_SIZE: usize = 255;
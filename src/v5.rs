@@ -7,6 +7,11 @@ pub const METH_GSSAPI: u8 = 1;
 pub const METH_USER_PASS: u8 = 2;
 pub const METH_NO_ACCEPTABLE_METHOD: u8 = 255;
 
+// as per RFC 1929
+pub const USER_PASS_VERSION: u8 = 1;
+pub const USER_PASS_SUCCEEDED: u8 = 0;
+pub const USER_PASS_FAILURE: u8 = 1;
+
 pub const CMD_CONNECT: u8 = 1;
 pub const CMD_BIND: u8 = 2;
 pub const CMD_UDP_ASSOCIATE: u8 = 3;
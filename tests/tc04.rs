@@ -1,3 +1,4 @@
+#![allow(clippy::useless_conversion, clippy::assertions_on_constants)]
 extern crate futures;
 extern crate tokio_io;
 extern crate tokio_core;
@@ -0,0 +1,149 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{socks_dial, socks_handshake, Acl, Action, Cidr, SocksRequestResponse,
+                     StaticResolver};
+use futures::{Future,Stream};
+use futures::future::Either;
+use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::io::{read_exact, write_all};
+
+const RULES: &str = "
+# block private ranges and internal domains
+deny  to 10.0.0.0/8
+deny  domain *.internal
+allow port 80,443 cmd connect
+default deny
+";
+
+#[test]
+fn test_acl_rules() {
+    let acl: Acl = RULES.parse().unwrap();
+    let public = SocksRequestResponse::new(vec![5, 1, 0, 1, 93, 184, 216, 34, 0, 80]);
+    let private = SocksRequestResponse::new(vec![5, 1, 0, 1, 10, 1, 2, 3, 0, 80]);
+    let internal = SocksRequestResponse::new(b"\x05\x01\x00\x03\x0aa.internal\x00\x50".to_vec());
+    let other_port = SocksRequestResponse::new(vec![5, 1, 0, 1, 93, 184, 216, 34, 0, 22]);
+    assert_eq!(acl.check(None, &public, None), Action::Allow);
+    assert_eq!(acl.check(None, &private, None), Action::Deny);
+    assert_eq!(acl.check(None, &internal, None), Action::Deny);
+    assert_eq!(acl.check(None, &other_port, None), Action::Deny);
+    assert!("allow to 10.0.0.0/33".parse::<Acl>().is_err());
+
+    // IPv4-mapped IPv6 addresses are compared as IPv4
+    let mapped = SocksRequestResponse::request(socksv5_future::Command::Connect,
+                                               &"[::ffff:10.0.0.1]:80".parse().unwrap());
+    assert_eq!(acl.check(None, &mapped, None), Action::Deny);
+    let cidr: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+    assert!(cidr.contains(&"10.9.8.7".parse().unwrap()));
+
+    // Names are checked again with their resolved addresses
+    let name = SocksRequestResponse::new(b"\x05\x01\x00\x03\x09a.example\x00\x50".to_vec());
+    assert_eq!(acl.check(None, &name, None), Action::Allow);
+    assert_eq!(acl.check_resolved(None, &name, "10.1.2.3".parse().unwrap(), None), Action::Deny);
+    assert_eq!(acl.check_resolved(None, &name, "::ffff:10.1.2.3".parse().unwrap(), None),
+               Action::Deny);
+    assert_eq!(acl.check_resolved(None, &name, "93.184.216.34".parse().unwrap(), None),
+               Action::Allow);
+    assert_eq!(acl.check_resolved(None, &internal, "93.184.216.34".parse().unwrap(), None),
+               Action::Deny);
+    assert!("permit to 10.0.0.0/8".parse::<Acl>().is_err());
+}
+
+#[test]
+fn test_acl_denied_request() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64005".parse().unwrap();
+    let handle = lp.handle();
+    let handle2= handle.clone();
+    let acl = Arc::new(RULES.parse::<Acl>().unwrap());
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(
+            socks_handshake(stream)
+                    .with_acl(acl.clone())
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.clone().spawn(server);
+
+    let test_conn = TcpStream::connect(&addr, &handle)
+        .and_then(|stream| {
+            write_all(stream,[5u8,1u8,0u8])
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;2])
+        })
+        .and_then(|(stream,_buf)| {
+            write_all(stream,[5u8,1,0,1,10,1,2,3,0,80])
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;10])
+        });
+    let timeout = tokio_core::reactor::Timeout::new(
+                    Duration::from_millis(1000), &handle).unwrap();
+
+    let timed_testcase = test_conn.select2(timeout).then(|res| match res {
+            Ok(Either::A((got, _timeout))) => Ok(got),
+            Ok(Either::B((_timeout_error, _get))) => {
+                Err(Error::new(ErrorKind::Other, "Timeout"))
+            }
+            Err(Either::A((get_error, _timeout))) => Err(get_error),
+            Err(Either::B((timeout_error, _get))) => Err(timeout_error),
+        });
+
+    let (_stream,buf) = lp.run(timed_testcase).unwrap();
+    assert_eq!(buf, [5u8,2,0,1,0,0,0,0,0,0]);
+}
+
+#[test]
+fn test_acl_resolved_address() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64087".parse().unwrap();
+    let handle = lp.handle();
+    let handle2= handle.clone();
+    let acl = Arc::new(RULES.parse::<Acl>().unwrap());
+    let resolver = Arc::new(StaticResolver::new(None)
+                                .insert("private.example", "10.1.2.3".parse().unwrap()));
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, peer)| {
+        let handle3 = handle2.clone();
+        let acl2 = acl.clone();
+        let resolver = resolver.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .with_acl(acl.clone())
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, resolver)
+                            .with_acl(acl2, Some(peer.ip()))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.clone().spawn(server);
+
+    // The name is allowed, its address is not
+    let test_conn = TcpStream::connect(&addr, &handle)
+        .and_then(|stream| {
+            write_all(stream,[5u8,1u8,0u8])
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;2])
+        })
+        .and_then(|(stream,_buf)| {
+            write_all(stream,b"\x05\x01\x00\x03\x0fprivate.example\x00\x50")
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;10])
+        });
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(buf, [5u8,2,0,1,0,0,0,0,0,0]);
+}
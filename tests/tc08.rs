@@ -0,0 +1,115 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{happy_eyeballs, interleave, socks_connect_host, socks_dial, socks_handshake,
+                     ClientConfig, StaticResolver};
+use futures::{Future,Stream};
+use futures::future::Either;
+use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener, TcpStream};
+
+#[test]
+fn test_interleave_address_families() {
+    let addrs: Vec<SocketAddr> = vec!["[::1]:1".parse().unwrap(),
+                                      "[::2]:1".parse().unwrap(),
+                                      "[::3]:1".parse().unwrap(),
+                                      "10.0.0.1:1".parse().unwrap(),
+                                      "10.0.0.2:1".parse().unwrap()];
+    let sorted = interleave(addrs.clone());
+    assert_eq!(sorted, vec![addrs[0], addrs[3], addrs[1], addrs[4], addrs[2]]);
+
+    let mut addrs = addrs;
+    addrs.reverse();
+    let sorted = interleave(addrs.clone());
+    assert_eq!(sorted, vec![addrs[0], addrs[2], addrs[1], addrs[3], addrs[4]]);
+}
+
+#[test]
+fn test_happy_eyeballs_race() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64008".parse().unwrap();
+    let handle = lp.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let server = listener.incoming().for_each(|_| Ok(())).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    // The first address is not reachable (TEST-NET-1) or does not answer.
+    let addrs = vec!["192.0.2.1:9".parse().unwrap(), addr];
+    let test_conn = happy_eyeballs(addrs, &handle)
+                        .with_attempt_delay(Duration::from_millis(50));
+    let timeout = tokio_core::reactor::Timeout::new(
+                    Duration::from_millis(1000), &handle).unwrap();
+
+    let timed_testcase = test_conn.select2(timeout).then(|res| match res {
+            Ok(Either::A((got, _timeout))) => Ok(got),
+            Ok(Either::B((_timeout_error, _get))) => {
+                Err(Error::new(ErrorKind::Other, "Timeout"))
+            }
+            Err(Either::A((get_error, _timeout))) => Err(get_error),
+            Err(Either::B((timeout_error, _get))) => Err(timeout_error),
+        });
+
+    let (stream,winner) = lp.run(timed_testcase).unwrap();
+    assert_eq!(winner, addr);
+    assert_eq!(stream.peer_addr().unwrap(), addr);
+}
+
+#[test]
+fn test_happy_eyeballs_all_refused() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let addrs = vec!["127.0.0.1:64009".parse().unwrap(),
+                     "127.0.0.1:64010".parse().unwrap()];
+    let res = lp.run(happy_eyeballs(addrs, &handle));
+    match res {
+        Ok(_) => panic!("connection should have failed"),
+        Err(error) => assert_eq!(error.kind(), ErrorKind::ConnectionRefused)
+    }
+}
+
+#[test]
+fn test_dial_reports_winner() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64102".parse().unwrap();
+    let dest: SocketAddr = "127.0.0.1:64103".parse().unwrap();
+    let handle = lp.handle();
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&dest, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(|_| Ok(())).then( |_| { Ok(())}));
+
+    // race.test resolves to an unreachable address (TEST-NET-1) first
+    let resolver = Arc::new(StaticResolver::new(None)
+                                .insert("race.test", "192.0.2.1".parse().unwrap())
+                                .insert("race.test", "127.0.0.1".parse().unwrap()));
+    let listener = TcpListener::bind(&proxy, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        let resolver = resolver.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, resolver)
+                            .with_attempt_delay(Duration::from_millis(50))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| {
+            socks_connect_host(stream, "race.test", 64103, &ClientConfig::default())
+        });
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+
+    // BND.ADDR is the address, which has won the race
+    assert_eq!(reply.bytes[1], 0);
+    assert_eq!(reply.socketaddr(), Some(dest));
+}
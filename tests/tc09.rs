@@ -0,0 +1,91 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{socks_handshake, socks_dial, socks_connect_host,
+                     ClientConfig, ResolveFuture, Resolver, StaticResolver, SystemResolver};
+use futures::{future, Future, Stream};
+use futures::future::Either;
+use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener,TcpStream};
+
+// Resolves every name to no address at all
+struct EmptyResolver;
+
+impl Resolver for EmptyResolver {
+    fn resolve(&self, _host: &str, _port: u16) -> ResolveFuture {
+        Box::new(future::ok(vec!()))
+    }
+}
+
+#[test]
+fn test_client_request_dns_mode() {
+    let mut lp = Core::new().unwrap();
+    let remote = ClientConfig::default();
+    let request = lp.run(remote.request("echo.test", 80)).unwrap();
+    assert_eq!(request.bytes, b"\x05\x01\x00\x03\x09echo.test\x00\x50".to_vec());
+
+    let request = lp.run(remote.request("10.1.2.3", 80)).unwrap();
+    assert_eq!(request.bytes, vec![5u8,1,0,1,10,1,2,3,0,80]);
+
+    let request = lp.run(remote.request("[::1]", 80)).unwrap();
+    assert_eq!(request.bytes[3], 4);
+    assert_eq!(request.bytes.len(), 22);
+
+    let resolver = StaticResolver::new(None).insert("echo.test", "127.0.0.1".parse().unwrap());
+    let local = ClientConfig::local_dns(Arc::new(resolver));
+    let request = lp.run(local.request("echo.test", 80)).unwrap();
+    assert_eq!(request.bytes, vec![5u8,1,0,1,127,0,0,1,0,80]);
+    assert!(lp.run(local.request("unknown.test", 80)).is_err());
+
+    let empty = ClientConfig::local_dns(Arc::new(EmptyResolver));
+    let error = lp.run(empty.request("echo.test", 80)).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn test_client_remote_dns() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64011".parse().unwrap();
+    let handle = lp.handle();
+    let handle2= handle.clone();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        // The proxy must receive the hostname
+                        assert_eq!(request.hostname(), Some(&b"localhost"[..]));
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.clone().spawn(server);
+
+    let test_conn = TcpStream::connect(&addr, &handle)
+        .and_then(|stream| {
+            socks_connect_host(stream, "localhost", 64011, &ClientConfig::default())
+        });
+    let timeout = tokio_core::reactor::Timeout::new(
+                    Duration::from_millis(1000), &handle).unwrap();
+
+    let timed_testcase = test_conn.select2(timeout).then(|res| match res {
+            Ok(Either::A((got, _timeout))) => Ok(got),
+            Ok(Either::B((_timeout_error, _get))) => {
+                Err(Error::new(ErrorKind::Other, "Timeout"))
+            }
+            Err(Either::A((get_error, _timeout))) => Err(get_error),
+            Err(Either::B((timeout_error, _get))) => Err(timeout_error),
+        });
+
+    let (_stream,reply) = lp.run(timed_testcase).unwrap();
+    assert_eq!(reply.bytes[1], 0);
+}
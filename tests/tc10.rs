@@ -0,0 +1,114 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use socksv5_future::{socks_handshake, socks_dial, Command, Credentials, Proxy, ProxyAddr,
+                     ProxyChain, ReplyCode, SocksRequestResponse, SystemResolver};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::TcpListener;
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn start_proxy(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Minimal proxy, which requires username/password and answers every request
+// with success, then echoes
+fn start_auth_proxy(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(
+            read_exact(stream,[0u8;4])
+                .and_then(|(stream,methods)| {
+                    assert_eq!(methods, [5u8,2,0,2]);
+                    write_all(stream,[5u8,2])
+                })
+                .and_then(|(stream,_buf)| read_exact(stream,[0u8;11]))
+                .and_then(|(stream,auth)| {
+                    assert_eq!(&auth, b"\x01\x04user\x04pass");
+                    write_all(stream,[1u8,0])
+                })
+                .and_then(|(stream,_buf)| read_exact(stream,[0u8;10]))
+                .and_then(|(stream,_request)| write_all(stream,[5u8,0,0,1,0,0,0,0,0,0]))
+                .and_then(|(stream,_buf)| {
+                    let (rd,wr) = stream.split();
+                    copy(rd,wr)
+                })
+                .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_proxy_chain() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let first: SocketAddr = "127.0.0.1:64013".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:64014".parse().unwrap();
+    let last: SocketAddr = "127.0.0.1:64015".parse().unwrap();
+    start_proxy(&handle, first);
+    start_proxy(&handle, second);
+    start_auth_proxy(&handle, last);
+
+    let chain = ProxyChain::new(vec![
+                    Proxy::new(first),
+                    Proxy::new(second),
+                    Proxy::new(last).with_credentials(Credentials::new("user","pass"))]);
+    let target: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let request = SocksRequestResponse::request(Command::Connect, &target);
+    let test_conn = chain.connect(request, &handle)
+        .map_err(From::from)
+        .and_then(|(stream,replies)| {
+            assert_eq!(replies.len(), 3);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn test_proxy_chain_failing_hop() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let first: SocketAddr = "127.0.0.1:64016".parse().unwrap();
+    let closed: SocketAddr = "127.0.0.1:64017".parse().unwrap();
+    start_proxy(&handle, first);
+
+    let chain = ProxyChain::new(vec![Proxy::new(first), Proxy::new(closed)]);
+    let request = SocksRequestResponse::request(Command::Connect, &first);
+    match lp.run(chain.connect(request, &handle)) {
+        Ok(_) => panic!("chain should have failed"),
+        Err(error) => {
+            assert_eq!(error.hop, 0);
+            assert_eq!(error.proxy, ProxyAddr::Tcp(first));
+            assert_eq!(error.reply, Some(ReplyCode::ConnectionRefused));
+        }
+    }
+}
@@ -0,0 +1,105 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use socksv5_future::{socks_handshake, socks_dial, socks_connect_handshake, Command, Forwarder,
+                     SocksRequestResponse, SystemResolver};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn start_servers(handle: &Handle, upstream: SocketAddr, echo: SocketAddr, forwarder: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&upstream, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let fwd = Forwarder::new(upstream);
+    let listener = TcpListener::bind(&forwarder, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_forwarder() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let upstream: SocketAddr = "127.0.0.1:64018".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64019".parse().unwrap();
+    let forwarder: SocketAddr = "127.0.0.1:64020".parse().unwrap();
+    let closed: SocketAddr = "127.0.0.1:64021".parse().unwrap();
+    start_servers(&handle, upstream, echo, forwarder);
+
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request))
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.bytes[1], 0);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // The upstream reply code is passed to the client
+    let request = SocksRequestResponse::request(Command::Connect, &closed);
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request));
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+    assert_eq!(reply.bytes[1], 5);
+}
+
+#[test]
+fn test_forwarder_without_upstream() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let forwarder: SocketAddr = "127.0.0.1:64022".parse().unwrap();
+    let upstream: SocketAddr = "127.0.0.1:64023".parse().unwrap();
+    let handle2 = handle.clone();
+    let fwd = Forwarder::new(upstream);
+    let listener = TcpListener::bind(&forwarder, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let request = SocksRequestResponse::request(Command::Connect, &upstream);
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request));
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+    assert_eq!(reply.bytes[1], 1);
+}
@@ -0,0 +1,167 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{socks_handshake, socks_dial, socks_connect_handshake, check_upstreams,
+                     Command, Forwarder, Policy, Proxy, ProxyKind, SocksRequestResponse,
+                     SystemResolver, UpstreamPool};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn pool(policy: Policy) -> Arc<UpstreamPool> {
+    let proxies = (1..4).map(|i| Proxy::new(SocketAddr::from(([127,0,0,i],1080)))).collect();
+    Arc::new(UpstreamPool::new(proxies, policy).with_max_failures(2))
+}
+
+fn request(port: u16) -> SocksRequestResponse {
+    SocksRequestResponse::request(Command::Connect, &SocketAddr::from(([10,0,0,1],port)))
+}
+
+#[test]
+fn test_upstream_policies() {
+    let rr = pool(Policy::RoundRobin);
+    let picks: Vec<usize> = (0..4).map(|_| UpstreamPool::select(&rr, &request(80), &[]).unwrap().index)
+                                  .collect();
+    assert_eq!(picks, vec![0,1,2,0]);
+
+    let lc = pool(Policy::LeastConnections);
+    let first = UpstreamPool::select(&lc, &request(80), &[]).unwrap();
+    let second = UpstreamPool::select(&lc, &request(80), &[]).unwrap();
+    assert_eq!((first.index,second.index), (0,1));
+    drop(first);
+    assert_eq!(UpstreamPool::select(&lc, &request(80), &[]).unwrap().index, 0);
+
+    let ch = pool(Policy::ConsistentHash);
+    let picks: Vec<usize> = (0..20).map(|p| UpstreamPool::select(&ch, &request(p), &[]).unwrap().index)
+                                   .collect();
+    let again: Vec<usize> = (0..20).map(|p| UpstreamPool::select(&ch, &request(p), &[]).unwrap().index)
+                                   .collect();
+    assert_eq!(picks, again);
+    assert!(picks.iter().any(|&i| i != picks[0]));
+
+    let fo = pool(Policy::Failover);
+    assert_eq!(UpstreamPool::select(&fo, &request(80), &[]).unwrap().index, 0);
+    fo.report_failure(0);
+    assert!(fo.is_up(0));
+    fo.report_failure(0);
+    assert!(!fo.is_up(0));
+    assert_eq!(UpstreamPool::select(&fo, &request(80), &[]).unwrap().index, 1);
+    assert_eq!(UpstreamPool::select(&fo, &request(80), &[1]).unwrap().index, 2);
+    fo.report_success(0);
+    assert_eq!(UpstreamPool::select(&fo, &request(80), &[]).unwrap().index, 0);
+}
+
+fn start_servers(handle: &Handle, upstream: SocketAddr, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&upstream, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_forwarder_failover_and_health_check() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let upstream: SocketAddr = "127.0.0.1:64024".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64025".parse().unwrap();
+    let dead: SocketAddr = "127.0.0.1:64026".parse().unwrap();
+    let forwarder: SocketAddr = "127.0.0.1:64027".parse().unwrap();
+    start_servers(&handle, upstream, echo);
+
+    let pool = Arc::new(UpstreamPool::new(vec![Proxy::new(dead), Proxy::new(upstream)],
+                                          Policy::Failover)
+                            .with_max_failures(1));
+    let fwd = Forwarder::with_pool(pool.clone());
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&forwarder, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request))
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.bytes[1], 0);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+    assert!(!pool.is_up(0));
+    assert!(pool.is_up(1));
+
+    // Active check marks the dead upstream down and keeps the other one up
+    pool.report_success(0);
+    let probe = SocksRequestResponse::request(Command::Connect, &echo);
+    lp.run(check_upstreams(pool.clone(), probe, Duration::from_millis(500), &handle)).unwrap();
+    assert!(!pool.is_up(0));
+    assert!(pool.is_up(1));
+}
+
+#[test]
+fn test_unsupported_command() {
+    let http = Proxy::new("127.0.0.1:64092".parse().unwrap()).with_kind(ProxyKind::Http);
+    let socks = Proxy::new(SocketAddr::from(([127,0,0,2],1080)));
+    let bind = SocksRequestResponse::request(Command::Bind, &SocketAddr::from(([10,0,0,1],80)));
+    let mixed = Arc::new(UpstreamPool::new(vec![http.clone(), socks], Policy::Failover));
+    assert_eq!(UpstreamPool::select(&mixed, &bind, &[]).unwrap().index, 1);
+    assert!(UpstreamPool::select(&mixed, &bind, &[1]).is_none());
+    assert_eq!(UpstreamPool::select(&mixed, &request(80), &[]).unwrap().index, 0);
+
+    // BIND toward HTTP upstreams only is rejected without touching their health
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let forwarder: SocketAddr = "127.0.0.1:64093".parse().unwrap();
+    let pool = Arc::new(UpstreamPool::new(vec![http], Policy::Failover).with_max_failures(1));
+    let fwd = Forwarder::with_pool(pool.clone());
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&forwarder, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())}));
+    for _ in 0..3 {
+        let test_conn = TcpStream::connect(&forwarder, &handle)
+            .and_then(|stream| write_all(stream, [5u8,1,0]))
+            .and_then(|(stream,_buf)| read_exact(stream, [0u8;2]))
+            .and_then(|(stream,_buf)| write_all(stream, [5u8,2,0,1,10,0,0,1,0,80]))
+            .and_then(|(stream,_buf)| read_exact(stream, [0u8;10]))
+            .map(|(_stream,buf)| buf);
+        assert_eq!(lp.run(test_conn).unwrap()[..2], [5u8,7]);
+    }
+    assert!(pool.is_up(0));
+}
@@ -0,0 +1,130 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use socksv5_future::{socks_handshake, socks_dial, socks_connect_handshake, Command, Outbound,
+                     ReplyCode, Router, RoutingTable, SocksRequestResponse, SystemResolver};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+const TABLE: &str = "
+proxy up 127.0.0.1:64030
+direct domain .corp.example
+via up domain localhost
+reject not_allowed port 25
+reject 4 to 10.0.0.0/8
+";
+
+#[test]
+fn test_routing_table() {
+    let table: RoutingTable = TABLE.parse().unwrap();
+    let domain = |name: &str, port: u16| {
+        SocksRequestResponse::request_hostname(Command::Connect, name, port).unwrap()
+    };
+    let ip = |addr: &str| {
+        SocksRequestResponse::request(Command::Connect, &addr.parse().unwrap())
+    };
+    assert_eq!(table.route(None, &domain("git.corp.example", 443), None), &Outbound::Direct);
+    assert_eq!(table.route(None, &domain("localhost", 443), None),
+               &Outbound::Upstream("up".to_string()));
+    assert_eq!(table.route(None, &ip("192.0.2.1:25"), None),
+               &Outbound::Reject(ReplyCode::NotAllowed));
+    assert_eq!(table.route(None, &ip("10.1.1.1:80"), None),
+               &Outbound::Reject(ReplyCode::HostUnreachable));
+    assert_eq!(table.route(None, &ip("192.0.2.1:80"), None), &Outbound::Direct);
+    // Hostnames are not resolved for routing, CIDR conditions don't match them
+    assert_eq!(table.route(None, &domain("intranet.example", 80), None), &Outbound::Direct);
+    assert!(table.upstream("up").is_some());
+
+    assert!("via unknown".parse::<RoutingTable>().is_err());
+    assert!("reject maybe".parse::<RoutingTable>().is_err());
+    assert!("default via up port 80\nproxy up 127.0.0.1:1".parse::<RoutingTable>().is_err());
+}
+
+fn start_servers(handle: &Handle, upstream: SocketAddr, echo: SocketAddr,
+                 counter: Arc<AtomicUsize>) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&upstream, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn ping(lp: &mut Core, router: SocketAddr, request: SocksRequestResponse) -> (u8,Vec<u8>) {
+    let handle = lp.handle();
+    let test_conn = TcpStream::connect(&router, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request))
+        .and_then(|(stream,reply)| {
+            let rep = reply.bytes[1];
+            if rep != 0 {
+                return futures::future::Either::B(futures::future::ok((rep,vec!())));
+            }
+            futures::future::Either::A(write_all(stream,b"ping")
+                .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]))
+                .map(move |(_stream,buf)| (rep,buf.to_vec())))
+        });
+    lp.run(test_conn).unwrap()
+}
+
+#[test]
+fn test_router() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let router: SocketAddr = "127.0.0.1:64028".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64029".parse().unwrap();
+    let upstream: SocketAddr = "127.0.0.1:64030".parse().unwrap();
+    let counter = Arc::new(AtomicUsize::new(0));
+    start_servers(&handle, upstream, echo, counter.clone());
+
+    let table = Arc::new(TABLE.parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&router, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let direct = SocksRequestResponse::request(Command::Connect, &echo);
+    assert_eq!(ping(&mut lp, router, direct), (0,b"ping".to_vec()));
+    assert_eq!(counter.load(Ordering::SeqCst), 0);
+
+    let via = SocksRequestResponse::request_hostname(Command::Connect, "localhost", 64029).unwrap();
+    assert_eq!(ping(&mut lp, router, via), (0,b"ping".to_vec()));
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+    let reject = SocksRequestResponse::request(Command::Connect, &"127.0.0.1:25".parse().unwrap());
+    assert_eq!(ping(&mut lp, router, reject), (2,vec!()));
+}
@@ -0,0 +1,175 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{socks_handshake, socks_dial, Acl, Resolver, StaticResolver, UserList};
+use futures::{Future,Stream};
+use futures::future::Either;
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn start_servers(handle: &Handle, proxy: SocketAddr, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let resolver: Arc<dyn Resolver> = Arc::new(
+        StaticResolver::new(None).insert("echo.test", "127.0.0.1".parse().unwrap()));
+    let acl: Arc<Acl> = Arc::new("deny user mallory".parse().unwrap());
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        let resolver = resolver.clone();
+        handle2.spawn(
+            socks_handshake(stream).with_socks4().with_acl(acl.clone())
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, resolver)
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Send a socks4 request and deliver the reply, followed by the echo of
+// "ping" if the request has been granted.
+fn run_request(lp: &mut Core, proxy: SocketAddr, request: Vec<u8>) -> Result<Vec<u8>,Error> {
+    let handle = lp.handle();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(move |stream| {
+            write_all(stream,request)
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,vec![0u8;8])
+        })
+        .and_then(|(stream,reply)| {
+            if reply[1] != 90 {
+                return Either::B(futures::future::ok(reply));
+            }
+            Either::A(write_all(stream,b"ping")
+                .and_then(|(stream,_buf)| {
+                    read_exact(stream,[0u8;4])
+                })
+                .map(move |(_stream,echo)| {
+                    let mut reply = reply;
+                    reply.extend_from_slice(&echo);
+                    reply
+                }))
+        });
+    let timeout = tokio_core::reactor::Timeout::new(
+                    Duration::from_millis(1000), &handle).unwrap();
+
+    let timed_testcase = test_conn.select2(timeout).then(|res| match res {
+            Ok(Either::A((got, _timeout))) => Ok(got),
+            Ok(Either::B((_timeout_error, _get))) => {
+                Err(Error::new(ErrorKind::Other, "Timeout"))
+            }
+            Err(Either::A((get_error, _timeout))) => Err(get_error),
+            Err(Either::B((timeout_error, _get))) => Err(timeout_error),
+        });
+    lp.run(timed_testcase)
+}
+
+#[test]
+fn test_socks4() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64031".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64032".parse().unwrap();
+    start_servers(&lp.handle(), proxy, echo);
+
+    // SOCKS4 CONNECT 127.0.0.1:64032 with USERID alice
+    let mut request = vec![4u8,1,0xfa,0x20,127,0,0,1];
+    request.extend_from_slice(b"alice\0");
+    let buf = run_request(&mut lp, proxy, request).unwrap();
+    assert_eq!(&buf[..2], &[0u8,90]);
+    assert_eq!(&buf[4..8], &[127u8,0,0,1]);
+    assert_eq!(&buf[8..], b"ping");
+
+    // SOCKS4A CONNECT echo.test:64032 with empty USERID
+    let mut request = vec![4u8,1,0xfa,0x20,0,0,0,1,0];
+    request.extend_from_slice(b"echo.test\0");
+    let buf = run_request(&mut lp, proxy, request).unwrap();
+    assert_eq!(&buf[..2], &[0u8,90]);
+    assert_eq!(&buf[8..], b"ping");
+
+    // SOCKS4A CONNECT unknown.test:64032
+    let mut request = vec![4u8,1,0xfa,0x20,0,0,0,1,0];
+    request.extend_from_slice(b"unknown.test\0");
+    let buf = run_request(&mut lp, proxy, request).unwrap();
+    assert_eq!(buf, [0u8,91,0,0,0,0,0,0]);
+
+    // USERID mallory is not verified, so it is not the user denied by the acl
+    let mut request = vec![4u8,1,0xfa,0x20,127,0,0,1];
+    request.extend_from_slice(b"mallory\0");
+    let buf = run_request(&mut lp, proxy, request).unwrap();
+    assert_eq!(&buf[..2], &[0u8,90]);
+
+    // BIND is not supported
+    let buf = run_request(&mut lp, proxy, b"\x04\x02\xfa\x20\x7f\x00\x00\x01\x00".to_vec()).unwrap();
+    assert_eq!(buf, [0u8,91,0,0,0,0,0,0]);
+}
+
+#[test]
+fn test_socks4_with_authenticator() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64086".parse().unwrap();
+    let handle = lp.handle();
+    let users: Arc<UserList> = Arc::new("alice secret".parse().unwrap());
+    let listener = TcpListener::bind(&proxy, &handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle.spawn(socks_handshake(stream).with_socks4().with_authenticator(users.clone())
+                        .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    lp.handle().spawn(server);
+
+    // SOCKS4 cannot authenticate, whatever the USERID is
+    let mut request = vec![4u8,1,0xfa,0x20,127,0,0,1];
+    request.extend_from_slice(b"alice\0");
+    let buf = run_request(&mut lp, proxy, request).unwrap();
+    assert_eq!(buf, [0u8,91,0,0,0,0,0,0]);
+}
+
+#[test]
+fn test_socks5_with_socks4_enabled() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64033".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64034".parse().unwrap();
+    start_servers(&lp.handle(), proxy, echo);
+
+    let handle = lp.handle();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| {
+            write_all(stream,[5u8,1u8,0u8])
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;2])
+        })
+        .and_then(|(stream,_buf)| {
+            write_all(stream,[5u8,1,0,1,127,0,0,1,0xfa,0x22])
+        })
+        .and_then(|(stream,_buf)| {
+            read_exact(stream,[0u8;10])
+        });
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+    assert_eq!(&reply[..4], &[5u8,0,0,1]);
+}
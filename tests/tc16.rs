@@ -0,0 +1,206 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use socksv5_future::{accept_any, http_connect_handshake, socks_dial, Command, Credentials,
+                     Forwarder, Policy, Proxy, ProxyChain, ProxyKind, ReplyCode, RoutingTable,
+                     SocksRequestResponse, SystemResolver, UpstreamPool};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read, read_exact, write_all};
+
+// Proxy for socks and HTTP clients on one port
+fn start_proxy(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            accept_any(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Minimal HTTP proxy, which requires user:pass and then echoes
+fn start_auth_http_proxy(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let expected = b"CONNECT 127.0.0.1:64040 HTTP/1.1\r\n\
+                         Host: 127.0.0.1:64040\r\n\
+                         Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        handle2.spawn(
+            read_exact(stream,vec![0u8;expected.len()])
+                .and_then(move |(stream,request)| {
+                    let response: &[u8] = if request[..] == expected[..] {
+                        b"HTTP/1.1 200 Connection established\r\n\r\n"
+                    }
+                    else {
+                        b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"
+                    };
+                    write_all(stream,response)
+                })
+                .and_then(|(stream,_buf)| {
+                    let (rd,wr) = stream.split();
+                    copy(rd,wr)
+                })
+                .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_echo(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_http_connect() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let auth_proxy: SocketAddr = "127.0.0.1:64037".parse().unwrap();
+    let first: SocketAddr = "127.0.0.1:64038".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:64039".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64040".parse().unwrap();
+    let forwarder: SocketAddr = "127.0.0.1:64041".parse().unwrap();
+    start_auth_http_proxy(&handle, auth_proxy);
+    start_proxy(&handle, first);
+    start_proxy(&handle, second);
+    start_echo(&handle, echo);
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+
+    // Basic authentication
+    let credentials = Some(Credentials::new("user","pass"));
+    let test_conn = TcpStream::connect(&auth_proxy, &handle)
+        .and_then(|stream| http_connect_handshake(stream, request.clone(), credentials))
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.reply_code(), ReplyCode::Succeeded);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let credentials = Some(Credentials::new("user","pas5"));
+    let test_conn = TcpStream::connect(&auth_proxy, &handle)
+        .and_then(|stream| http_connect_handshake(stream, request.clone(), credentials));
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+    assert_eq!(reply.reply_code(), ReplyCode::NotAllowed);
+
+    // socks5 hop followed by HTTP hop
+    let chain = ProxyChain::new(vec![
+                    Proxy::new(first),
+                    Proxy::new(second).with_kind(ProxyKind::Http)]);
+    let test_conn = chain.connect(request.clone(), &handle)
+        .map_err(|e| e.into())
+        .and_then(|(stream,replies)| {
+            assert_eq!(replies.len(), 2);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // Forwarder with an HTTP upstream from a routing table
+    let table: RoutingTable = "proxy web http://127.0.0.1:64039\ndefault via web".parse().unwrap();
+    let fwd = Forwarder::with_pool(table.upstream("web").unwrap());
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&forwarder, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then(|_| Ok(())));
+        Ok(())
+    }).then(|_| Ok(())));
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| write_all(stream,[5u8,1,0]))
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;2]))
+        .and_then(move |(stream,_buf)| write_all(stream,request.bytes))
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;10]))
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply[1], 0);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[test]
+fn test_http_header_injection() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let upstream: SocketAddr = "127.0.0.1:64104".parse().unwrap();
+    let forwarder: SocketAddr = "127.0.0.1:64105".parse().unwrap();
+
+    // HTTP upstream, which records the first data it receives and closes
+    let received = Arc::new(Mutex::new(Vec::<u8>::new()));
+    let received2 = received.clone();
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&upstream, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+        let received = received2.clone();
+        handle2.spawn(read(stream, vec![0u8;1024])
+            .map(move |(_stream,buf,n)| received.lock().unwrap().extend(&buf[..n]))
+            .then(|_| Ok(())));
+        Ok(())
+    }).then(|_| Ok(())));
+
+    let proxy = Proxy::new(upstream).with_kind(ProxyKind::Http)
+                    .with_credentials(Credentials::new("user","pass"));
+    let request = SocksRequestResponse::request_hostname(Command::Connect,
+                                                         "evil.test\r\nX-Evil: 1", 80).unwrap();
+
+    // ProxyChain refuses the hostname
+    let res = lp.run(ProxyChain::new(vec![proxy.clone()]).connect(request.clone(), &handle));
+    match res {
+        Ok(_) => panic!("hostname should have been rejected"),
+        Err(error) => assert_eq!(error.error.kind(), ErrorKind::InvalidInput)
+    }
+
+    // Forwarder answers with a failure
+    let pool = Arc::new(UpstreamPool::new(vec![proxy], Policy::Failover));
+    let fwd = Forwarder::with_pool(pool);
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&forwarder, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(fwd.serve(stream, &handle2).then(|_| Ok(())));
+        Ok(())
+    }).then(|_| Ok(())));
+    let test_conn = TcpStream::connect(&forwarder, &handle)
+        .and_then(|stream| write_all(stream,[5u8,1,0]))
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;2]))
+        .and_then(move |(stream,_buf)| write_all(stream,request.bytes))
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;10]));
+    let (_stream,reply) = lp.run(test_conn).unwrap();
+    assert_eq!(reply[..2], [5u8,1]);
+
+    lp.run(Timeout::new(Duration::from_millis(100), &handle).unwrap()).unwrap();
+    let received = received.lock().unwrap();
+    assert!(!received.windows(6).any(|w| w == b"X-Evil"));
+    assert!(!received.windows(7).any(|w| w == b"CONNECT"));
+}
@@ -0,0 +1,83 @@
+#![cfg(feature = "tls")]
+
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use socksv5_future::{socks_dial, socks_tls_connect_handshake, socks_tls_handshake, tls_acceptor,
+                     tls_connector, Command, ReplyCode, SocksRequestResponse, SystemResolver};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+const CERT: &[u8] = include_bytes!("tls/cert.pem");
+const KEY: &[u8] = include_bytes!("tls/key.pem");
+
+fn start_servers(handle: &Handle, proxy: SocketAddr, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let acceptor = tls_acceptor(CERT, KEY).unwrap();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_tls_handshake(stream, &acceptor)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_socks_over_tls() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let proxy: SocketAddr = "127.0.0.1:64042".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64043".parse().unwrap();
+    start_servers(&handle, proxy, echo);
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+
+    let connector = tls_connector(Some(CERT)).unwrap();
+    let req = request.clone();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(move |stream| {
+            socks_tls_connect_handshake(stream, "localhost", &connector, req, None)
+        })
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.reply_code(), ReplyCode::Succeeded);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // The self-signed certificate is not trusted without root
+    let connector = tls_connector(None).unwrap();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(move |stream| {
+            socks_tls_connect_handshake(stream, "localhost", &connector, request, None)
+        });
+    assert!(lp.run(test_conn).is_err());
+}
@@ -0,0 +1,171 @@
+#![cfg(unix)]
+
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use socksv5_future::{socks_connect_handshake, socks_connect_unix, socks_dial, socks_handshake_over,
+                     Command, Proxy, ProxyChain, ReplyCode, Router, RoutingTable,
+                     SocksRequestResponse, SystemResolver, UnixSocksListener};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn socket_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("socksv5_future_{}_{}.sock", name, process::id()))
+}
+
+#[test]
+fn test_unix_socket_proxy() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64044".parse().unwrap();
+    let path = socket_path("proxy");
+
+    let listener = UnixSocksListener::bind(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    listener.set_permissions(0o660).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+    serve_unix(&handle, listener);
+
+    let handle2 = handle.clone();
+    let echo_listener = TcpListener::bind(&echo, &handle).unwrap();
+    handle.spawn(echo_listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())}));
+
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let test_conn = socks_connect_unix(&path, request, None)
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.reply_code(), ReplyCode::Succeeded);
+            write_all(stream,b"ping")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // The socket is in use
+    assert!(UnixSocksListener::bind(&path).is_err());
+}
+
+// Socks server on the unix listener, which connects directly
+fn serve_unix(handle: &Handle, listener: UnixSocksListener) {
+    let handle2 = handle.clone();
+    let server = listener.for_each(move |stream| {
+        let handle3 = handle2.clone();
+        handle2.spawn(
+            socks_handshake_over(stream)
+                    .and_then(move |(stream,request)| {
+                        socks_dial(stream, &request, &handle3, Arc::new(SystemResolver))
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_unix_socket_file_handling() {
+    // A stale socket file is replaced and removed on drop
+    let path = socket_path("stale");
+    drop(net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let listener = UnixSocksListener::bind(&path).unwrap();
+    assert!(path.exists());
+    drop(listener);
+    assert!(!path.exists());
+
+    // Other files are not touched
+    let path = socket_path("file");
+    fs::write(&path, b"data").unwrap();
+    assert!(UnixSocksListener::bind(&path).is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    fs::remove_file(&path).unwrap();
+
+    // The mode is set before the socket appears at the path
+    let path = socket_path("mode");
+    let listener = UnixSocksListener::bind_with_mode(&path, 0o660).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o660);
+    let prefix = format!(".{}.", path.file_name().unwrap().to_string_lossy());
+    let leftovers = fs::read_dir(env::temp_dir()).unwrap()
+                        .filter(|entry| {
+                            let name = entry.as_ref().unwrap().file_name();
+                            name.to_string_lossy().starts_with(&prefix)
+                        })
+                        .count();
+    assert_eq!(leftovers, 0);
+    drop(listener);
+}
+
+#[test]
+fn test_unix_socket_upstream() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let router_addr: SocketAddr = "127.0.0.1:64096".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64097".parse().unwrap();
+    let path = socket_path("upstream");
+    serve_unix(&handle, UnixSocksListener::bind(&path).unwrap());
+
+    let handle2 = handle.clone();
+    let echo_listener = TcpListener::bind(&echo, &handle).unwrap();
+    handle.spawn(echo_listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())}));
+
+    // Chain with the unix proxy as first hop
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let chain = ProxyChain::new(vec![Proxy::unix(&path)]);
+    let test_conn = chain.connect(request.clone(), &handle)
+        .map_err(std::io::Error::from)
+        .and_then(|(stream,_replies)| write_all(stream,b"ping"))
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // No proxy can connect to a socket path
+    let chain = ProxyChain::new(vec![Proxy::new(router_addr), Proxy::unix(&path)]);
+    let error = lp.run(chain.connect(request.clone(), &handle)).err().unwrap();
+    assert_eq!(error.hop, 1);
+    assert_eq!(error.error.kind(), std::io::ErrorKind::InvalidInput);
+
+    // Router with the unix proxy as upstream
+    let table: RoutingTable = format!("proxy u unix:{}\ndefault via u\n", path.display())
+                                .parse().unwrap();
+    let router = Router::new(Arc::new(table), Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&router_addr, &handle).unwrap();
+    handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(router.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())}));
+    let test_conn = TcpStream::connect(&router_addr, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request))
+        .and_then(|(stream,reply)| {
+            assert_eq!(reply.reply_code(), ReplyCode::Succeeded);
+            write_all(stream,b"pong")
+        })
+        .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]));
+    let (_stream,buf) = lp.run(test_conn).unwrap();
+    assert_eq!(&buf, b"pong");
+}
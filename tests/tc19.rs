@@ -0,0 +1,244 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate tracing;
+extern crate socksv5_future;
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use socksv5_future::{read_proxy_header, socks_dial, socks_handshake_over, Acl, ProxyHeader,
+                     ProxyVersion, Router, RoutingTable, SystemResolver, ACCESS_LOG_TARGET};
+use futures::{Future,Stream};
+use futures::future::Either;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+use tracing::{Event, Metadata};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+
+type Seen = Arc<Mutex<Vec<Option<ProxyHeader>>>>;
+
+fn start_servers(handle: &Handle, proxy: SocketAddr, backend: SocketAddr) -> Seen {
+    let handle2 = handle.clone();
+    let acl: Arc<Acl> = Arc::new("deny from 203.0.113.0/24".parse().unwrap());
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let handle3 = handle2.clone();
+        let acl = acl.clone();
+        handle2.spawn(
+            read_proxy_header(stream)
+                    .and_then(move |(stream,header)| {
+                        let source = header.map(|header| header.source);
+                        let handshake = socks_handshake_over(stream).with_acl(acl);
+                        match source {
+                            Some(source) => handshake.with_peer(source),
+                            None => handshake
+                        }.map(move |(stream,request)| (stream,request,source))
+                    })
+                    .and_then(move |(stream,request,source)| {
+                        let dial = socks_dial(stream, &request, &handle3, Arc::new(SystemResolver));
+                        match source {
+                            Some(source) => dial.with_proxy_header(ProxyVersion::V2, source),
+                            None => dial
+                        }
+                    })
+                    .and_then(|(client,target)| {
+                        let (client_rd,client_wr) = client.split();
+                        let (target_rd,target_wr) = target.split();
+                        copy(client_rd,target_wr).join(copy(target_rd,client_wr))
+                    })
+                    .then( |_| { Ok(())})
+        );
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    // Echo server, which records the received PROXY headers
+    let seen: Seen = Arc::new(Mutex::new(vec!()));
+    let seen2 = seen.clone();
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&backend, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let seen = seen2.clone();
+        handle2.spawn(read_proxy_header(stream)
+            .and_then(move |(stream,header)| {
+                seen.lock().unwrap().push(header);
+                let (rd,wr) = stream.split();
+                copy(rd,wr)
+            })
+            .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+    seen
+}
+
+// Send header, greeting and request at once. Deliver the reply and
+// the echo of "ping", if the request succeeded.
+fn run_request(lp: &mut Core, proxy: SocketAddr, header: &[u8], backend: SocketAddr)
+                                                            -> Result<Vec<u8>,Error> {
+    let handle = lp.handle();
+    let mut request = header.to_vec();
+    request.extend_from_slice(&[5u8,1,0, 5,1,0,1,127,0,0,1]);
+    request.extend_from_slice(&backend.port().to_be_bytes());
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(move |stream| write_all(stream,request))
+        .and_then(|(stream,_buf)| read_exact(stream,vec![0u8;12]))
+        .and_then(|(stream,reply)| {
+            if reply[3] != 0 {
+                return Either::B(futures::future::ok(reply));
+            }
+            Either::A(write_all(stream,b"ping")
+                .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]))
+                .map(move |(_stream,echo)| {
+                    let mut reply = reply;
+                    reply.extend_from_slice(&echo);
+                    reply
+                }))
+        });
+    let timeout = tokio_core::reactor::Timeout::new(
+                    Duration::from_millis(1000), &handle).unwrap();
+
+    let timed_testcase = test_conn.select2(timeout).then(|res| match res {
+            Ok(Either::A((got, _timeout))) => Ok(got),
+            Ok(Either::B((_timeout_error, _get))) => {
+                Err(Error::new(ErrorKind::Other, "Timeout"))
+            }
+            Err(Either::A((get_error, _timeout))) => Err(get_error),
+            Err(Either::B((timeout_error, _get))) => Err(timeout_error),
+        });
+    lp.run(timed_testcase)
+}
+
+#[test]
+fn test_proxy_protocol() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64045".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:64046".parse().unwrap();
+    let seen = start_servers(&lp.handle(), proxy, backend);
+
+    // Version 1 from the balancer, version 2 to the backend
+    let buf = run_request(&mut lp, proxy, b"PROXY TCP4 198.51.100.7 127.0.0.1 5555 64045\r\n",
+                          backend).unwrap();
+    assert_eq!(&buf[2..4], &[5u8,0]);
+    assert_eq!(&buf[12..], b"ping");
+    let header = seen.lock().unwrap().pop().unwrap().unwrap();
+    assert_eq!(header.source, "198.51.100.7:5555".parse().unwrap());
+    assert_eq!(header.destination, backend);
+
+    // Version 2 with ipv6 source
+    let source: SocketAddr = "[2001:db8::1]:6666".parse().unwrap();
+    let header = ProxyHeader::new(source, proxy).encode(ProxyVersion::V2);
+    let buf = run_request(&mut lp, proxy, &header, backend).unwrap();
+    assert_eq!(&buf[12..], b"ping");
+    let header = seen.lock().unwrap().pop().unwrap().unwrap();
+    assert_eq!(header.source, source);
+
+    // The acl sees the original client
+    let buf = run_request(&mut lp, proxy, b"PROXY TCP4 203.0.113.9 127.0.0.1 5555 64045\r\n",
+                          backend).unwrap();
+    assert_eq!(&buf[2..4], &[5u8,2]);
+
+    // Without header the connection is closed
+    assert!(run_request(&mut lp, proxy, b"", backend).is_err());
+
+    let mixed = ProxyHeader::new("192.0.2.1:1".parse().unwrap(), "[2001:db8::2]:2".parse().unwrap());
+    assert_eq!(mixed.encode(ProxyVersion::V1),
+               b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 1 2\r\n".to_vec());
+}
+
+// Collects the messages of the access log
+struct AccessLog {
+    next_id: AtomicUsize,
+    lines: Arc<Mutex<Vec<String>>>
+}
+
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+impl tracing::Subscriber for AccessLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == ACCESS_LOG_TARGET
+    }
+
+    fn new_span(&self, _span: &Attributes) -> Id {
+        Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        self.lines.lock().unwrap().push(message.0);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+#[test]
+fn test_proxy_protocol_access_log() {
+    let lines = Arc::new(Mutex::new(vec!()));
+    let access_log = AccessLog { next_id: AtomicUsize::new(0), lines: lines.clone() };
+    tracing::subscriber::with_default(access_log, || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+        let proxy: SocketAddr = "127.0.0.1:64106".parse().unwrap();
+        let echo: SocketAddr = "127.0.0.1:64107".parse().unwrap();
+
+        let handle2 = handle.clone();
+        let listener = TcpListener::bind(&echo, &handle).unwrap();
+        handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+            let (rd,wr) = stream.split();
+            handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+            Ok(())
+        }).then( |_| { Ok(())}));
+
+        // Router behind a load balancer, which sends PROXY headers
+        let table = Arc::new("".parse::<RoutingTable>().unwrap());
+        let router = Router::new(table, Arc::new(SystemResolver));
+        let handle2 = handle.clone();
+        let listener = TcpListener::bind(&proxy, &handle).unwrap();
+        handle.spawn(listener.incoming().for_each(move |(stream, _addr)| {
+            let router = router.clone();
+            let handle3 = handle2.clone();
+            handle2.spawn(read_proxy_header(stream)
+                .and_then(|(stream,header)| {
+                    let source = header.map(|header| header.source);
+                    socks_handshake_over(stream).map(move |(stream,request)| (stream,request,source))
+                })
+                .and_then(move |(stream,request,source)| {
+                    router.serve_request_from(stream, request, source, &handle3)
+                })
+                .then( |_| { Ok(())}));
+            Ok(())
+        }).then( |_| { Ok(())}));
+
+        let buf = run_request(&mut lp, proxy, b"PROXY TCP4 198.51.100.7 127.0.0.1 5555 64106\r\n",
+                              echo).unwrap();
+        assert_eq!(&buf[12..], b"ping");
+        lp.run(Timeout::new(Duration::from_millis(200), &handle).unwrap()).unwrap();
+    });
+
+    // The access log names the client of the PROXY header, not the balancer
+    let lines = lines.lock().unwrap();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with("198.51.100.7:5555 - 127.0.0.1:64107 4 4 "), "{}", lines[0]);
+}
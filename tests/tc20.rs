@@ -0,0 +1,106 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::ErrorKind;
+use std::net::{SocketAddr, Shutdown};
+use std::time::Duration;
+use socksv5_future::{relay, RelayStats};
+use futures::{Future,Stream};
+use futures::sync::mpsc;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::io::{read_to_end, write_all};
+
+// The relay connects each client to the backend and reports the stats
+fn start_relay(handle: &Handle, addr: SocketAddr, backend: SocketAddr,
+               idle_timeout: Duration) -> mpsc::UnboundedReceiver<RelayStats> {
+    let (tx,rx) = mpsc::unbounded();
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(client, _addr)| {
+        let handle3 = handle2.clone();
+        let tx = tx.clone();
+        handle2.spawn(TcpStream::connect(&backend, &handle2)
+            .and_then(move |target| relay(client,target).with_idle_timeout(idle_timeout, &handle3))
+            .map(move |stats| { let _ = tx.unbounded_send(stats); })
+            .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+    rx
+}
+
+// The backend reads until end of file and answers with the number of bytes
+fn start_backend(handle: &Handle, addr: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&addr, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(read_to_end(stream, vec!())
+            .and_then(|(stream,data)| write_all(stream, format!("done:{}", data.len())))
+            .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_relay() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let proxy: SocketAddr = "127.0.0.1:64047".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:64048".parse().unwrap();
+    start_backend(&handle, backend);
+    let rx = start_relay(&handle, proxy, backend, Duration::from_millis(300));
+
+    // The backend answers only after the end of file has been relayed
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| write_all(stream, b"hello"))
+        .and_then(|(stream,_buf)| {
+            stream.shutdown(Shutdown::Write)?;
+            Ok(stream)
+        })
+        .and_then(|stream| read_to_end(stream, vec!()))
+        .map(|(_stream,data)| data);
+    assert_eq!(lp.run(test_conn).unwrap(), b"done:5".to_vec());
+    let (stats,rx) = lp.run(rx.into_future()).ok().unwrap();
+    let stats = stats.unwrap();
+    assert_eq!((stats.sent,stats.received,stats.timed_out), (5,6,false));
+
+    // An idle client is disconnected after the timeout
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| read_to_end(stream, vec!()))
+        .map(|(_stream,data)| data);
+    assert_eq!(lp.run(test_conn).unwrap(), b"".to_vec());
+    let (stats,_rx) = lp.run(rx.into_future()).ok().unwrap();
+    let stats = stats.unwrap();
+    assert_eq!((stats.sent,stats.received,stats.timed_out), (0,0,true));
+    assert!(stats.duration >= Duration::from_millis(300));
+}
+
+#[test]
+fn test_relay_reset() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let proxy: SocketAddr = "127.0.0.1:64088".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:64089".parse().unwrap();
+    start_backend(&handle, backend);
+    let rx = start_relay(&handle, proxy, backend, Duration::from_secs(5));
+
+    // The client resets the connection after the data has been relayed
+    let handle2 = handle.clone();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| write_all(stream, b"hello"))
+        .and_then(move |(stream,_buf)| {
+            Timeout::new(Duration::from_millis(100), &handle2).unwrap().map(|_| stream)
+        })
+        .and_then(|stream| stream.set_linger(Some(Duration::from_secs(0))));
+    lp.run(test_conn).unwrap();
+
+    // The stats of the bytes moved before the error are still delivered
+    let (stats,_rx) = lp.run(rx.into_future()).ok().unwrap();
+    let stats = stats.unwrap();
+    assert_eq!((stats.sent,stats.received,stats.timed_out), (5,0,false));
+    assert_eq!(stats.error, Some(ErrorKind::ConnectionReset));
+}
@@ -0,0 +1,103 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io;
+use std::net::{SocketAddr, Shutdown};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use socksv5_future::{socks_handshake, socks_connect_handshake_with_credentials, BandwidthLimiter,
+                     Command, Credentials, Rate, Router, RoutingTable, SocksRequestResponse,
+                     SystemResolver, UserList};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_to_end, write_all};
+
+const SIZE: usize = 300_000;
+const RATE: u64 = 200_000;
+
+fn start_servers(handle: &Handle, proxy: SocketAddr, echo: SocketAddr,
+                 limiter: Arc<BandwidthLimiter>) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+
+    let users = Arc::new("alice secret\nbob hunter2\n".parse::<UserList>().unwrap());
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver)).with_bandwidth(limiter);
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let rt = rt.clone();
+        let handle3 = handle2.clone();
+        handle2.spawn(socks_handshake(stream)
+            .with_authenticator(users.clone())
+            .and_then(move |(client,request)| rt.serve_request(client, request, &handle3))
+            .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Send SIZE bytes through the proxy to the echo server and deliver the time
+// until all bytes have been received back.
+fn transfer(lp: &mut Core, proxy: SocketAddr, echo: SocketAddr,
+            credentials: Option<Credentials>) -> io::Result<Duration> {
+    let handle = lp.handle();
+    let data: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    let expected = data.clone();
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let start = Instant::now();
+    let test_conn = TcpStream::connect(&proxy, &handle)
+        .and_then(move |stream| socks_connect_handshake_with_credentials(stream, request, credentials))
+        .and_then(move |(stream,_reply)| write_all(stream, data))
+        .and_then(|(stream,_buf)| {
+            stream.shutdown(Shutdown::Write)?;
+            Ok(stream)
+        })
+        .and_then(|stream| read_to_end(stream, vec!()))
+        .map(move |(_stream,data)| {
+            assert!(data == expected);
+            start.elapsed()
+        });
+    lp.run(test_conn)
+}
+
+#[test]
+fn test_bandwidth() {
+    let mut lp = Core::new().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64051".parse().unwrap();
+    let echo: SocketAddr = "127.0.0.1:64052".parse().unwrap();
+    let limiter = Arc::new(BandwidthLimiter::new());
+    limiter.set_user("alice", Rate::new(Some(RATE), None));
+    start_servers(&lp.handle(), proxy, echo, limiter.clone());
+
+    // RFC 1929 authentication is required
+    assert!(transfer(&mut lp, proxy, echo, None).is_err());
+    let wrong = Credentials::new("alice", "wrong");
+    assert_eq!(transfer(&mut lp, proxy, echo, Some(wrong)).unwrap_err().kind(),
+               io::ErrorKind::PermissionDenied);
+
+    // One second burst, then the rate
+    let alice = Credentials::new("alice", "secret");
+    assert!(transfer(&mut lp, proxy, echo, Some(alice)).unwrap() >= Duration::from_millis(450));
+    let bob = Credentials::new("bob", "hunter2");
+    assert!(transfer(&mut lp, proxy, echo, Some(bob.clone())).unwrap() < Duration::from_millis(400));
+
+    // Limits can be changed at runtime
+    limiter.set_global(Rate::new(None, Some(RATE)));
+    assert!(transfer(&mut lp, proxy, echo, Some(bob.clone())).unwrap() >= Duration::from_millis(450));
+    limiter.set_global(Rate::unlimited());
+    limiter.set_per_connection(Rate::new(Some(RATE), Some(RATE)));
+    assert!(transfer(&mut lp, proxy, echo, Some(bob.clone())).unwrap() >= Duration::from_millis(450));
+    limiter.set_per_connection(Rate::unlimited());
+    assert!(transfer(&mut lp, proxy, echo, Some(bob)).unwrap() < Duration::from_millis(400));
+}
@@ -0,0 +1,217 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use socksv5_future::{socks_handshake, socks_connect_handshake_with_credentials, Command,
+                     ConnectionLimits, Credentials, Router, RoutingTable, SocksRequestResponse,
+                     SystemResolver, UserList};
+use futures::{Future,Stream};
+use futures::future::Either;
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn router(limits: &Arc<ConnectionLimits>) -> Router {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    Router::new(table, Arc::new(SystemResolver)).with_limits(limits.clone())
+}
+
+// Router, which admits the clients before the handshake
+fn start_router(handle: &Handle, proxy: SocketAddr, limits: &Arc<ConnectionLimits>) {
+    let rt = router(limits);
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Router behind a handshake with username/password authentication
+fn start_auth_router(handle: &Handle, proxy: SocketAddr, limits: &Arc<ConnectionLimits>) {
+    let users = Arc::new("alice a\nbob b\ncarol c\n".parse::<UserList>().unwrap());
+    let rt = router(limits);
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let rt = rt.clone();
+        let handle3 = handle2.clone();
+        handle2.spawn(socks_handshake(stream)
+            .with_authenticator(users.clone())
+            .and_then(move |(client,request)| rt.serve_request(client, request, &handle3))
+            .then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+type Tunnel = Box<dyn Future<Item=(u8,Option<TcpStream>), Error=io::Error>>;
+
+// Reply code and the stream of an open tunnel, which has echoed "ping"
+fn tunnel(handle: &Handle, proxy: SocketAddr, echo: SocketAddr, user: Option<&str>) -> Tunnel {
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let credentials = user.map(|user| Credentials::new(user, &user[..1]));
+    Box::new(TcpStream::connect(&proxy, handle)
+        .and_then(move |stream| socks_connect_handshake_with_credentials(stream, request, credentials))
+        .and_then(|(stream,reply)| {
+            let rep = reply.bytes[1];
+            if rep != 0 {
+                return Either::B(futures::future::ok((rep,None)));
+            }
+            Either::A(write_all(stream,b"ping")
+                .and_then(|(stream,_buf)| read_exact(stream,[0u8;4]))
+                .map(move |(stream,buf)| {
+                    assert_eq!(&buf, b"ping");
+                    (rep,Some(stream))
+                }))
+        }))
+}
+
+fn open(lp: &mut Core, proxy: SocketAddr, echo: SocketAddr, user: Option<&str>) -> io::Result<(u8,Option<TcpStream>)> {
+    let handle = lp.handle();
+    lp.run(tunnel(&handle, proxy, echo, user))
+}
+
+// Let the server notice closed connections
+fn settle(lp: &mut Core) {
+    let timeout = Timeout::new(Duration::from_millis(100), &lp.handle()).unwrap();
+    lp.run(timeout).unwrap();
+}
+
+#[test]
+fn test_connection_limits() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64053".parse().unwrap();
+    start_echo(&handle, echo);
+
+    // Handshakes
+    let proxy: SocketAddr = "127.0.0.1:64054".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_handshakes(1));
+    start_router(&handle, proxy, &limits);
+    let silent = lp.run(TcpStream::connect(&proxy, &handle)).unwrap();
+    settle(&mut lp);
+    assert_eq!(limits.handshakes(), 1);
+    assert!(open(&mut lp, proxy, echo, None).is_err());
+    drop(silent);
+    settle(&mut lp);
+    let (rep,stream) = open(&mut lp, proxy, echo, None).unwrap();
+    assert_eq!((rep, limits.handshakes(), limits.tunnels()), (0,0,1));
+    drop(stream);
+    settle(&mut lp);
+    assert_eq!(limits.tunnels(), 0);
+
+    // Tunnels and users, the request has been read
+    let proxy: SocketAddr = "127.0.0.1:64055".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_tunnels(2).with_max_per_user(1));
+    start_auth_router(&handle, proxy, &limits);
+    let (rep,alice) = open(&mut lp, proxy, echo, Some("alice")).unwrap();
+    assert_eq!(rep, 0);
+    assert_eq!(open(&mut lp, proxy, echo, Some("alice")).unwrap().0, 1);
+    let (rep,_bob) = open(&mut lp, proxy, echo, Some("bob")).unwrap();
+    assert_eq!(rep, 0);
+    assert_eq!(open(&mut lp, proxy, echo, Some("carol")).unwrap().0, 1);
+    drop(alice);
+    settle(&mut lp);
+    assert_eq!(open(&mut lp, proxy, echo, Some("carol")).unwrap().0, 0);
+
+    // Per source ip
+    let proxy: SocketAddr = "127.0.0.1:64056".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_per_ip(1));
+    start_router(&handle, proxy, &limits);
+    let (rep,_first) = open(&mut lp, proxy, echo, None).unwrap();
+    assert_eq!(rep, 0);
+    assert!(open(&mut lp, proxy, echo, None).is_err());
+
+    // Queued until the first tunnel is closed
+    let proxy: SocketAddr = "127.0.0.1:64057".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_tunnels(1).with_queue());
+    start_router(&handle, proxy, &limits);
+    let (rep,first) = open(&mut lp, proxy, echo, None).unwrap();
+    assert_eq!(rep, 0);
+    let close_first = Timeout::new(Duration::from_millis(200), &handle).unwrap()
+        .map(move |_| drop(first));
+    let start = Instant::now();
+    let queued = tunnel(&handle, proxy, echo, None).map(move |res| (res,start.elapsed()));
+    let (((rep,_second),elapsed),_) = lp.run(queued.join(close_first)).unwrap();
+    assert_eq!((rep, limits.tunnels()), (0,1));
+    assert!(elapsed >= Duration::from_millis(200));
+}
+
+#[test]
+fn test_connection_queue() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64098".parse().unwrap();
+    start_echo(&handle, echo);
+
+    // One queued tunnel, the next one is rejected
+    let proxy: SocketAddr = "127.0.0.1:64099".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_tunnels(1).with_max_queue(1));
+    start_router(&handle, proxy, &limits);
+    let (rep,first) = open(&mut lp, proxy, echo, None).unwrap();
+    assert_eq!(rep, 0);
+    let (tx,second) = futures::sync::oneshot::channel();
+    handle.spawn(tunnel(&handle, proxy, echo, None).then(move |res| {
+        let _ = tx.send(res);
+        Ok(())
+    }));
+    settle(&mut lp);
+    assert_eq!(limits.queued(), 1);
+    assert_eq!(open(&mut lp, proxy, echo, None).unwrap().0, 1);
+
+    // A freed slot wakes the queued tunnel
+    drop(first);
+    let (rep,_second) = lp.run(second).unwrap().unwrap();
+    assert_eq!((rep, limits.tunnels(), limits.queued()), (0,1,0));
+}
+
+#[test]
+fn test_connection_queue_wakes_one() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64100".parse().unwrap();
+    start_echo(&handle, echo);
+
+    let proxy: SocketAddr = "127.0.0.1:64101".parse().unwrap();
+    let limits = Arc::new(ConnectionLimits::new().with_max_tunnels(1).with_queue());
+    start_router(&handle, proxy, &limits);
+    let (rep,first) = open(&mut lp, proxy, echo, None).unwrap();
+    assert_eq!(rep, 0);
+    let done = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for _ in 0..3 {
+        let done = done.clone();
+        handle.spawn(tunnel(&handle, proxy, echo, None).then(move |res| {
+            done.lock().unwrap().push(res.ok());
+            Ok(())
+        }));
+    }
+    settle(&mut lp);
+    assert_eq!(limits.queued(), 3);
+
+    // Only one of the queued tunnels takes the slot
+    drop(first);
+    settle(&mut lp);
+    assert_eq!((limits.tunnels(), limits.queued()), (1,2));
+    let done = done.lock().unwrap();
+    assert_eq!(done.len(), 1);
+    assert!(matches!(done[0], Some((0,Some(_)))));
+}
@@ -0,0 +1,117 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use socksv5_future::{serve_metrics, socks_connect_handshake, Command, Metrics, Router,
+                     RoutingTable, SocksRequestResponse, SystemResolver};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, read_to_end, write_all};
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_router(handle: &Handle, proxy: SocketAddr, metrics: &Arc<Metrics>) {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver)).with_metrics(metrics.clone());
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn get(core: &mut Core, addr: SocketAddr, path: &str) -> String {
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    let fut = TcpStream::connect(&addr, &core.handle())
+        .and_then(move |stream| write_all(stream, request.into_bytes()))
+        .and_then(|(stream,_buf)| read_to_end(stream, vec!()));
+    let (_stream,response) = core.run(fut).unwrap();
+    String::from_utf8(response).unwrap()
+}
+
+fn pause(core: &mut Core) {
+    let timeout = Timeout::new(Duration::from_millis(200), &core.handle()).unwrap();
+    core.run(timeout).unwrap();
+}
+
+#[test]
+fn test_metrics() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64058".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64059".parse().unwrap();
+    let endpoint: SocketAddr = "127.0.0.1:64060".parse().unwrap();
+    let closed: SocketAddr = "127.0.0.1:64061".parse().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    start_echo(&handle, echo);
+    start_router(&handle, proxy, &metrics);
+    let listener = TcpListener::bind(&endpoint, &handle).unwrap();
+    handle.spawn(serve_metrics(listener, metrics.clone(), &handle).then( |_| { Ok(())}));
+
+    // Tunnel to the echo server
+    let request = SocksRequestResponse::request(Command::Connect, &echo);
+    let fut = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request))
+        .and_then(|(stream,_reply)| write_all(stream, b"ping"))
+        .and_then(|(stream,_buf)| read_exact(stream, [0u8;4]));
+    let (stream,buf) = lp.run(fut).unwrap();
+    assert_eq!(&buf, b"ping");
+    assert_eq!(metrics.active_tunnels(), 1);
+    drop(stream);
+    pause(&mut lp);
+    assert_eq!(metrics.active_tunnels(), 0);
+
+    // Destination refuses the connection
+    let request = SocksRequestResponse::request(Command::Connect, &closed);
+    let fut = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request));
+    let (_stream,reply) = lp.run(fut).unwrap();
+    assert_eq!(reply.bytes[1], 5);
+
+    // Not socks5
+    let fut = TcpStream::connect(&proxy, &handle)
+        .and_then(|stream| write_all(stream, [4u8, 1]))
+        .and_then(|(stream,_buf)| read_to_end(stream, vec!()));
+    let (_stream,answer) = lp.run(fut).unwrap();
+    assert!(answer.is_empty());
+    pause(&mut lp);
+
+    let text = metrics.render();
+    for line in &["socks_handshakes_total{outcome=\"success\"} 2",
+                  "socks_handshakes_total{outcome=\"error\",error=\"other\"} 1",
+                  "socks_auth_methods_total{method=\"no_auth\"} 2",
+                  "socks_commands_total{command=\"connect\"} 2",
+                  "socks_replies_sent_total{code=\"succeeded\"} 1",
+                  "socks_replies_sent_total{code=\"connection_refused\"} 1",
+                  "socks_relayed_bytes_total{direction=\"upload\"} 4",
+                  "socks_relayed_bytes_total{direction=\"download\"} 4",
+                  "socks_active_tunnels 0",
+                  "socks_dial_duration_seconds_bucket{le=\"+Inf\"} 1",
+                  "socks_dial_duration_seconds_count 1"] {
+        assert!(text.lines().any(|l| l == *line), "missing {} in\n{}", line, text);
+    }
+
+    // Served over HTTP
+    let response = get(&mut lp, endpoint, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(&text));
+    let response = get(&mut lp, endpoint, "/");
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
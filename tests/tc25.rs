@@ -0,0 +1,161 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate tracing;
+extern crate socksv5_future;
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use socksv5_future::{socks_connect_handshake, AccessLogEntry, Command, RelayStats, Router,
+                     RoutingTable, SocksRequestResponse, SystemResolver, ACCESS_LOG_TARGET};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle, Timeout};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+use tracing::{Event, Metadata};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+
+// Collects the events as "target: field=value ..."
+struct Collector {
+    next_id: AtomicUsize,
+    events: Arc<Mutex<Vec<String>>>
+}
+
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push_str(&format!(" {}={:?}", field.name(), value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push_str(&format!(" {}={}", field.name(), value));
+    }
+}
+
+impl tracing::Subscriber for Collector {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &Attributes) -> Id {
+        Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) as u64 + 1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event) {
+        let mut fields = Fields(format!("{}:", event.metadata().target()));
+        event.record(&mut fields);
+        self.events.lock().unwrap().push(fields.0);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_router(handle: &Handle, proxy: SocketAddr) {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_access_log_entry() {
+    let request = SocksRequestResponse::request_hostname(Command::Connect, "example.com", 443)
+                        .unwrap();
+    assert_eq!(request.destination(), "example.com:443");
+    let stats = RelayStats {
+        sent: 512,
+        received: 20480,
+        duration: Duration::from_millis(1250),
+        timed_out: false,
+        error: None
+    };
+    let entry = AccessLogEntry::new(Some("192.0.2.1:50812".parse().unwrap()), &request, stats);
+    assert_eq!(entry.to_string(), "192.0.2.1:50812 - example.com:443 512 20480 1.250 -");
+
+    let addr: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+    let mut request = SocksRequestResponse::request(Command::Connect, &addr);
+    request.user = Some("alice".to_string());
+    let stats = RelayStats { timed_out: true, ..stats };
+    let entry = AccessLogEntry::new(None, &request, stats);
+    assert_eq!(entry.to_string(), "- alice [2001:db8::1]:80 512 20480 1.250 timeout");
+
+    let stats = RelayStats { timed_out: false, error: Some(ErrorKind::ConnectionReset), ..stats };
+    let entry = AccessLogEntry::new(None, &request, stats);
+    assert_eq!(entry.to_string(), "- alice [2001:db8::1]:80 512 20480 1.250 error");
+
+    // Client-controlled fields cannot inject separators or lines
+    let mut request = SocksRequestResponse::request_hostname(Command::Connect,
+                                                             "a b\nfake.example", 80).unwrap();
+    request.user = Some("eve\\ 1.2.3.4\u{2028}".to_string());
+    let stats = RelayStats { error: None, ..stats };
+    let entry = AccessLogEntry::new(None, &request, stats);
+    assert_eq!(entry.to_string(),
+               "- eve\\x5c\\x201.2.3.4\\u{2028} a\\x20b\\x0afake.example:80 512 20480 1.250 -");
+}
+
+#[test]
+fn test_tracing() {
+    let events = Arc::new(Mutex::new(vec!()));
+    let collector = Collector { next_id: AtomicUsize::new(0), events: events.clone() };
+    tracing::subscriber::with_default(collector, || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+        let echo: SocketAddr = "127.0.0.1:64062".parse().unwrap();
+        let proxy: SocketAddr = "127.0.0.1:64063".parse().unwrap();
+        start_echo(&handle, echo);
+        start_router(&handle, proxy);
+
+        let request = SocksRequestResponse::request(Command::Connect, &echo);
+        let fut = TcpStream::connect(&proxy, &handle)
+            .and_then(|stream| socks_connect_handshake(stream, request))
+            .and_then(|(stream,_reply)| write_all(stream, b"ping"))
+            .and_then(|(stream,_buf)| read_exact(stream, [0u8;4]));
+        let (stream,buf) = lp.run(fut).unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(stream);
+        let timeout = Timeout::new(Duration::from_millis(200), &handle).unwrap();
+        lp.run(timeout).unwrap();
+    });
+
+    let events = events.lock().unwrap();
+    let contains = |text: &str| events.iter().any(|e| e.contains(text));
+    assert!(contains("from=WaitClientAuthentication to=ReadAuthenticationMethods"));
+    assert!(contains("method=no_auth"));
+    assert!(contains("target=127.0.0.1:64062 command=connect"));
+    assert!(contains("from=WaitReply"));
+    assert!(contains("reply=succeeded"));
+    let access: Vec<&String> = events.iter()
+                                     .filter(|e| e.starts_with(&format!("{}:", ACCESS_LOG_TARGET)))
+                                     .collect();
+    assert_eq!(access.len(), 1);
+    assert!(access[0].contains(" - 127.0.0.1:64062 4 4 "), "{}", access[0]);
+}
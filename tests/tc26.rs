@@ -0,0 +1,152 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use socksv5_future::{socks_connect_handshake_with_credentials, Credentials,
+                     SocksRequestResponse};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+// Kills the server at the end of the test
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn write_file(name: &str, content: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("socksv5d-tc26-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn wait_for(addr: SocketAddr) {
+    for _ in 0..100 {
+        if net::TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("socksv5d does not listen on {}", addr);
+}
+
+fn ping(core: &mut Core, proxy: SocketAddr, echo: SocketAddr, credentials: Credentials) -> bool {
+    let request = SocksRequestResponse::request(socksv5_future::Command::Connect, &echo);
+    let fut = TcpStream::connect(&proxy, &core.handle())
+        .and_then(move |stream| socks_connect_handshake_with_credentials(stream, request,
+                                                                         Some(credentials)))
+        .and_then(|(stream,_reply)| write_all(stream, b"ping"))
+        .and_then(|(stream,_buf)| read_exact(stream, [0u8;4]));
+    match core.run(fut) {
+        Ok((_stream,buf)) => &buf == b"ping",
+        Err(_) => false
+    }
+}
+
+#[test]
+fn test_socksv5d() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64064".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64065".parse().unwrap();
+    let metrics: SocketAddr = "127.0.0.1:64066".parse().unwrap();
+    start_echo(&handle, echo);
+
+    let users = write_file("users", "alice secret\n");
+    let config = write_file("config.toml", &format!("\
+        listen = \"127.0.0.1:1\"\n\
+        users = \"{}\"\n\
+        metrics = \"{}\"\n\
+        log_level = \"warn\"\n\
+        [timeouts]\n\
+        handshake = 5\n\
+        idle = 60\n", users.display(), metrics));
+    // The listen address of the file is replaced by the flag
+    let _daemon = Daemon(Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--config").arg(&config)
+        .arg("--listen").arg(proxy.to_string())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+    wait_for(proxy);
+    wait_for(metrics);
+
+    assert!(ping(&mut lp, proxy, echo, Credentials::new("alice", "secret")));
+    assert!(!ping(&mut lp, proxy, echo, Credentials::new("alice", "wrong")));
+
+    let mut stream = net::TcpStream::connect(metrics).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.contains("socks_handshakes_total{outcome=\"success\"} 1\n"));
+    assert!(response.contains("socks_handshakes_total{outcome=\"error\",error=\"permission_denied\"} 1\n"));
+    assert!(response.contains("socks_auth_methods_total{method=\"username_password\"} 2\n"));
+
+    let _ = fs::remove_file(users);
+    let _ = fs::remove_file(config);
+}
+
+#[test]
+fn test_socksv5d_invalid_config() {
+    let config = write_file("invalid.toml", "listen = \"127.0.0.1:64067\"\nport = 1080\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--config").arg(&config)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unknown key 'port'"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--routes").arg("/nonexistent").arg("--upstream").arg("127.0.0.1:1")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("upstream and routes exclude each other"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--users").arg("/nonexistent").arg("--socks4")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("socks4 and users exclude each other"));
+
+    // --routes replaces upstream of the file, the routes file is read
+    let upstream = write_file("upstream.toml", "upstream = [\"127.0.0.1:1\"]\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--config").arg(&upstream).arg("--routes").arg("/nonexistent")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr.contains("exclude each other"), "{}", stderr);
+    assert!(stderr.contains("/nonexistent"), "{}", stderr);
+    let _ = fs::remove_file(config);
+    let _ = fs::remove_file(upstream);
+}
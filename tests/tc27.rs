@@ -0,0 +1,99 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use socksv5_future::{Router, RoutingTable, SystemResolver};
+use futures::{future, Future, Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::TcpListener;
+use tokio_io::AsyncRead;
+use tokio_io::io::copy;
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_router(handle: &Handle, proxy: SocketAddr) {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+// Echo server and router in their own thread, while the client runs
+fn start_servers(echo: SocketAddr, proxy: SocketAddr) {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+        start_echo(&handle, echo);
+        start_router(&handle, proxy);
+        tx.send(()).unwrap();
+        lp.run(future::empty::<(),()>()).unwrap();
+    });
+    rx.recv().unwrap();
+}
+
+#[test]
+fn test_socksv5_client() {
+    let echo: SocketAddr = "127.0.0.1:64068".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64069".parse().unwrap();
+    start_servers(echo, proxy);
+
+    // Chain of two hops through the same router, piped to the echo server
+    let mut child = Command::new(env!("CARGO_BIN_EXE_socksv5-client"))
+        .arg("-x").arg(proxy.to_string())
+        .arg("-x").arg(format!("socks5://{}", proxy))
+        .arg("--pipe")
+        .arg(echo.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"ping").unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(&output.stdout, b"ping");
+    assert!(stderr.contains("method=\"no_auth\""), "{}", stderr);
+    assert!(stderr.contains("hop 0 socks5://127.0.0.1:64069: reply Succeeded (0), bound address 127.0.0.1:"),
+            "{}", stderr);
+    assert!(stderr.contains("hop 1 socks5://127.0.0.1:64069: reply Succeeded (0)"), "{}", stderr);
+
+    // Nothing listens on the target
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5-client"))
+        .arg("-x").arg(proxy.to_string())
+        .arg("127.0.0.1:64070")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("reply ConnectionRefused (5)"), "{}", stderr);
+
+    // Missing proxy
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5-client"))
+        .arg("127.0.0.1:64070")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,124 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Read, Write};
+use std::net;
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use socksv5_future::{Metrics, PortForward, Proxy, Router, RoutingTable, SocksRequestResponse,
+                     SystemResolver};
+use futures::{future, Future, Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+// Kills the forwarder at the end of the test
+struct Forwarder(Child);
+
+impl Drop for Forwarder {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_router(handle: &Handle, proxy: SocketAddr) {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_port_forward() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64071".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64072".parse().unwrap();
+    let local: SocketAddr = "127.0.0.1:64073".parse().unwrap();
+    start_echo(&handle, echo);
+    start_router(&handle, proxy);
+
+    let metrics = Arc::new(Metrics::new());
+    let request = SocksRequestResponse::request(socksv5_future::Command::Connect, &echo);
+    let forward = PortForward::new(Proxy::new(proxy), request).with_metrics(metrics.clone());
+    let listener = TcpListener::bind(&local, &handle).unwrap();
+    handle.spawn(forward.listen(listener, &handle).then( |_| { Ok(())}));
+
+    // Both connections stay open
+    let mut streams = vec!();
+    for _ in 0..2 {
+        let fut = TcpStream::connect(&local, &handle)
+            .and_then(|stream| write_all(stream, b"ping"))
+            .and_then(|(stream,_buf)| read_exact(stream, [0u8;4]));
+        let (stream,buf) = lp.run(fut).unwrap();
+        assert_eq!(&buf, b"ping");
+        streams.push(stream);
+    }
+    assert_eq!(metrics.active_tunnels(), 2);
+}
+
+#[test]
+fn test_forward_subcommand() {
+    let echo: SocketAddr = "127.0.0.1:64074".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64075".parse().unwrap();
+    let local: SocketAddr = "127.0.0.1:64076".parse().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+        start_echo(&handle, echo);
+        start_router(&handle, proxy);
+        tx.send(()).unwrap();
+        lp.run(future::empty::<(),()>()).unwrap();
+    });
+    rx.recv().unwrap();
+
+    let _forwarder = Forwarder(Command::new(env!("CARGO_BIN_EXE_socksv5-client"))
+        .arg("forward")
+        .arg("-L").arg(local.to_string())
+        .arg("-x").arg(format!("socks5://{}", proxy))
+        .arg(echo.to_string())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+    let mut stream = (0..100).filter_map(|_| {
+        net::TcpStream::connect(local).map_err(|_| thread::sleep(Duration::from_millis(50))).ok()
+    }).next().expect("forwarder does not listen");
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0u8;4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+
+    // Missing local address
+    let output = Command::new(env!("CARGO_BIN_EXE_socksv5-client"))
+        .arg("forward")
+        .arg("-x").arg(proxy.to_string())
+        .arg(echo.to_string())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
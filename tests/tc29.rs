@@ -0,0 +1,125 @@
+#![cfg(target_os = "linux")]
+
+extern crate futures;
+extern crate libc;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::io::{Read, Write};
+use std::net;
+use std::net::SocketAddr;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use socksv5_future::{original_destination, Metrics, Proxy, Router, RoutingTable, SystemResolver,
+                     TransparentProxy};
+use futures::{future, Future, Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_to_end};
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn start_router(handle: &Handle, proxy: SocketAddr) {
+    let table = Arc::new("".parse::<RoutingTable>().unwrap());
+    let rt = Router::new(table, Arc::new(SystemResolver));
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&proxy, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        handle2.spawn(rt.serve(stream, &handle2).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+#[test]
+fn test_not_redirected() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let proxy: SocketAddr = "127.0.0.1:64077".parse().unwrap();
+    let local: SocketAddr = "127.0.0.1:64078".parse().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    start_router(&handle, proxy);
+    let transparent = TransparentProxy::new(Proxy::new(proxy)).with_metrics(metrics.clone());
+    let listener = TcpListener::bind(&local, &handle).unwrap();
+    handle.spawn(transparent.listen(listener, &handle).then( |_| { Ok(())}));
+
+    // Without redirect there is no original destination or it is the listener
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accept = listener.incoming().take(1).collect();
+    let connect = TcpStream::connect(&addr, &handle);
+    let (accepted,_stream) = lp.run(accept.join(connect)).unwrap();
+    if let Ok(destination) = original_destination(&accepted[0].0) {
+        assert_eq!(destination, addr);
+    }
+
+    // The direct connection is closed without tunnel
+    let fut = TcpStream::connect(&local, &handle)
+        .and_then(|stream| read_to_end(stream, vec!()));
+    let (_stream,buf) = lp.run(fut).unwrap();
+    assert!(buf.is_empty());
+    assert_eq!(metrics.active_tunnels(), 0);
+}
+
+// Requires root and iptables:
+//     cargo test --test tc29 -- --ignored
+// The servers run in the initial network namespace. The test thread moves
+// into a new one, where connections to the echo port are redirected to the
+// listener of the transparent proxy. Sockets stay in the namespace they are
+// created in, so the proxy reaches the router outside.
+#[test]
+#[ignore]
+fn test_redirect() {
+    let echo: SocketAddr = "127.0.0.1:64079".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64080".parse().unwrap();
+    let local: SocketAddr = "127.0.0.1:64081".parse().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (listener_tx, listener_rx) = mpsc::channel::<net::TcpListener>();
+    let metrics2 = metrics.clone();
+    thread::spawn(move || {
+        let mut lp = Core::new().unwrap();
+        let handle = lp.handle();
+        start_echo(&handle, echo);
+        start_router(&handle, proxy);
+        ready_tx.send(()).unwrap();
+        let listener = TcpListener::from_listener(listener_rx.recv().unwrap(), &local, &handle)
+                            .unwrap();
+        let transparent = TransparentProxy::new(Proxy::new(proxy)).with_metrics(metrics2);
+        handle.spawn(transparent.listen(listener, &handle).then( |_| { Ok(())}));
+        ready_tx.send(()).unwrap();
+        lp.run(future::empty::<(),()>()).unwrap();
+    });
+    ready_rx.recv().unwrap();
+
+    assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWNET) }, 0, "unshare failed");
+    for cmd in &["ip link set lo up",
+                 "iptables -t nat -A OUTPUT -p tcp -d 127.0.0.1 --dport 64079 \
+                  -j REDIRECT --to-ports 64081"] {
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        let status = Command::new(args[0]).args(&args[1..]).status().unwrap();
+        assert!(status.success(), "{} failed", cmd);
+    }
+    listener_tx.send(net::TcpListener::bind(local).unwrap()).unwrap();
+    ready_rx.recv().unwrap();
+
+    let mut stream = net::TcpStream::connect(echo).unwrap();
+    stream.write_all(b"ping").unwrap();
+    let mut buf = [0u8;4];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"ping");
+    assert_eq!(metrics.active_tunnels(), 1);
+}
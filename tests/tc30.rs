@@ -0,0 +1,155 @@
+#![cfg(unix)]
+
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::env;
+use std::fs;
+use std::net;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use socksv5_future::{socks_connect_handshake_with_credentials, ConfigHandle, Credentials,
+                     SocksRequestResponse};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Handle};
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::AsyncRead;
+use tokio_io::io::{copy, read_exact, write_all};
+
+// Kills the server at the end of the test
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_echo(handle: &Handle, echo: SocketAddr) {
+    let handle2 = handle.clone();
+    let listener = TcpListener::bind(&echo, handle).unwrap();
+    let server = listener.incoming().for_each(move |(stream, _addr)| {
+        let (rd,wr) = stream.split();
+        handle2.spawn(copy(rd,wr).then( |_| { Ok(())}));
+        Ok(())
+    }).then( |_| { Ok(())});
+    handle.spawn(server);
+}
+
+fn write_file(name: &str, content: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("socksv5d-tc30-{}-{}", std::process::id(), name));
+    fs::write(&path, content).unwrap();
+    path
+}
+
+fn wait_for(addr: SocketAddr) {
+    for _ in 0..100 {
+        if net::TcpStream::connect(addr).is_ok() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("socksv5d does not listen on {}", addr);
+}
+
+fn tunnel(core: &mut Core, proxy: SocketAddr, echo: SocketAddr, credentials: Credentials)
+                                                                        -> Option<TcpStream> {
+    let request = SocksRequestResponse::request(socksv5_future::Command::Connect, &echo);
+    let fut = TcpStream::connect(&proxy, &core.handle())
+        .and_then(move |stream| socks_connect_handshake_with_credentials(stream, request,
+                                                                         Some(credentials)));
+    core.run(fut).ok().map(|(stream,_reply)| stream)
+}
+
+fn ping(core: &mut Core, stream: TcpStream) -> Option<TcpStream> {
+    let fut = write_all(stream, b"ping")
+        .and_then(|(stream,_buf)| read_exact(stream, [0u8;4]));
+    match core.run(fut) {
+        Ok((stream,buf)) if &buf == b"ping" => Some(stream),
+        _ => None
+    }
+}
+
+// The reload happens in the background
+fn wait_until_accepted(core: &mut Core, proxy: SocketAddr, echo: SocketAddr,
+                       credentials: Credentials) {
+    for _ in 0..100 {
+        if tunnel(core, proxy, echo, credentials.clone()).is_some() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("{} is not accepted", credentials.username);
+}
+
+fn hangup(daemon: &Daemon) {
+    let status = Command::new("kill").arg("-HUP").arg(daemon.0.id().to_string())
+                        .status().unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_config_handle() {
+    let handle = ConfigHandle::new(1);
+    let clone = handle.clone();
+    let before = handle.get();
+    assert_eq!(*clone.swap(2), 1);
+    assert_eq!(*before, 1);
+    assert_eq!(*handle.get(), 2);
+}
+
+#[test]
+fn test_reload() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let echo: SocketAddr = "127.0.0.1:64082".parse().unwrap();
+    let proxy: SocketAddr = "127.0.0.1:64083".parse().unwrap();
+    start_echo(&handle, echo);
+    let alice = Credentials::new("alice", "secret");
+    let bob = Credentials::new("bob", "secret");
+
+    let users = write_file("users", "alice secret\n");
+    let config_text = format!("listen = \"{}\"\nusers = \"{}\"\nwatch = 1\n",
+                              proxy, users.display());
+    let config = write_file("config.toml", &config_text);
+    let daemon = Daemon(Command::new(env!("CARGO_BIN_EXE_socksv5d"))
+        .arg("--config").arg(&config)
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap());
+    wait_for(proxy);
+
+    let established = tunnel(&mut lp, proxy, echo, alice.clone()).unwrap();
+    let established = ping(&mut lp, established).unwrap();
+    assert!(tunnel(&mut lp, proxy, echo, bob.clone()).is_none());
+
+    // Reload on SIGHUP
+    fs::write(&users, "bob secret\n").unwrap();
+    hangup(&daemon);
+    wait_until_accepted(&mut lp, proxy, echo, bob.clone());
+    assert!(tunnel(&mut lp, proxy, echo, alice.clone()).is_none());
+    let established = ping(&mut lp, established).unwrap();
+
+    // An invalid configuration is not applied
+    fs::write(&config, "port = 1080\n").unwrap();
+    hangup(&daemon);
+    thread::sleep(Duration::from_millis(300));
+    assert!(tunnel(&mut lp, proxy, echo, alice.clone()).is_none());
+    assert!(tunnel(&mut lp, proxy, echo, bob.clone()).is_some());
+
+    // Reload on changed file
+    fs::write(&config, &config_text).unwrap();
+    fs::write(&users, "alice secret\n").unwrap();
+    wait_until_accepted(&mut lp, proxy, echo, alice);
+    assert!(tunnel(&mut lp, proxy, echo, bob).is_none());
+    assert!(ping(&mut lp, established).is_some());
+
+    let _ = fs::remove_file(users);
+    let _ = fs::remove_file(config);
+}
@@ -0,0 +1,70 @@
+extern crate futures;
+extern crate tokio_io;
+extern crate tokio_core;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use socksv5_future::{socks_connect_handshake, socks_handshake, Command, SocksRequestResponse};
+use futures::{Future,Stream};
+use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener,TcpStream};
+use tokio_io::io::{read_exact, write_all};
+
+// Request or reply with the longest domain allowed by RFC 1928
+fn long_domain(cmd_or_rep: u8) -> Vec<u8> {
+    let mut bytes = vec![5u8, cmd_or_rep, 0, 3, 255];
+    bytes.extend_from_slice(&[b'a'; 255]);
+    bytes.extend_from_slice(&[0, 80]);
+    bytes
+}
+
+#[test]
+fn test_server_long_domain() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64084".parse().unwrap();
+    let handle = lp.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let server = listener.incoming().take(2).collect()
+        .and_then(|mut streams| {
+            let (long,_addr) = streams.remove(0);
+            let (empty,_addr) = streams.remove(0);
+            socks_handshake(long).map(|(_stream,request)| request)
+                .join(socks_handshake(empty).then(Ok))
+        });
+    let client = |request: Vec<u8>| {
+        TcpStream::connect(&addr, &handle)
+            .and_then(|stream| write_all(stream, [5u8,1,0]))
+            .and_then(|(stream,_buf)| read_exact(stream, [0u8;2]))
+            .and_then(move |(stream,_buf)| write_all(stream, request))
+    };
+    let long = client(long_domain(1));
+    // Empty domain, followed by a byte of early data
+    let empty = client(vec![5u8,1,0,3,0,0,80,0]);
+    let fut = long.and_then(|long| empty.map(|empty| (long,empty)));
+    let ((request,empty),_clients) = lp.run(server.join(fut)).unwrap();
+    assert_eq!(request.command(), Command::Connect);
+    assert_eq!(request.hostname(), Some(&[b'a'; 255][..]));
+    assert_eq!(request.port(), 80);
+    assert!(empty.is_err());
+}
+
+#[test]
+fn test_client_long_domain() {
+    let mut lp = Core::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:64085".parse().unwrap();
+    let handle = lp.handle();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let proxy = listener.incoming().take(1).collect()
+        .and_then(|mut streams| {
+            let (stream,_addr) = streams.remove(0);
+            read_exact(stream, [0u8;3])
+                .and_then(|(stream,_buf)| write_all(stream, [5u8,0]))
+                .and_then(|(stream,_buf)| read_exact(stream, [0u8;10]))
+                .and_then(|(stream,_buf)| write_all(stream, long_domain(0)))
+        });
+    let request = SocksRequestResponse::request(Command::Connect, &addr);
+    let client = TcpStream::connect(&addr, &handle)
+        .and_then(|stream| socks_connect_handshake(stream, request));
+    let (_proxy,(_stream,reply)) = lp.run(proxy.join(client)).unwrap();
+    assert_eq!(reply.bytes, long_domain(0));
+}
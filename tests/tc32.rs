@@ -0,0 +1,66 @@
+extern crate futures;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use socksv5_future::{CachingResolver, ResolveFuture, Resolver, SystemResolver, RESOLVER_QUEUE};
+use futures::{future, Future};
+
+struct CountingResolver {
+    lookups: Arc<AtomicUsize>
+}
+
+impl Resolver for CountingResolver {
+    fn resolve(&self, _host: &str, port: u16) -> ResolveFuture {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        let addr: SocketAddr = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+        Box::new(future::ok(vec![addr]))
+    }
+}
+
+#[test]
+fn test_cache_max_entries() {
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let resolver = CachingResolver::new(CountingResolver { lookups: lookups.clone() },
+                                        Duration::from_secs(60))
+                        .with_max_entries(2);
+    for host in &["a.test", "b.test", "a.test", "c.test"] {
+        resolver.resolve(host, 80).wait().unwrap();
+    }
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    assert_eq!(resolver.len(), 2);
+
+    // a.test expired first and has been evicted, c.test is still cached
+    resolver.resolve("c.test", 80).wait().unwrap();
+    assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    resolver.resolve("a.test", 80).wait().unwrap();
+    assert_eq!(lookups.load(Ordering::SeqCst), 4);
+    assert_eq!(resolver.len(), 2);
+}
+
+#[test]
+fn test_cache_sweep_expired() {
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let resolver = CachingResolver::new(CountingResolver { lookups: lookups.clone() },
+                                        Duration::from_millis(0))
+                        .with_max_entries(3);
+    for i in 0..10 {
+        resolver.resolve(&format!("host{}.test", i), 80).wait().unwrap();
+        assert!(resolver.len() <= 3);
+    }
+    assert_eq!(lookups.load(Ordering::SeqCst), 10);
+}
+
+#[test]
+fn test_system_resolver_pool() {
+    // More lookups than threads are queued and all complete
+    let lookups: Vec<_> = (0..RESOLVER_QUEUE / 4)
+                            .map(|_| SystemResolver.resolve("127.0.0.1", 80))
+                            .collect();
+    let results = future::join_all(lookups).wait().unwrap();
+    for addrs in results {
+        assert_eq!(addrs, vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,54 @@
+#![cfg(target_os = "linux")]
+extern crate futures;
+extern crate tokio_core;
+extern crate libc;
+extern crate socksv5_future;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use socksv5_future::{accept_retry, ACCEPT_BACKOFF_MS};
+use futures::{Future,Stream};
+use tokio_core::reactor::{Core, Timeout};
+use tokio_core::net::TcpListener;
+
+// Use up the file descriptors of the process
+fn exhaust_fds() -> Vec<libc::c_int> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit), 0);
+        limit.rlim_cur = limit.rlim_cur.min(256);
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &limit), 0);
+    }
+    let mut fds = vec!();
+    loop {
+        let fd = unsafe { libc::dup(0) };
+        if fd < 0 {
+            return fds;
+        }
+        fds.push(fd);
+    }
+}
+
+#[test]
+fn test_accept_retry_emfile() {
+    let mut lp = Core::new().unwrap();
+    let handle = lp.handle();
+    let addr: SocketAddr = "127.0.0.1:64108".parse().unwrap();
+    let listener = TcpListener::bind(&addr, &handle).unwrap();
+    let accepted = accept_retry(listener, &handle).into_future().map_err(|(e,_)| e);
+
+    // The connection waits in the backlog, its accept fails with EMFILE
+    let client = std::net::TcpStream::connect(addr).unwrap();
+    let fds = exhaust_fds();
+    assert!(!fds.is_empty());
+    let release = Timeout::new(Duration::from_millis(50), &handle).unwrap()
+        .map(move |_| for fd in fds {
+            unsafe { libc::close(fd); }
+        });
+
+    let start = Instant::now();
+    let ((accepted,_incoming),_) = lp.run(accepted.join(release)).unwrap();
+    let (_stream,peer) = accepted.unwrap();
+    assert_eq!(peer, client.local_addr().unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(ACCEPT_BACKOFF_MS));
+}